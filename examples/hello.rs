@@ -9,28 +9,41 @@ use std::{
     borrow::Cow,
     env,
     ffi::{CStr, CString},
-    mem,
-    path::PathBuf,
+    io, mem,
 };
 
 const HELLO_STR: &str = "Hello World!\n";
 const HELLO_NAME: &str = "hello";
 const HELLO_NODEID: NodeId = 2;
 
-fn main() {
-    let mountpoint = env::args()
-        .nth(1)
-        .map(PathBuf::from)
-        .expect("requires the mountpoint path");
+fn main() -> io::Result<()> {
+    pretty_env_logger::init();
 
-    let mut session = Builder::new("hello")
-        .debug(true)
-        .build(Hello)
-        .expect("failed to start fuse session");
+    let cmdline = Builder::from_args::<()>(env::args_os())?;
+    if cmdline.show_help {
+        cmdline.print_help();
+        return Ok(());
+    }
+    if cmdline.show_version {
+        cmdline.print_version();
+        return Ok(());
+    }
+
+    let mountpoint = cmdline
+        .mountpoint
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "missing mountpoint"))?;
+
+    let mut session = cmdline.builder.build(Hello)?;
+    session.set_signal_handlers()?;
+    session.mount(&mountpoint)?;
+    session.daemonize(cmdline.foreground)?;
+    if cmdline.singlethread {
+        session.run_loop()?;
+    } else {
+        session.run_loop_mt()?;
+    }
 
-    session.set_signal_handlers().unwrap();
-    session.mount(&mountpoint).unwrap();
-    session.run_loop().unwrap();
+    Ok(())
 }
 
 struct Hello;
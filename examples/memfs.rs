@@ -1,27 +1,37 @@
 use chrono::Local;
 use libc::{dev_t, mode_t, off_t, stat, statvfs};
 use libfuse::{
-    dir::DirBuf,
+    dir::{DirBuf, DirSnapshot, OpenDirOptions},
     file::{Entry, ReadOptions, RenameFlags, SetAttrs, WriteOptions},
+    handle::HandleTable,
+    inode::InodeTable,
     session::Builder,
     NodeId, OperationResult, Operations, ROOT_NODEID,
 };
 use std::{
     borrow::Cow,
-    collections::hash_map::{Entry as MapEntry, HashMap},
+    collections::HashMap,
+    env,
     ffi::{CStr, CString},
     io,
-    path::PathBuf,
 };
-use structopt::StructOpt;
-
-#[derive(Debug, StructOpt)]
-struct Args {
-    mountpoint: PathBuf,
-}
 
 fn main() -> io::Result<()> {
-    let args = Args::from_args();
+    pretty_env_logger::init();
+
+    let cmdline = Builder::from_args::<()>(env::args_os())?;
+    if cmdline.show_help {
+        cmdline.print_help();
+        return Ok(());
+    }
+    if cmdline.show_version {
+        cmdline.print_version();
+        return Ok(());
+    }
+
+    let mountpoint = cmdline
+        .mountpoint
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "missing mountpoint"))?;
 
     let mut memfs = MemFs::new();
 
@@ -49,49 +59,47 @@ fn main() -> io::Result<()> {
         )
         .unwrap();
 
-    let mut session = Builder::new("memfs") //
-        .debug(true)
-        .build(memfs)?;
+    let mut session = cmdline.builder.build(memfs)?;
     session.set_signal_handlers()?;
-    session.mount(&args.mountpoint)?;
-    session.run_loop()?;
+    session.mount(&mountpoint)?;
+    session.daemonize(cmdline.foreground)?;
+    if cmdline.singlethread {
+        session.run_loop()?;
+    } else {
+        session.run_loop_mt()?;
+    }
 
     Ok(())
 }
 
 struct MemFs {
-    inodes: HashMap<NodeId, INode>,
-    next_id: u64,
+    inodes: InodeTable<INode>,
+    dirs: HandleTable<DirSnapshot>,
 }
 
 impl MemFs {
     fn new() -> Self {
-        let mut inodes = HashMap::new();
-
         let now = Local::now();
-        inodes.insert(
-            ROOT_NODEID,
-            INode::Dir(Dir {
-                parent: None,
-                children: HashMap::new(),
-                attr: {
-                    let mut attr: stat = unsafe { std::mem::zeroed() };
-                    attr.st_ino = ROOT_NODEID;
-                    attr.st_nlink = 2;
-                    attr.st_ctime = now.timestamp();
-                    attr.st_mtime = now.timestamp();
-                    attr.st_atime = now.timestamp();
-                    attr.st_mode = libc::S_IFDIR | 0o777;
-                    attr.st_uid = unsafe { libc::getuid() };
-                    attr.st_gid = unsafe { libc::getgid() };
-                    attr
-                },
-            }),
-        );
+        let inodes = InodeTable::new(INode::Dir(Dir {
+            parent: None,
+            children: HashMap::new(),
+            attr: {
+                let mut attr: stat = unsafe { std::mem::zeroed() };
+                attr.st_ino = ROOT_NODEID;
+                attr.st_nlink = 2;
+                attr.st_ctime = now.timestamp();
+                attr.st_mtime = now.timestamp();
+                attr.st_atime = now.timestamp();
+                attr.st_mode = libc::S_IFDIR | 0o777;
+                attr.st_uid = unsafe { libc::getuid() };
+                attr.st_gid = unsafe { libc::getgid() };
+                attr
+            },
+        }));
 
         Self {
             inodes,
-            next_id: 2, // inode 1 is reserved for the root directory.
+            dirs: HandleTable::new(),
         }
     }
 
@@ -99,33 +107,25 @@ impl MemFs {
         &mut self,
         parent: NodeId,
         name: String,
-        mut inode: INode,
+        inode: INode,
     ) -> OperationResult<NodeId> {
-        let parent = self.inodes.get_mut(&parent).ok_or_else(|| libc::ENOENT)?;
+        let dir = self.inodes.get(parent).ok_or_else(|| libc::ENOENT)?;
+        let dir = dir.as_dir().ok_or_else(|| libc::ENOTDIR)?;
+        if dir.children.contains_key(&name) {
+            return Err(libc::EEXIST);
+        }
 
-        let ino = self.next_id;
+        let ino = self.inodes.insert(inode);
+        self.inodes.get_mut(ino).unwrap().attr_mut().st_ino = ino;
 
-        let dir = parent.as_dir_mut().ok_or_else(|| libc::ENOTDIR)?;
-        match dir.children.entry(name.into()) {
-            MapEntry::Occupied(..) => return Err(libc::EEXIST),
-            MapEntry::Vacant(entry) => {
-                entry.insert(ino);
-            }
-        }
+        let dir = self.inodes.get_mut(parent).unwrap();
+        dir.as_dir_mut().unwrap().children.insert(name, ino);
 
-        match self.inodes.entry(ino) {
-            MapEntry::Occupied(..) => Err(libc::EEXIST),
-            MapEntry::Vacant(entry) => {
-                inode.attr_mut().st_ino = ino;
-                entry.insert(inode);
-                self.next_id += 1;
-                Ok(ino)
-            }
-        }
+        Ok(ino)
     }
 
     fn remove_inode(&mut self, parent: NodeId, name: String) -> OperationResult<()> {
-        let parent = self.inodes.get_mut(&parent).ok_or_else(|| libc::ENOENT)?;
+        let parent = self.inodes.get_mut(parent).ok_or_else(|| libc::ENOENT)?;
         let ino = parent
             .as_dir_mut()
             .ok_or_else(|| libc::ENOTDIR)?
@@ -133,28 +133,31 @@ impl MemFs {
             .remove(&name);
 
         if let Some(ino) = ino {
-            self.inodes.remove(&ino);
+            self.inodes.unlink(ino);
         }
 
         Ok(())
     }
+
+    fn entry(&mut self, ino: NodeId) -> OperationResult<Entry> {
+        let attr = *self.inodes.get(ino).ok_or_else(|| libc::ENOENT)?.attr();
+        self.inodes.entry(ino, attr).ok_or_else(|| libc::ENOENT)
+    }
 }
 
 impl Operations for MemFs {
     fn lookup(&mut self, parent: NodeId, name: &CStr) -> OperationResult<Entry> {
         let name = name.to_str().map_err(|_| libc::EIO)?;
 
-        let parent = self.inodes.get(&parent).ok_or_else(|| libc::ENOENT)?;
+        let parent = self.inodes.get(parent).ok_or_else(|| libc::ENOENT)?;
         let parent = parent.as_dir().ok_or_else(|| libc::ENOTDIR)?;
 
-        let child = parent.children.get(name).ok_or_else(|| libc::ENOENT)?;
-        let child = self.inodes.get(child).ok_or_else(|| libc::ENOENT)?;
+        let child = *parent.children.get(name).ok_or_else(|| libc::ENOENT)?;
+        self.entry(child)
+    }
 
-        Ok(Entry {
-            nodeid: child.attr().st_ino,
-            attr: *child.attr(),
-            ..Default::default()
-        })
+    fn forget(&mut self, ino: NodeId, nlookup: u64) {
+        self.inodes.forget(ino, nlookup);
     }
 
     fn mknod(
@@ -179,7 +182,6 @@ impl Operations for MemFs {
                 data: vec![],
                 attr: {
                     let mut attr: stat = unsafe { std::mem::zeroed() };
-                    attr.st_ino = self.next_id;
                     attr.st_nlink = 1;
                     attr.st_ctime = now.timestamp();
                     attr.st_mtime = now.timestamp();
@@ -190,13 +192,7 @@ impl Operations for MemFs {
                 },
             }),
         )?;
-        let inode = self.inodes.get(&ino).unwrap();
-
-        Ok(Entry {
-            nodeid: inode.attr().st_ino,
-            attr: *inode.attr(),
-            ..Entry::default()
-        })
+        self.entry(ino)
     }
 
     fn mkdir(&mut self, parent: NodeId, name: &CStr, mode: mode_t) -> OperationResult<Entry> {
@@ -211,7 +207,6 @@ impl Operations for MemFs {
                 children: HashMap::new(),
                 attr: {
                     let mut attr: stat = unsafe { std::mem::zeroed() };
-                    attr.st_ino = self.next_id;
                     attr.st_nlink = 1;
                     attr.st_ctime = now.timestamp();
                     attr.st_mtime = now.timestamp();
@@ -222,13 +217,7 @@ impl Operations for MemFs {
                 },
             }),
         )?;
-        let inode = self.inodes.get(&ino).unwrap();
-
-        Ok(Entry {
-            nodeid: inode.attr().st_ino,
-            attr: *inode.attr(),
-            ..Entry::default()
-        })
+        self.entry(ino)
     }
 
     fn unlink(&mut self, parent: NodeId, name: &CStr) -> OperationResult<()> {
@@ -258,17 +247,14 @@ impl Operations for MemFs {
 
         // check if the destination has already exist.
         if flags.contains(RenameFlags::NOREPLACE) {
-            let newparent = self.inodes.get(&newparent).ok_or_else(|| libc::ENOENT)?;
+            let newparent = self.inodes.get(newparent).ok_or_else(|| libc::ENOENT)?;
             let newparent = newparent.as_dir().ok_or_else(|| libc::ENOTDIR)?;
             if newparent.children.contains_key(newname) {
                 return Err(libc::EEXIST);
             }
         }
 
-        let oldparent = self
-            .inodes
-            .get_mut(&oldparent)
-            .ok_or_else(|| libc::ENOENT)?;
+        let oldparent = self.inodes.get_mut(oldparent).ok_or_else(|| libc::ENOENT)?;
         let oldparent = oldparent.as_dir_mut().ok_or_else(|| libc::ENOTDIR)?;
         let ino = oldparent
             .children
@@ -276,22 +262,19 @@ impl Operations for MemFs {
             .ok_or_else(|| libc::ENOENT)?;
 
         let oldino = {
-            let newparent = self
-                .inodes
-                .get_mut(&newparent)
-                .ok_or_else(|| libc::ENOENT)?;
+            let newparent = self.inodes.get_mut(newparent).ok_or_else(|| libc::ENOENT)?;
             let newparent = newparent.as_dir_mut().ok_or_else(|| libc::ENOTDIR)?;
             newparent.children.insert(newname.into(), ino)
         };
 
         if let Some(oldino) = oldino {
-            self.inodes.remove(&oldino);
+            self.inodes.unlink(oldino);
         }
 
         Ok(())
     }
 
-    // TODO: symlink, readlink, forget
+    // TODO: symlink, readlink
 
     fn statfs(&mut self, _: NodeId) -> OperationResult<statvfs> {
         let mut st: statvfs = unsafe { std::mem::zeroed() };
@@ -307,7 +290,7 @@ impl Operations for MemFs {
         _: &mut ReadOptions,
         _: u64,
     ) -> OperationResult<Cow<'_, [u8]>> {
-        let file = self.inodes.get(&ino).ok_or_else(|| libc::ENOENT)?;
+        let file = self.inodes.get(ino).ok_or_else(|| libc::ENOENT)?;
         let file = file.as_file().ok_or_else(|| libc::EISDIR)?;
 
         debug_assert!(offset >= 0);
@@ -328,7 +311,7 @@ impl Operations for MemFs {
         _: &mut WriteOptions,
         _: u64,
     ) -> OperationResult<usize> {
-        let file = self.inodes.get_mut(&ino).ok_or_else(|| libc::ENOENT)?;
+        let file = self.inodes.get_mut(ino).ok_or_else(|| libc::ENOENT)?;
         let file = file.as_file_mut().ok_or_else(|| libc::EISDIR)?;
 
         debug_assert!(offset >= 0);
@@ -342,17 +325,12 @@ impl Operations for MemFs {
         Ok(buf.len())
     }
 
-    fn readdir(
-        &mut self,
-        ino: NodeId,
-        offset: off_t,
-        buf: &mut DirBuf,
-        _: u64,
-    ) -> OperationResult<()> {
-        let dir = self.inodes.get(&ino).ok_or_else(|| libc::ENOENT)?;
+    fn opendir(&mut self, ino: NodeId, _: &mut OpenDirOptions) -> OperationResult<u64> {
+        let dir = self.inodes.get(ino).ok_or_else(|| libc::ENOENT)?;
         let dir = dir.as_dir().ok_or_else(|| libc::ENOTDIR)?;
 
-        for (i, (name, ino)) in dir.dirs(ino).enumerate().skip(offset as usize) {
+        let mut snapshot = DirSnapshot::new();
+        for (name, ino) in dir.dirs(ino) {
             let name = CString::new(name).map_err(|_| libc::EIO)?;
             let attr = match ino {
                 ROOT_NODEID => {
@@ -361,22 +339,35 @@ impl Operations for MemFs {
                     attr
                 }
                 ino => {
-                    let inode = self.inodes.get(&ino).ok_or_else(|| libc::ENOENT)?;
+                    let inode = self.inodes.get(ino).ok_or_else(|| libc::ENOENT)?;
                     inode.attr().clone()
                 }
             };
-            let off = (i + 1) as off_t;
-
-            if buf.add(&*name, &attr, off) {
-                break;
-            }
+            snapshot.push(name, attr);
         }
 
+        Ok(self.dirs.insert(snapshot))
+    }
+
+    fn readdir(
+        &mut self,
+        _: NodeId,
+        offset: off_t,
+        buf: &mut DirBuf,
+        fh: u64,
+    ) -> OperationResult<()> {
+        let snapshot = self.dirs.get(fh).ok_or_else(|| libc::EBADF)?;
+        snapshot.fill(offset, buf);
+        Ok(())
+    }
+
+    fn releasedir(&mut self, _: NodeId, fh: u64) -> OperationResult<()> {
+        self.dirs.remove(fh);
         Ok(())
     }
 
     fn getattr(&mut self, ino: NodeId, _: Option<u64>) -> OperationResult<(stat, f64)> {
-        let inode = self.inodes.get(&ino).ok_or_else(|| libc::ENOENT)?;
+        let inode = self.inodes.get(ino).ok_or_else(|| libc::ENOENT)?;
         Ok((inode.attr().clone(), 0.0))
     }
 
@@ -386,7 +377,7 @@ impl Operations for MemFs {
         attrs: &SetAttrs<'_>,
         _: Option<u64>,
     ) -> OperationResult<(stat, f64)> {
-        let inode = self.inodes.get_mut(&ino).ok_or_else(|| libc::ENOENT)?;
+        let inode = self.inodes.get_mut(ino).ok_or_else(|| libc::ENOENT)?;
         let now = Local::now();
 
         if let Some(mode) = attrs.mode() {
@@ -522,3 +513,34 @@ impl Dir {
             )
     }
 }
+
+#[cfg(all(test, feature = "testing"))]
+mod tests {
+    use super::*;
+    use libfuse::testing::{mount_tmp, posix::Suite};
+
+    /// Run the POSIX checks against the mounted filesystem.
+    ///
+    /// This test requires `/dev/fuse` and the permission to mount, so it is
+    /// run explicitly by `cargo test --features testing --example memfs --
+    /// --ignored`.
+    #[test]
+    #[ignore]
+    fn posix() {
+        let mounted = mount_tmp(MemFs::new()).unwrap();
+        let report = Suite::new()
+            // `rename` supports only `RENAME_EXCHANGE`.
+            .skip("rename_replace")
+            .skip("rename_dir_nonempty")
+            // `rmdir` does not check whether the directory is empty.
+            .skip("rmdir_nonempty")
+            // `link`, `symlink` and the extended attributes are not
+            // implemented.
+            .skip("hard_link")
+            .skip("symlink")
+            .skip("xattr")
+            .run(mounted.path());
+        mounted.unmount().unwrap();
+        assert!(report.is_success(), "\n{}", report);
+    }
+}
@@ -4,12 +4,23 @@ use libfuse::{
     session::Builder,
     NodeId, OperationResult, Operations, ROOT_NODEID,
 };
-use std::{borrow::Cow, env, ffi::CStr, io, path::PathBuf};
+use std::{borrow::Cow, env, ffi::CStr, io};
 
 fn main() -> io::Result<()> {
-    let mountpoint = env::args()
-        .nth(1)
-        .map(PathBuf::from)
+    pretty_env_logger::init();
+
+    let cmdline = Builder::from_args::<()>(env::args_os())?;
+    if cmdline.show_help {
+        cmdline.print_help();
+        return Ok(());
+    }
+    if cmdline.show_version {
+        cmdline.print_version();
+        return Ok(());
+    }
+
+    let mountpoint = cmdline
+        .mountpoint
         .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "missing mountpoint"))?;
 
     if !mountpoint.is_file() {
@@ -23,14 +34,16 @@ fn main() -> io::Result<()> {
         libc::umask(0);
     }
 
-    let mut session = Builder::new("null")
-        .debug(true)
-        .build(Null)
-        .expect("failed to start fuse session");
+    let mut session = cmdline.builder.build(Null)?;
 
     session.set_signal_handlers()?;
     session.mount(&mountpoint)?;
-    session.run_loop()?;
+    session.daemonize(cmdline.foreground)?;
+    if cmdline.singlethread {
+        session.run_loop()?;
+    } else {
+        session.run_loop_mt()?;
+    }
 
     Ok(())
 }
@@ -0,0 +1,57 @@
+use libfuse::{
+    passthrough::Passthrough,
+    session::{Builder, CustomOptions},
+};
+use std::{env, io, path::PathBuf};
+
+#[derive(Default)]
+struct Options {
+    source: Option<PathBuf>,
+}
+
+impl CustomOptions for Options {
+    fn parse(&mut self, name: &str, value: Option<&str>) -> Result<bool, String> {
+        match (name, value) {
+            ("source", Some(source)) => {
+                self.source = Some(source.into());
+                Ok(true)
+            }
+            ("source", None) => Err("source requires a directory".into()),
+            _ => Ok(false),
+        }
+    }
+}
+
+fn main() -> io::Result<()> {
+    pretty_env_logger::init();
+
+    let cmdline = Builder::from_args::<Options>(env::args_os())?;
+    if cmdline.show_help {
+        cmdline.print_help();
+        println!("\n    -o source=DIR          directory to be mirrored (default: /)");
+        return Ok(());
+    }
+    if cmdline.show_version {
+        cmdline.print_version();
+        return Ok(());
+    }
+
+    let mountpoint = cmdline
+        .mountpoint
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "missing mountpoint"))?;
+    let source = cmdline.options.source.unwrap_or_else(|| "/".into());
+
+    let fs = Passthrough::new(&source)?;
+
+    let mut session = cmdline.builder.build(fs)?;
+    session.set_signal_handlers()?;
+    session.mount(&mountpoint)?;
+    session.daemonize(cmdline.foreground)?;
+    if cmdline.singlethread {
+        session.run_loop()?;
+    } else {
+        session.run_loop_mt()?;
+    }
+
+    Ok(())
+}
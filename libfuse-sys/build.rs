@@ -11,9 +11,16 @@ fn main() {
     // Build helper C functions.
     let mut helpers = cc::Build::new();
     helpers.file(manifest_dir.join("src/helpers/common.c"));
+    helpers.file(manifest_dir.join("src/helpers/loop_mt.c"));
     if cfg!(feature = "cache-readdir") {
         helpers.file(manifest_dir.join("src/helpers/cache_readdir.c"));
     }
+    if cfg!(feature = "handoff") {
+        helpers.file(manifest_dir.join("src/helpers/handoff.c"));
+    }
+    if cfg!(feature = "custom-io") {
+        helpers.file(manifest_dir.join("src/helpers/custom_io.c"));
+    }
     helpers.define("FUSE_USE_VERSION", FUSE_USE_VERSION);
     for incpath in &fuse3_config.include_paths {
         helpers.include(incpath);
@@ -1,9 +1,11 @@
 use crate::{
-    fuse_conn_info, //
+    fuse_args, //
+    fuse_conn_info,
     fuse_ctx,
     fuse_entry_param,
     fuse_file_info,
     fuse_ino_t,
+    fuse_log_level,
     fuse_lowlevel_ops,
     fuse_req_t,
     fuse_session,
@@ -38,6 +40,64 @@ extern "C" {
     ) -> *mut fuse_session;
 }
 
+extern "C" {
+    /// Enter the multi-threaded event loop, with the configuration of
+    /// `struct fuse_loop_config`.
+    pub fn fuse_session_loop_mt_wrapped(
+        se: *mut fuse_session,
+        clone_fd: c_int,
+        max_idle_threads: c_uint,
+    ) -> c_int;
+}
+
+extern "C" {
+    /// Use the specified file descriptor for the communication, through the
+    /// given replacements of `read(2)` and `writev(2)`. They receive the user
+    /// data of the session as the last argument.
+    #[cfg(feature = "handoff")]
+    pub fn fuse_session_custom_io_with(
+        se: *mut fuse_session,
+        fd: c_int,
+        writev: unsafe extern "C" fn(c_int, *mut libc::iovec, c_int, *mut c_void) -> libc::ssize_t,
+        read: unsafe extern "C" fn(c_int, *mut c_void, size_t, *mut c_void) -> libc::ssize_t,
+    ) -> c_int;
+
+    /// Process a message stored in memory as if it was received from the
+    /// kernel.
+    #[cfg(feature = "handoff")]
+    pub fn fuse_session_process_mem(se: *mut fuse_session, mem: *mut c_void, size: size_t);
+
+    /// Use the specified file descriptor for the communication, through
+    /// `read(2)` and `writev(2)`, instead of `/dev/fuse`.
+    #[cfg(feature = "custom-io")]
+    pub fn fuse_session_custom_io_fd(se: *mut fuse_session, fd: c_int) -> c_int;
+}
+
+extern "C" {
+    /// Parse the generic FUSE command line options.
+    ///
+    /// The value of `mountpoint` is allocated by `malloc` and must be freed
+    /// by the caller.
+    pub fn fuse_parse_cmdline_wrapped(
+        args: *mut fuse_args,
+        foreground: *mut c_int,
+        singlethread: *mut c_int,
+        debug: *mut c_int,
+        show_help: *mut c_int,
+        show_version: *mut c_int,
+        clone_fd: *mut c_int,
+        max_idle_threads: *mut c_uint,
+        mountpoint: *mut *mut c_char,
+    ) -> c_int;
+}
+
+extern "C" {
+    /// Install a log handler which receives the formatted messages.
+    ///
+    /// Passing `None` restores the default handler that prints to stderr.
+    pub fn fuse_set_log_func_wrapped(func: c_fn!(fuse_log_level::Type, *const c_char));
+}
+
 extern "C" {
     pub fn fuse_ctx_uid(ctx: *const fuse_ctx) -> uid_t;
     pub fn fuse_ctx_gid(ctx: *const fuse_ctx) -> gid_t;
@@ -50,6 +110,8 @@ extern "C" {
     pub fn fuse_conn_info_congestion_threshold(conn: *const fuse_conn_info) -> c_uint;
     pub fn fuse_conn_info_max_background(conn: *const fuse_conn_info) -> c_uint;
     pub fn fuse_conn_info_max_read(conn: *const fuse_conn_info) -> c_uint;
+    /// Allocate a zeroed `fuse_conn_info`, which must be freed by `free`.
+    pub fn fuse_conn_info_new() -> *mut fuse_conn_info;
     pub fn fuse_conn_info_proto_major(conn: *const fuse_conn_info) -> c_uint;
     pub fn fuse_conn_info_proto_minor(conn: *const fuse_conn_info) -> c_uint;
     pub fn fuse_conn_info_set_congestion_threshold(conn: *mut fuse_conn_info, threshold: c_uint);
@@ -67,8 +129,11 @@ extern "C" {
     pub fn fuse_file_info_flock_release(fi: *const fuse_file_info) -> c_uint;
     pub fn fuse_file_info_flush(fi: *const fuse_file_info) -> c_uint;
     pub fn fuse_file_info_lock_owner(fi: *const fuse_file_info) -> u64;
+    /// Allocate a zeroed `fuse_file_info`, which must be freed by `free`.
+    pub fn fuse_file_info_new() -> *mut fuse_file_info;
     pub fn fuse_file_info_set_direct_io(fi: *mut fuse_file_info, direct_io: c_int);
     pub fn fuse_file_info_set_fh(fi: *mut fuse_file_info, fh: u64);
+    pub fn fuse_file_info_set_flags(fi: *mut fuse_file_info, flags: c_int);
     pub fn fuse_file_info_set_keep_cache(fi: *mut fuse_file_info, keep_cache: c_uint);
     pub fn fuse_file_info_set_nonseekable(fi: *mut fuse_file_info, nonseekable: c_uint);
     pub fn fuse_file_info_writepage(fi: *const fuse_file_info) -> c_uint;
@@ -1,5 +1,8 @@
 #include <fuse_lowlevel.h>
 #include <malloc.h>
+#include <stdarg.h>
+#include <stdio.h>
+#include <string.h>
 #include <sys/stat.h>
 
 struct fuse_session*
@@ -13,6 +16,61 @@ fuse_session_new_wrapped(int argc, char const* const* argv,
     return se;
 }
 
+int
+fuse_parse_cmdline_wrapped(struct fuse_args* args, int* foreground,
+                           int* singlethread, int* debug, int* show_help,
+                           int* show_version, int* clone_fd,
+                           unsigned int* max_idle_threads, char** mountpoint)
+{
+    struct fuse_cmdline_opts opts;
+    memset(&opts, 0, sizeof(opts));
+    if (fuse_parse_cmdline(args, &opts) != 0) {
+        return -1;
+    }
+    *foreground = opts.foreground;
+    *singlethread = opts.singlethread;
+    *debug = opts.debug;
+    *show_help = opts.show_help;
+    *show_version = opts.show_version;
+    *clone_fd = opts.clone_fd;
+    *max_idle_threads = opts.max_idle_threads;
+    *mountpoint = opts.mountpoint;
+    return 0;
+}
+
+static void (*fuse_log_func_rs)(enum fuse_log_level, char const*) = NULL;
+
+static void
+fuse_log_func_trampoline(enum fuse_log_level level, char const* fmt,
+                         va_list ap)
+{
+    va_list ap2;
+    va_copy(ap2, ap);
+    int len = vsnprintf(NULL, 0, fmt, ap2);
+    va_end(ap2);
+    if (len < 0) {
+        return;
+    }
+
+    char* msg = (char*)malloc(len + 1);
+    if (msg == NULL) {
+        return;
+    }
+    vsnprintf(msg, len + 1, fmt, ap);
+
+    if (fuse_log_func_rs != NULL) {
+        fuse_log_func_rs(level, msg);
+    }
+    free(msg);
+}
+
+void
+fuse_set_log_func_wrapped(void (*func)(enum fuse_log_level, char const*))
+{
+    fuse_log_func_rs = func;
+    fuse_set_log_func(func != NULL ? fuse_log_func_trampoline : NULL);
+}
+
 uid_t
 fuse_ctx_uid(struct fuse_ctx const* ctx)
 {
@@ -315,6 +373,12 @@ fuse_ll_ops_on_create(struct fuse_lowlevel_ops* op,
     op->create = create;
 }
 
+struct fuse_conn_info*
+fuse_conn_info_new(void)
+{
+    return (struct fuse_conn_info*)calloc(1, sizeof(struct fuse_conn_info));
+}
+
 unsigned int
 fuse_conn_info_proto_major(struct fuse_conn_info const* conn)
 {
@@ -396,6 +460,12 @@ fuse_conn_info_set_time_gran(struct fuse_conn_info* conn,
     conn->time_gran = time_gran;
 }
 
+struct fuse_file_info*
+fuse_file_info_new(void)
+{
+    return (struct fuse_file_info*)calloc(1, sizeof(struct fuse_file_info));
+}
+
 int
 fuse_file_info_flags(struct fuse_file_info const* fi)
 {
@@ -444,6 +514,12 @@ fuse_file_info_set_fh(struct fuse_file_info* fi, uint64_t fh)
     fi->fh = fh;
 }
 
+void
+fuse_file_info_set_flags(struct fuse_file_info* fi, int flags)
+{
+    fi->flags = flags;
+}
+
 void
 fuse_file_info_set_direct_io(struct fuse_file_info* fi, int direct_io)
 {
@@ -0,0 +1,27 @@
+#include <fuse_lowlevel.h>
+#include <sys/uio.h>
+#include <unistd.h>
+
+static ssize_t
+custom_io_writev(int fd, struct iovec* iov, int count, void* userdata)
+{
+    (void)userdata;
+    return writev(fd, iov, count);
+}
+
+static ssize_t
+custom_io_read(int fd, void* buf, size_t buf_len, void* userdata)
+{
+    (void)userdata;
+    return read(fd, buf, buf_len);
+}
+
+int
+fuse_session_custom_io_fd(struct fuse_session* se, int fd)
+{
+    static const struct fuse_custom_io io = {
+        .writev = custom_io_writev,
+        .read = custom_io_read,
+    };
+    return fuse_session_custom_io(se, &io, fd);
+}
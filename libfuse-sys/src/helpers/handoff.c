@@ -0,0 +1,27 @@
+#include <fuse_lowlevel.h>
+#include <string.h>
+#include <sys/uio.h>
+
+int
+fuse_session_custom_io_with(struct fuse_session* se,
+                            int fd,
+                            ssize_t (*writev_fn)(int, struct iovec*, int, void*),
+                            ssize_t (*read_fn)(int, void*, size_t, void*))
+{
+    struct fuse_custom_io io;
+    memset(&io, 0, sizeof(io));
+    io.writev = writev_fn;
+    io.read = read_fn;
+    return fuse_session_custom_io(se, &io, fd);
+}
+
+void
+fuse_session_process_mem(struct fuse_session* se, void* mem, size_t size)
+{
+    struct fuse_buf buf;
+    memset(&buf, 0, sizeof(buf));
+    buf.size = size;
+    buf.mem = mem;
+    buf.fd = -1;
+    fuse_session_process_buf(se, &buf);
+}
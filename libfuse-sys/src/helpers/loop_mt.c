@@ -0,0 +1,15 @@
+/* `struct fuse_loop_config` is available since FUSE_USE_VERSION 32. */
+#undef FUSE_USE_VERSION
+#define FUSE_USE_VERSION 32
+
+#include <fuse_lowlevel.h>
+
+int
+fuse_session_loop_mt_wrapped(struct fuse_session* se, int clone_fd,
+                             unsigned int max_idle_threads)
+{
+    struct fuse_loop_config config;
+    config.clone_fd = clone_fd;
+    config.max_idle_threads = max_idle_threads;
+    return fuse_session_loop_mt(se, &config);
+}
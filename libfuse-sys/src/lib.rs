@@ -4,7 +4,14 @@
 
 pub mod helpers;
 
-use libc::{c_char, c_double, c_int, c_void, off_t, size_t, stat, statvfs};
+use libc::{c_char, c_double, c_int, c_ulong, c_void, off_t, size_t, stat, statvfs};
+
+#[repr(C)]
+pub struct fuse_args {
+    pub argc: c_int,
+    pub argv: *mut *mut c_char,
+    pub allocated: c_int,
+}
 
 #[repr(C)]
 pub struct fuse_conn_info {
@@ -31,6 +38,13 @@ pub struct fuse_lowlevel_ops {
     _unused: [u8; 0],
 }
 
+#[repr(C)]
+pub struct fuse_opt {
+    pub templ: *const c_char,
+    pub offset: c_ulong,
+    pub value: c_int,
+}
+
 #[repr(C)]
 pub struct fuse_req {
     _unused: [u8; 0],
@@ -43,6 +57,15 @@ pub struct fuse_session {
 
 pub type fuse_ino_t = u64;
 pub type fuse_req_t = *mut fuse_req;
+pub type fuse_interrupt_func_t = Option<unsafe extern "C" fn(req: fuse_req_t, data: *mut c_void)>;
+pub type fuse_opt_proc_t = Option<
+    unsafe extern "C" fn(
+        data: *mut c_void,
+        arg: *const c_char,
+        key: c_int,
+        outargs: *mut fuse_args,
+    ) -> c_int,
+>;
 
 extern "C" {
     pub fn fuse_add_direntry(
@@ -54,6 +77,25 @@ extern "C" {
         off: off_t,
     ) -> size_t;
 
+    pub fn fuse_cmdline_help();
+
+    pub fn fuse_daemonize(foreground: c_int) -> c_int;
+
+    pub fn fuse_lowlevel_help();
+
+    pub fn fuse_lowlevel_version();
+
+    pub fn fuse_opt_free_args(args: *mut fuse_args);
+
+    pub fn fuse_opt_parse(
+        args: *mut fuse_args,
+        data: *mut c_void,
+        opts: *const fuse_opt,
+        proc_: fuse_opt_proc_t,
+    ) -> c_int;
+
+    pub fn fuse_pkgversion() -> *const c_char;
+
     pub fn fuse_remove_signal_handlers(se: *mut fuse_session);
 
     pub fn fuse_reply_attr(req: fuse_req_t, attr: *const stat, attr_timeout: c_double) -> c_int;
@@ -84,6 +126,10 @@ extern "C" {
 
     pub fn fuse_req_ctx(req: fuse_req_t) -> *const fuse_ctx;
 
+    pub fn fuse_req_interrupt_func(req: fuse_req_t, func: fuse_interrupt_func_t, data: *mut c_void);
+
+    pub fn fuse_req_interrupted(req: fuse_req_t) -> c_int;
+
     pub fn fuse_req_userdata(req: fuse_req_t) -> *mut c_void;
 
     pub fn fuse_session_destroy(se: *mut fuse_session);
@@ -132,6 +178,34 @@ pub mod fuse_cap_flags {
     pub const FUSE_CAP_NO_OPENDIR_SUPPORT: Type = 1 << 24;
 }
 
+/// Special keys passed to the option processing function.
+pub mod fuse_opt_key {
+    use libc::c_int;
+
+    pub type Type = c_int;
+
+    pub const FUSE_OPT_KEY_OPT: Type = -1;
+    pub const FUSE_OPT_KEY_NONOPT: Type = -2;
+    pub const FUSE_OPT_KEY_KEEP: Type = -3;
+    pub const FUSE_OPT_KEY_DISCARD: Type = -4;
+}
+
+/// Log severity levels passed to the log handler.
+pub mod fuse_log_level {
+    use libc::c_uint;
+
+    pub type Type = c_uint;
+
+    pub const FUSE_LOG_EMERG: Type = 0;
+    pub const FUSE_LOG_ALERT: Type = 1;
+    pub const FUSE_LOG_CRIT: Type = 2;
+    pub const FUSE_LOG_ERR: Type = 3;
+    pub const FUSE_LOG_WARNING: Type = 4;
+    pub const FUSE_LOG_NOTICE: Type = 5;
+    pub const FUSE_LOG_INFO: Type = 6;
+    pub const FUSE_LOG_DEBUG: Type = 7;
+}
+
 /// Ioctl flags.
 pub mod fuse_ioctl_flags {
     use libc::c_int;
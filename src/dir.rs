@@ -1,14 +1,28 @@
+use crate::{common::NodeId, file::FileType, interrupt::Interrupt};
 use libc::{c_char, off_t, stat};
-use libfuse_sys::{fuse_add_direntry, fuse_file_info, fuse_req};
-use std::{ffi::CStr, ptr};
+use libfuse_sys::{fuse_add_direntry, fuse_file_info, fuse_req_t};
+use std::{
+    borrow::Cow,
+    ffi::{CStr, CString},
+    iter::FromIterator,
+    mem, ptr,
+};
 
 pub struct DirBuf<'a> {
-    pub(crate) req: &'a mut fuse_req,
+    // libfuse does not use the request to encode the entries, so this is
+    // null when the buffer is filled outside of a session by `testing`.
+    pub(crate) req: fuse_req_t,
     pub(crate) buf: &'a mut [u8],
     pub(crate) pos: usize,
+    pub(crate) interrupt: Interrupt,
 }
 
 impl<'a> DirBuf<'a> {
+    /// Returns the token to observe the interruption of this request.
+    pub fn interrupt(&self) -> &Interrupt {
+        &self.interrupt
+    }
+
     /// Add an directory entry to the send buffer.
     ///
     /// If the size of entry to be added is larger than the send buffer,
@@ -37,6 +51,112 @@ impl<'a> DirBuf<'a> {
 
         false
     }
+
+    /// Add the directory entries yielded by an iterator to the send buffer.
+    ///
+    /// The entries `.` and `..` are added before those of the iterator, and
+    /// the offset of each entry is assigned from its position. The entries
+    /// before `offset` are skipped, and the rest are added until the buffer
+    /// is full. The iterator must therefore yield the entries in the same
+    /// order on every call; otherwise the entries are duplicated or skipped
+    /// across the calls.
+    ///
+    /// Since the skipped entries are still walked, reading a directory of
+    /// `n` entries through several calls costs `O(n^2)` in total. For large
+    /// or frequently modified directories, take a `DirSnapshot` in `opendir`
+    /// instead and resume from its index with `DirSnapshot::fill`.
+    pub fn add_entries<'n>(
+        &mut self,
+        id: NodeId,
+        offset: off_t,
+        entries: impl IntoIterator<Item = (Cow<'n, CStr>, FileType, NodeId)>,
+    ) {
+        // The parent directory is unknown here, and the kernel does not use
+        // the node ID of `..` anyway.
+        let dot = CStr::from_bytes_with_nul(b".\0").unwrap();
+        let dotdot = CStr::from_bytes_with_nul(b"..\0").unwrap();
+        let dots = vec![
+            (Cow::Borrowed(dot), FileType::Directory, id),
+            (Cow::Borrowed(dotdot), FileType::Directory, UNKNOWN_INO),
+        ];
+
+        let skip = if offset > 0 { offset as usize } else { 0 };
+        let entries = dots.into_iter().chain(entries).enumerate().skip(skip);
+        for (i, (name, kind, nodeid)) in entries {
+            let mut attr: stat = unsafe { mem::zeroed() };
+            attr.st_ino = nodeid;
+            attr.st_mode = kind.mode();
+            if self.add(&name, &attr, (i + 1) as off_t) {
+                break;
+            }
+        }
+    }
+}
+
+/// The directory entries yielded by `Operations::readdir_entries`.
+pub type DirEntries<'a> = Box<dyn Iterator<Item = (Cow<'a, CStr>, FileType, NodeId)> + 'a>;
+
+/// The node ID used by libfuse for the entries whose node is not known.
+const UNKNOWN_INO: NodeId = 0xffff_ffff;
+
+/// A snapshot of the directory entries, taken when the directory is opened.
+///
+/// The snapshot is intended to be created in `opendir` and kept in
+/// a `HandleTable` until `releasedir`. Since `readdir` resumes from
+/// the index in the snapshot, the entries are neither duplicated nor
+/// skipped even if the directory is modified between the calls.
+#[derive(Default)]
+pub struct DirSnapshot {
+    entries: Vec<(CString, stat)>,
+}
+
+impl DirSnapshot {
+    /// Create an empty snapshot.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append an entry to the snapshot.
+    pub fn push(&mut self, name: CString, attr: stat) {
+        self.entries.push((name, attr));
+    }
+
+    /// Returns the number of entries in the snapshot.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns whether the snapshot has no entries.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Add the entries after `offset` to the send buffer, until it is full.
+    ///
+    /// The offset of each entry is its index in the snapshot plus one,
+    /// so the `offset` passed to `readdir` can be given as it is.
+    pub fn fill(&self, offset: off_t, buf: &mut DirBuf<'_>) {
+        let skip = if offset > 0 { offset as usize } else { 0 };
+        for (i, (name, attr)) in self.entries.iter().enumerate().skip(skip) {
+            if buf.add(name, attr, (i + 1) as off_t) {
+                break;
+            }
+        }
+    }
+}
+
+impl FromIterator<(CString, stat)> for DirSnapshot {
+    fn from_iter<I: IntoIterator<Item = (CString, stat)>>(iter: I) -> Self {
+        Self {
+            entries: iter.into_iter().collect(),
+        }
+    }
+}
+
+impl Extend<(CString, stat)> for DirSnapshot {
+    fn extend<I: IntoIterator<Item = (CString, stat)>>(&mut self, iter: I) {
+        self.entries.extend(iter);
+    }
 }
 
 pub struct OpenDirOptions<'a>(pub(crate) &'a mut fuse_file_info);
@@ -51,3 +171,108 @@ impl<'a> OpenDirOptions<'a> {
         self
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::decode_dirents;
+
+    fn snapshot(names: &[&str]) -> DirSnapshot {
+        names
+            .iter()
+            .map(|&name| {
+                let mut attr: stat = unsafe { mem::zeroed() };
+                attr.st_mode = libc::S_IFREG;
+                (CString::new(name).unwrap(), attr)
+            })
+            .collect()
+    }
+
+    /// Fill a buffer of `size` bytes by `f`, returning the names, offsets
+    /// and inode numbers added.
+    fn read(size: usize, f: impl FnOnce(&mut DirBuf<'_>)) -> Vec<(String, off_t, u64)> {
+        let mut buf = vec![0u8; size];
+        let mut dirbuf = DirBuf {
+            req: ptr::null_mut(),
+            buf: &mut buf[..],
+            pos: 0,
+            interrupt: Interrupt::current_or_detached(),
+        };
+        f(&mut dirbuf);
+        let len = dirbuf.pos;
+
+        decode_dirents(&buf[..len])
+            .into_iter()
+            .map(|entry| (entry.name.into_string().unwrap(), entry.offset, entry.ino))
+            .collect()
+    }
+
+    fn fill(snapshot: &DirSnapshot, offset: off_t, size: usize) -> Vec<(String, off_t)> {
+        read(size, |buf| snapshot.fill(offset, buf))
+            .into_iter()
+            .map(|(name, off, _)| (name, off))
+            .collect()
+    }
+
+    #[test]
+    fn fill_from_offset() {
+        let snapshot = snapshot(&["a", "b", "c"]);
+        assert_eq!(snapshot.len(), 3);
+
+        let all = fill(&snapshot, 0, 4096);
+        assert_eq!(all, vec![("a".into(), 1), ("b".into(), 2), ("c".into(), 3)]);
+        assert_eq!(fill(&snapshot, 2, 4096), vec![("c".into(), 3)]);
+        assert!(fill(&snapshot, 3, 4096).is_empty());
+    }
+
+    #[test]
+    fn fill_stops_when_full() {
+        let snapshot = snapshot(&["a", "b", "c"]);
+
+        // Each entry takes 32 bytes, so only two fit.
+        let first = fill(&snapshot, 0, 80);
+        assert_eq!(first, vec![("a".into(), 1), ("b".into(), 2)]);
+        let rest = fill(&snapshot, first.last().unwrap().1, 80);
+        assert_eq!(rest, vec![("c".into(), 3)]);
+    }
+
+    #[test]
+    fn empty_snapshot() {
+        let snapshot = DirSnapshot::new();
+        assert!(snapshot.is_empty());
+        assert!(fill(&snapshot, 0, 4096).is_empty());
+    }
+
+    fn add_entries(offset: off_t, size: usize) -> Vec<(String, off_t, u64)> {
+        let names = ["a", "b", "c"];
+        read(size, |buf| {
+            let entries = names.iter().enumerate().map(|(i, name)| {
+                let name = CString::new(*name).unwrap();
+                (Cow::Owned(name), FileType::Regular, (i + 2) as NodeId)
+            });
+            buf.add_entries(1, offset, entries);
+        })
+    }
+
+    #[test]
+    fn add_entries_with_dots() {
+        let all = add_entries(0, 4096);
+        let names: Vec<_> = all.iter().map(|(name, _, _)| &**name).collect();
+        assert_eq!(names, vec![".", "..", "a", "b", "c"]);
+        let offsets: Vec<_> = all.iter().map(|&(_, off, _)| off).collect();
+        assert_eq!(offsets, vec![1, 2, 3, 4, 5]);
+        assert_eq!(all[0].2, 1);
+        assert_eq!(all[2].2, 2);
+    }
+
+    #[test]
+    fn add_entries_resumes_from_offset() {
+        // Each entry takes 32 bytes, so three fit.
+        let first = add_entries(0, 100);
+        assert_eq!(first.len(), 3);
+        let rest = add_entries(first[2].1, 100);
+        let names: Vec<_> = rest.iter().map(|(name, _, _)| &**name).collect();
+        assert_eq!(names, vec!["b", "c"]);
+        assert!(add_entries(5, 100).is_empty());
+    }
+}
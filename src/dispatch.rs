@@ -0,0 +1,350 @@
+//! Reply-agnostic bodies of the operations.
+//!
+//! Each function calls the corresponding method of `Operations` with the
+//! arguments decoded from a request, and converts the result into a
+//! `Reply`. The callbacks registered to libfuse send the reply to the
+//! kernel, and `testing::Harness` returns it to the caller, so that both
+//! go through the same conversions.
+
+use crate::{
+    common::NodeId,
+    dir::{DirBuf, OpenDirOptions},
+    file::{
+        Entry, //
+        FlushOptions,
+        OpenOptions,
+        ReadOptions,
+        ReleaseOptions,
+        RenameFlags,
+        SetAttrs,
+        WriteOptions,
+        XAttrFlags,
+        XAttrReply,
+    },
+    interrupt::Interrupt,
+    ops::{OperationResult, Operations},
+};
+use libc::{c_int, dev_t, mode_t, off_t, stat, statvfs};
+use libfuse_sys::{fuse_file_info, fuse_req_t, helpers::fuse_file_info_fh};
+use std::{
+    borrow::Cow,
+    cmp,
+    ffi::{CStr, CString},
+};
+
+/// The reply to a request.
+pub(crate) enum Reply<'a> {
+    /// No reply, for `forget`.
+    None,
+    /// A successful reply without data.
+    Ok,
+    Entry(Entry),
+    Create(Entry, u64),
+    Attr(stat, f64),
+    Readlink(CString),
+    Open(u64),
+    Write(usize),
+    Buf(Cow<'a, [u8]>),
+    Statfs(statvfs),
+    Xattr(usize),
+}
+
+pub(crate) fn lookup<T: Operations>(
+    ops: &mut T,
+    parent: NodeId,
+    name: &CStr,
+) -> OperationResult<Reply<'static>> {
+    ops.lookup(parent, name).map(Reply::Entry)
+}
+
+pub(crate) fn forget<T: Operations>(
+    ops: &mut T,
+    id: NodeId,
+    nlookup: u64,
+) -> OperationResult<Reply<'static>> {
+    ops.forget(id, nlookup);
+    Ok(Reply::None)
+}
+
+pub(crate) fn getattr<T: Operations>(
+    ops: &mut T,
+    id: NodeId,
+    fh: Option<u64>,
+) -> OperationResult<Reply<'static>> {
+    let (attr, timeout) = ops.getattr(id, fh)?;
+    Ok(Reply::Attr(attr, timeout))
+}
+
+pub(crate) fn setattr<T: Operations>(
+    ops: &mut T,
+    id: NodeId,
+    attrs: &SetAttrs<'_>,
+    fh: Option<u64>,
+) -> OperationResult<Reply<'static>> {
+    let (attr, timeout) = ops.setattr(id, attrs, fh)?;
+    Ok(Reply::Attr(attr, timeout))
+}
+
+pub(crate) fn readlink<T: Operations>(ops: &mut T, id: NodeId) -> OperationResult<Reply<'static>> {
+    ops.readlink(id).map(Reply::Readlink)
+}
+
+pub(crate) fn mknod<T: Operations>(
+    ops: &mut T,
+    parent: NodeId,
+    name: &CStr,
+    mode: mode_t,
+    rdev: dev_t,
+) -> OperationResult<Reply<'static>> {
+    ops.mknod(parent, name, mode, rdev).map(Reply::Entry)
+}
+
+pub(crate) fn mkdir<T: Operations>(
+    ops: &mut T,
+    parent: NodeId,
+    name: &CStr,
+    mode: mode_t,
+) -> OperationResult<Reply<'static>> {
+    ops.mkdir(parent, name, mode).map(Reply::Entry)
+}
+
+pub(crate) fn unlink<T: Operations>(
+    ops: &mut T,
+    parent: NodeId,
+    name: &CStr,
+) -> OperationResult<Reply<'static>> {
+    ops.unlink(parent, name).map(|()| Reply::Ok)
+}
+
+pub(crate) fn rmdir<T: Operations>(
+    ops: &mut T,
+    parent: NodeId,
+    name: &CStr,
+) -> OperationResult<Reply<'static>> {
+    ops.rmdir(parent, name).map(|()| Reply::Ok)
+}
+
+pub(crate) fn symlink<T: Operations>(
+    ops: &mut T,
+    link: &CStr,
+    parent: NodeId,
+    name: &CStr,
+) -> OperationResult<Reply<'static>> {
+    ops.symlink(link, parent, name).map(Reply::Entry)
+}
+
+pub(crate) fn rename<T: Operations>(
+    ops: &mut T,
+    oldparent: NodeId,
+    oldname: &CStr,
+    newparent: NodeId,
+    newname: &CStr,
+    flags: RenameFlags,
+) -> OperationResult<Reply<'static>> {
+    ops.rename(oldparent, oldname, newparent, newname, flags)
+        .map(|()| Reply::Ok)
+}
+
+pub(crate) fn link<T: Operations>(
+    ops: &mut T,
+    id: NodeId,
+    newparent: NodeId,
+    newname: &CStr,
+) -> OperationResult<Reply<'static>> {
+    ops.link(id, newparent, newname).map(Reply::Entry)
+}
+
+pub(crate) fn open<T: Operations>(
+    ops: &mut T,
+    id: NodeId,
+    fi: &mut fuse_file_info,
+) -> OperationResult<Reply<'static>> {
+    ops.open(id, &mut OpenOptions(fi)).map(Reply::Open)
+}
+
+/// Read the data, truncated to `bufsize` bytes.
+pub(crate) fn read<'a, T: Operations>(
+    ops: &'a mut T,
+    id: NodeId,
+    offset: off_t,
+    bufsize: usize,
+    fi: &mut fuse_file_info,
+) -> OperationResult<Reply<'a>> {
+    let fh = unsafe { fuse_file_info_fh(fi) };
+    let mut options = ReadOptions(fi, Interrupt::current_or_detached());
+    let data = ops.read(id, offset, bufsize, &mut options, fh)?;
+    let data = match data {
+        Cow::Borrowed(data) => Cow::Borrowed(&data[..cmp::min(data.len(), bufsize)]),
+        Cow::Owned(mut data) => {
+            data.truncate(bufsize);
+            Cow::Owned(data)
+        }
+    };
+    Ok(Reply::Buf(data))
+}
+
+pub(crate) fn write<T: Operations>(
+    ops: &mut T,
+    id: NodeId,
+    data: &[u8],
+    offset: off_t,
+    fi: &mut fuse_file_info,
+) -> OperationResult<Reply<'static>> {
+    let fh = unsafe { fuse_file_info_fh(fi) };
+    let mut options = WriteOptions(fi, Interrupt::current_or_detached());
+    ops.write(id, data, offset, &mut options, fh)
+        .map(Reply::Write)
+}
+
+pub(crate) fn flush<T: Operations>(
+    ops: &mut T,
+    id: NodeId,
+    fi: &mut fuse_file_info,
+) -> OperationResult<Reply<'static>> {
+    let fh = unsafe { fuse_file_info_fh(fi) };
+    let mut options = FlushOptions(fi, Interrupt::current_or_detached());
+    ops.flush(id, &mut options, fh).map(|()| Reply::Ok)
+}
+
+pub(crate) fn release<T: Operations>(
+    ops: &mut T,
+    id: NodeId,
+    fi: &mut fuse_file_info,
+) -> OperationResult<Reply<'static>> {
+    let fh = unsafe { fuse_file_info_fh(fi) };
+    ops.release(id, &mut ReleaseOptions(fi), fh)
+        .map(|()| Reply::Ok)
+}
+
+pub(crate) fn fsync<T: Operations>(
+    ops: &mut T,
+    id: NodeId,
+    datasync: c_int,
+    fh: u64,
+) -> OperationResult<Reply<'static>> {
+    ops.fsync(id, datasync, fh).map(|()| Reply::Ok)
+}
+
+pub(crate) fn opendir<T: Operations>(
+    ops: &mut T,
+    id: NodeId,
+    fi: &mut fuse_file_info,
+) -> OperationResult<Reply<'static>> {
+    ops.opendir(id, &mut OpenDirOptions(fi)).map(Reply::Open)
+}
+
+/// Read the directory entries into a buffer of `size` bytes.
+///
+/// `req` is only passed to `fuse_add_direntry`, and may be null.
+pub(crate) fn readdir<T: Operations>(
+    ops: &mut T,
+    req: fuse_req_t,
+    id: NodeId,
+    size: usize,
+    offset: off_t,
+    fh: u64,
+) -> OperationResult<Reply<'static>> {
+    let mut buf = vec![0u8; size];
+    let mut dir_buf = DirBuf {
+        req,
+        buf: &mut buf[..],
+        pos: 0,
+        interrupt: Interrupt::current_or_detached(),
+    };
+    ops.readdir(id, offset, &mut dir_buf, fh)?;
+    let pos = dir_buf.pos;
+    buf.truncate(pos);
+    Ok(Reply::Buf(Cow::Owned(buf)))
+}
+
+pub(crate) fn releasedir<T: Operations>(
+    ops: &mut T,
+    id: NodeId,
+    fh: u64,
+) -> OperationResult<Reply<'static>> {
+    ops.releasedir(id, fh).map(|()| Reply::Ok)
+}
+
+pub(crate) fn fsyncdir<T: Operations>(
+    ops: &mut T,
+    id: NodeId,
+    datasync: c_int,
+    fh: u64,
+) -> OperationResult<Reply<'static>> {
+    ops.fsyncdir(id, datasync, fh).map(|()| Reply::Ok)
+}
+
+pub(crate) fn statfs<T: Operations>(ops: &mut T, id: NodeId) -> OperationResult<Reply<'static>> {
+    ops.statfs(id).map(Reply::Statfs)
+}
+
+pub(crate) fn setxattr<T: Operations>(
+    ops: &mut T,
+    id: NodeId,
+    name: &CStr,
+    value: &[u8],
+    flags: XAttrFlags,
+) -> OperationResult<Reply<'static>> {
+    ops.setxattr(id, name, value, flags).map(|()| Reply::Ok)
+}
+
+pub(crate) fn getxattr<'a, T: Operations>(
+    ops: &'a mut T,
+    id: NodeId,
+    name: &CStr,
+    size: usize,
+) -> OperationResult<Reply<'a>> {
+    let reply = ops.getxattr(id, name, size)?;
+    xattr(reply, size)
+}
+
+pub(crate) fn listxattr<T: Operations>(
+    ops: &mut T,
+    id: NodeId,
+    size: usize,
+) -> OperationResult<Reply<'_>> {
+    let reply = ops.listxattr(id, size)?;
+    xattr(reply, size)
+}
+
+pub(crate) fn removexattr<T: Operations>(
+    ops: &mut T,
+    id: NodeId,
+    name: &CStr,
+) -> OperationResult<Reply<'static>> {
+    ops.removexattr(id, name).map(|()| Reply::Ok)
+}
+
+pub(crate) fn access<T: Operations>(
+    ops: &mut T,
+    id: NodeId,
+    mask: c_int,
+) -> OperationResult<Reply<'static>> {
+    ops.access(id, mask).map(|()| Reply::Ok)
+}
+
+pub(crate) fn create<T: Operations>(
+    ops: &mut T,
+    parent: NodeId,
+    name: &CStr,
+    mode: mode_t,
+    fi: &mut fuse_file_info,
+) -> OperationResult<Reply<'static>> {
+    let (entry, fh) = ops.create(parent, name, mode, &mut OpenOptions(fi))?;
+    Ok(Reply::Create(entry, fh))
+}
+
+/// Convert the reply of `getxattr` or `listxattr` for the buffer of
+/// `size` bytes.
+///
+/// The kernel passes zero as `size` to query the size of the value, so
+/// the length of the data is replied in that case. Otherwise, the data
+/// larger than the buffer results in `ERANGE`.
+fn xattr(reply: XAttrReply<'_>, size: usize) -> OperationResult<Reply<'_>> {
+    match reply {
+        XAttrReply::Size(size) => Ok(Reply::Xattr(size)),
+        XAttrReply::Data(data) if size == 0 => Ok(Reply::Xattr(data.len())),
+        XAttrReply::Data(data) if data.len() <= size => Ok(Reply::Buf(data)),
+        XAttrReply::Data(..) => Err(libc::ERANGE),
+    }
+}
@@ -0,0 +1,580 @@
+//! A layer that injects faults into the operations.
+
+use crate::{
+    common::NodeId,
+    dir::{DirBuf, DirEntries, OpenDirOptions},
+    file::{
+        Entry, //
+        FlushOptions,
+        OpenOptions,
+        ReadOptions,
+        ReleaseOptions,
+        RenameFlags,
+        SetAttrs,
+        WriteOptions,
+        XAttrFlags,
+        XAttrReply,
+    },
+    layer::Layer,
+    ops::{OperationResult, Operations},
+};
+use libc::{c_int, dev_t, mode_t, off_t, stat, statvfs};
+use std::{
+    borrow::Cow,
+    cmp,
+    ffi::{CStr, CString},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// A fault to be injected, along with the condition when it occurs.
+#[derive(Debug, Clone)]
+pub struct Fault {
+    kind: FaultKind,
+    op: Option<String>,
+    node: Option<NodeId>,
+    probability: f64,
+    times: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum FaultKind {
+    Errno(c_int),
+    ShortIo(usize),
+    Delay(Duration),
+}
+
+impl Fault {
+    fn new(kind: FaultKind) -> Self {
+        Self {
+            kind,
+            op: None,
+            node: None,
+            probability: 1.0,
+            times: None,
+        }
+    }
+
+    /// Fail the operation with the error number.
+    pub fn errno(errno: c_int) -> Self {
+        Self::new(FaultKind::Errno(errno))
+    }
+
+    /// Limit the size of the data read or written by `read` and `write`
+    /// to `size` bytes.
+    ///
+    /// The fault is ignored by the other operations.
+    pub fn short_io(size: usize) -> Self {
+        Self::new(FaultKind::ShortIo(size))
+    }
+
+    /// Delay the operation by the duration before it is forwarded.
+    pub fn delay(duration: Duration) -> Self {
+        Self::new(FaultKind::Delay(duration))
+    }
+
+    /// Inject the fault only into the operation, e.g. `"read"`.
+    pub fn op(mut self, op: &str) -> Self {
+        self.op = Some(op.into());
+        self
+    }
+
+    /// Inject the fault only into the operations on the node.
+    ///
+    /// The node is the parent directory for the operations which take
+    /// a name, e.g. `lookup` and `mkdir`.
+    pub fn node(mut self, node: NodeId) -> Self {
+        self.node = Some(node);
+        self
+    }
+
+    /// Inject the fault with the probability between 0.0 and 1.0.
+    pub fn probability(mut self, probability: f64) -> Self {
+        self.probability = probability;
+        self
+    }
+
+    /// Remove the fault after it has been injected `times` times.
+    pub fn times(mut self, times: u64) -> Self {
+        self.times = Some(times);
+        self
+    }
+
+    fn matches(&self, op: &str, node: NodeId) -> bool {
+        match (&self.op, self.node) {
+            (Some(o), _) if o != op => false,
+            (_, Some(n)) if n != node => false,
+            _ => true,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    faults: Vec<Fault>,
+    rng: u64,
+}
+
+impl State {
+    fn seed(&mut self, seed: u64) {
+        // xorshift gets stuck at zero.
+        self.rng = if seed == 0 {
+            0x853c_49e6_748f_ea9b
+        } else {
+            seed
+        };
+    }
+
+    fn next_f64(&mut self) -> f64 {
+        let mut x = self.rng;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.rng = x;
+        (x >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// A handle to configure the faults of a `FaultInjector`.
+///
+/// The handle can be cloned and used from another thread while the
+/// session is running.
+#[derive(Debug, Clone)]
+pub struct FaultHandle {
+    state: Arc<Mutex<State>>,
+}
+
+impl FaultHandle {
+    /// Add a fault.
+    ///
+    /// The faults are checked in the order they were added. All matching
+    /// delays are applied, and the first matching error or short I/O is
+    /// injected.
+    pub fn inject(&self, fault: Fault) {
+        self.state.lock().unwrap().faults.push(fault);
+    }
+
+    /// Remove all faults.
+    pub fn clear(&self) {
+        self.state.lock().unwrap().faults.clear();
+    }
+
+    /// Reseed the random number generator used for the probabilities,
+    /// to make the injection reproducible.
+    pub fn seed(&self, seed: u64) {
+        self.state.lock().unwrap().seed(seed);
+    }
+}
+
+/// A layer that injects faults into the operations of the inner
+/// filesystem, for testing how applications handle them.
+///
+/// No faults are injected until they are added via `FaultHandle`.
+/// `init`, `destroy` and `forget` are always forwarded as is, and so are
+/// `release` and `releasedir` whose results are replaced by the injected
+/// errors, so that the inner filesystem does not leak the handles.
+#[derive(Debug)]
+pub struct FaultInjector<T: Operations> {
+    inner: T,
+    state: Arc<Mutex<State>>,
+}
+
+impl<T: Operations> FaultInjector<T> {
+    pub fn new(inner: T) -> Self {
+        let mut state = State {
+            faults: vec![],
+            rng: 0,
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        state.seed(now.as_secs() ^ u64::from(now.subsec_nanos()));
+        Self {
+            inner,
+            state: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    /// Returns a handle to configure the faults.
+    pub fn handle(&self) -> FaultHandle {
+        FaultHandle {
+            state: self.state.clone(),
+        }
+    }
+
+    /// Consume the layer, returning the inner filesystem.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    /// Apply the faults matching the operation. Returns the limit of the
+    /// data size if a short I/O is injected.
+    fn inject(&self, op: &str, node: NodeId) -> OperationResult<Option<usize>> {
+        let mut delay = Duration::default();
+        let mut result = Ok(None);
+        {
+            let state = &mut *self.state.lock().unwrap();
+            let mut i = 0;
+            while i < state.faults.len() {
+                let fault = &state.faults[i];
+                if !fault.matches(op, node) {
+                    i += 1;
+                    continue;
+                }
+                match fault.kind {
+                    FaultKind::Errno(..) | FaultKind::ShortIo(..) if result != Ok(None) => {
+                        i += 1;
+                        continue;
+                    }
+                    FaultKind::ShortIo(..) if op != "read" && op != "write" => {
+                        i += 1;
+                        continue;
+                    }
+                    _ => (),
+                }
+                let probability = fault.probability;
+                if probability < 1.0 && state.next_f64() >= probability {
+                    i += 1;
+                    continue;
+                }
+
+                let fault = &mut state.faults[i];
+                match fault.kind {
+                    FaultKind::Errno(errno) => result = Err(errno),
+                    FaultKind::ShortIo(size) => result = Ok(Some(size)),
+                    FaultKind::Delay(duration) => delay += duration,
+                }
+                if let Some(times) = fault.times.as_mut() {
+                    if *times <= 1 {
+                        state.faults.remove(i);
+                        continue;
+                    }
+                    *times -= 1;
+                }
+                i += 1;
+            }
+        }
+        if delay > Duration::default() {
+            thread::sleep(delay);
+        }
+        result
+    }
+}
+
+impl<T: Operations> Layer for FaultInjector<T> {
+    type Inner = T;
+
+    fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    fn inner_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    fn lookup(&mut self, parent: NodeId, name: &CStr) -> OperationResult<Entry> {
+        self.inject("lookup", parent)?;
+        self.inner.lookup(parent, name)
+    }
+
+    fn readlink(&mut self, id: NodeId) -> OperationResult<CString> {
+        self.inject("readlink", id)?;
+        self.inner.readlink(id)
+    }
+
+    fn mknod(
+        &mut self,
+        parent: NodeId,
+        name: &CStr,
+        mode: mode_t,
+        rdev: dev_t,
+    ) -> OperationResult<Entry> {
+        self.inject("mknod", parent)?;
+        self.inner.mknod(parent, name, mode, rdev)
+    }
+
+    fn mkdir(&mut self, parent: NodeId, name: &CStr, mode: mode_t) -> OperationResult<Entry> {
+        self.inject("mkdir", parent)?;
+        self.inner.mkdir(parent, name, mode)
+    }
+
+    fn unlink(&mut self, parent: NodeId, name: &CStr) -> OperationResult<()> {
+        self.inject("unlink", parent)?;
+        self.inner.unlink(parent, name)
+    }
+
+    fn rmdir(&mut self, parent: NodeId, name: &CStr) -> OperationResult<()> {
+        self.inject("rmdir", parent)?;
+        self.inner.rmdir(parent, name)
+    }
+
+    fn symlink(&mut self, link: &CStr, parent: NodeId, name: &CStr) -> OperationResult<Entry> {
+        self.inject("symlink", parent)?;
+        self.inner.symlink(link, parent, name)
+    }
+
+    fn rename(
+        &mut self,
+        oldparent: NodeId,
+        oldname: &CStr,
+        newparent: NodeId,
+        newname: &CStr,
+        flags: RenameFlags,
+    ) -> OperationResult<()> {
+        self.inject("rename", oldparent)?;
+        self.inner
+            .rename(oldparent, oldname, newparent, newname, flags)
+    }
+
+    fn link(&mut self, id: NodeId, newparent: NodeId, newname: &CStr) -> OperationResult<Entry> {
+        self.inject("link", id)?;
+        self.inner.link(id, newparent, newname)
+    }
+
+    fn statfs(&mut self, id: NodeId) -> OperationResult<statvfs> {
+        self.inject("statfs", id)?;
+        self.inner.statfs(id)
+    }
+
+    fn setxattr(
+        &mut self,
+        id: NodeId,
+        name: &CStr,
+        value: &[u8],
+        flags: XAttrFlags,
+    ) -> OperationResult<()> {
+        self.inject("setxattr", id)?;
+        self.inner.setxattr(id, name, value, flags)
+    }
+
+    fn getxattr(
+        &mut self,
+        id: NodeId,
+        name: &CStr,
+        size: usize,
+    ) -> OperationResult<XAttrReply<'_>> {
+        self.inject("getxattr", id)?;
+        self.inner.getxattr(id, name, size)
+    }
+
+    fn listxattr(&mut self, id: NodeId, size: usize) -> OperationResult<XAttrReply<'_>> {
+        self.inject("listxattr", id)?;
+        self.inner.listxattr(id, size)
+    }
+
+    fn removexattr(&mut self, id: NodeId, name: &CStr) -> OperationResult<()> {
+        self.inject("removexattr", id)?;
+        self.inner.removexattr(id, name)
+    }
+
+    fn access(&mut self, id: NodeId, mask: c_int) -> OperationResult<()> {
+        self.inject("access", id)?;
+        self.inner.access(id, mask)
+    }
+
+    fn open(&mut self, id: NodeId, options: &mut OpenOptions<'_>) -> OperationResult<u64> {
+        self.inject("open", id)?;
+        self.inner.open(id, options)
+    }
+
+    fn create(
+        &mut self,
+        parent: NodeId,
+        name: &CStr,
+        mode: mode_t,
+        options: &mut OpenOptions<'_>,
+    ) -> OperationResult<(Entry, u64)> {
+        self.inject("create", parent)?;
+        self.inner.create(parent, name, mode, options)
+    }
+
+    fn read(
+        &mut self,
+        id: NodeId,
+        off: off_t,
+        bufsize: usize,
+        opts: &mut ReadOptions<'_>,
+        fh: u64,
+    ) -> OperationResult<Cow<'_, [u8]>> {
+        let limit = self.inject("read", id)?;
+        let bufsize = limit.map_or(bufsize, |limit| cmp::min(bufsize, limit));
+        let data = self.inner.read(id, off, bufsize, opts, fh)?;
+        Ok(match data {
+            Cow::Borrowed(data) if data.len() > bufsize => Cow::Borrowed(&data[..bufsize]),
+            Cow::Owned(mut data) => {
+                data.truncate(bufsize);
+                Cow::Owned(data)
+            }
+            data => data,
+        })
+    }
+
+    fn write(
+        &mut self,
+        id: NodeId,
+        buf: &[u8],
+        off: off_t,
+        opts: &mut WriteOptions<'_>,
+        fh: u64,
+    ) -> OperationResult<usize> {
+        let limit = self.inject("write", id)?;
+        let buf = &buf[..limit.map_or(buf.len(), |limit| cmp::min(buf.len(), limit))];
+        self.inner.write(id, buf, off, opts, fh)
+    }
+
+    fn flush(&mut self, id: NodeId, opts: &mut FlushOptions<'_>, fh: u64) -> OperationResult<()> {
+        self.inject("flush", id)?;
+        self.inner.flush(id, opts, fh)
+    }
+
+    fn getattr(&mut self, id: NodeId, fh: Option<u64>) -> OperationResult<(stat, f64)> {
+        self.inject("getattr", id)?;
+        self.inner.getattr(id, fh)
+    }
+
+    fn setattr(
+        &mut self,
+        id: NodeId,
+        attrs: &SetAttrs<'_>,
+        fh: Option<u64>,
+    ) -> OperationResult<(stat, f64)> {
+        self.inject("setattr", id)?;
+        self.inner.setattr(id, attrs, fh)
+    }
+
+    fn fsync(&mut self, id: NodeId, datasync: c_int, fh: u64) -> OperationResult<()> {
+        self.inject("fsync", id)?;
+        self.inner.fsync(id, datasync, fh)
+    }
+
+    fn release(
+        &mut self,
+        id: NodeId,
+        options: &mut ReleaseOptions<'_>,
+        fh: u64,
+    ) -> OperationResult<()> {
+        let injected = self.inject("release", id);
+        self.inner.release(id, options, fh)?;
+        injected.map(drop)
+    }
+
+    fn opendir(&mut self, id: NodeId, options: &mut OpenDirOptions) -> OperationResult<u64> {
+        self.inject("opendir", id)?;
+        self.inner.opendir(id, options)
+    }
+
+    fn readdir(
+        &mut self,
+        id: NodeId,
+        offset: off_t,
+        buf: &mut DirBuf<'_>,
+        fh: u64,
+    ) -> OperationResult<()> {
+        self.inject("readdir", id)?;
+        self.inner.readdir(id, offset, buf, fh)
+    }
+
+    fn readdir_entries(&mut self, id: NodeId, fh: u64) -> OperationResult<DirEntries<'_>> {
+        self.inject("readdir_entries", id)?;
+        self.inner.readdir_entries(id, fh)
+    }
+
+    fn fsyncdir(&mut self, id: NodeId, datasync: c_int, fh: u64) -> OperationResult<()> {
+        self.inject("fsyncdir", id)?;
+        self.inner.fsyncdir(id, datasync, fh)
+    }
+
+    fn releasedir(&mut self, id: NodeId, fh: u64) -> OperationResult<()> {
+        let injected = self.inject("releasedir", id);
+        self.inner.releasedir(id, fh)?;
+        injected.map(drop)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct Counter {
+        lookups: usize,
+        releases: usize,
+    }
+
+    impl Operations for Counter {
+        fn lookup(&mut self, _: NodeId, _: &CStr) -> OperationResult<Entry> {
+            self.lookups += 1;
+            Ok(Entry::default())
+        }
+
+        fn read(
+            &mut self,
+            _: NodeId,
+            _: off_t,
+            bufsize: usize,
+            _: &mut ReadOptions<'_>,
+            _: u64,
+        ) -> OperationResult<Cow<'_, [u8]>> {
+            Ok(Cow::Owned(vec![0; bufsize]))
+        }
+
+        fn releasedir(&mut self, _: NodeId, _: u64) -> OperationResult<()> {
+            self.releases += 1;
+            Ok(())
+        }
+    }
+
+    fn name() -> &'static CStr {
+        CStr::from_bytes_with_nul(b"name\0").unwrap()
+    }
+
+    #[test]
+    fn errno_with_filters() {
+        let mut fs = FaultInjector::new(Counter::default());
+        fs.handle()
+            .inject(Fault::errno(libc::EIO).op("lookup").node(2).times(2));
+
+        assert!(Operations::lookup(&mut fs, 1, name()).is_ok());
+        assert_eq!(
+            Operations::lookup(&mut fs, 2, name()).err(),
+            Some(libc::EIO)
+        );
+        assert_eq!(
+            Operations::lookup(&mut fs, 2, name()).err(),
+            Some(libc::EIO)
+        );
+        assert!(Operations::lookup(&mut fs, 2, name()).is_ok());
+        assert_eq!(fs.inner().lookups, 2);
+    }
+
+    #[test]
+    fn probability_is_reproducible() {
+        let run = || {
+            let mut fs = FaultInjector::new(Counter::default());
+            let handle = fs.handle();
+            handle.seed(42);
+            handle.inject(Fault::errno(libc::EIO).probability(0.5));
+            (0..64)
+                .map(|_| Operations::lookup(&mut fs, 1, name()).is_err())
+                .collect::<Vec<_>>()
+        };
+        let results = run();
+        assert_eq!(results, run());
+        assert!(results.iter().any(|&failed| failed));
+        assert!(results.iter().any(|&failed| !failed));
+    }
+
+    #[test]
+    fn release_is_always_forwarded() {
+        let mut fs = FaultInjector::new(Counter::default());
+        fs.handle().inject(Fault::errno(libc::EIO));
+
+        assert_eq!(Operations::releasedir(&mut fs, 1, 1).err(), Some(libc::EIO));
+        assert_eq!(fs.inner().releases, 1);
+
+        fs.handle().clear();
+        assert!(Operations::releasedir(&mut fs, 1, 2).is_ok());
+        assert_eq!(fs.inner().releases, 2);
+    }
+}
@@ -1,4 +1,4 @@
-use crate::common::NodeId;
+use crate::{common::NodeId, interrupt::Interrupt};
 use bitflags::bitflags;
 use libc::{c_int, gid_t, mode_t, stat, timespec, uid_t};
 use libfuse_sys::{
@@ -37,6 +37,47 @@ impl Default for Entry {
     }
 }
 
+/// The type of a file.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum FileType {
+    Regular,
+    Directory,
+    Symlink,
+    CharDevice,
+    BlockDevice,
+    Fifo,
+    Socket,
+}
+
+impl FileType {
+    /// Returns the type from the file mode, or `None` if the type bits are unknown.
+    pub fn from_mode(mode: mode_t) -> Option<Self> {
+        match mode & libc::S_IFMT {
+            libc::S_IFREG => Some(FileType::Regular),
+            libc::S_IFDIR => Some(FileType::Directory),
+            libc::S_IFLNK => Some(FileType::Symlink),
+            libc::S_IFCHR => Some(FileType::CharDevice),
+            libc::S_IFBLK => Some(FileType::BlockDevice),
+            libc::S_IFIFO => Some(FileType::Fifo),
+            libc::S_IFSOCK => Some(FileType::Socket),
+            _ => None,
+        }
+    }
+
+    /// Returns the type bits of the file mode.
+    pub fn mode(self) -> mode_t {
+        match self {
+            FileType::Regular => libc::S_IFREG,
+            FileType::Directory => libc::S_IFDIR,
+            FileType::Symlink => libc::S_IFLNK,
+            FileType::CharDevice => libc::S_IFCHR,
+            FileType::BlockDevice => libc::S_IFBLK,
+            FileType::Fifo => libc::S_IFIFO,
+            FileType::Socket => libc::S_IFSOCK,
+        }
+    }
+}
+
 pub struct OpenOptions<'a>(pub(crate) &'a mut fuse_file_info);
 
 impl<'a> OpenOptions<'a> {
@@ -66,9 +107,14 @@ impl<'a> OpenOptions<'a> {
     }
 }
 
-pub struct ReadOptions<'a>(pub(crate) &'a mut fuse_file_info);
+pub struct ReadOptions<'a>(pub(crate) &'a mut fuse_file_info, pub(crate) Interrupt);
 
 impl<'a> ReadOptions<'a> {
+    /// Returns the token to observe the interruption of this request.
+    pub fn interrupt(&self) -> &Interrupt {
+        &self.1
+    }
+
     pub fn flags(&self) -> c_int {
         unsafe { fuse_file_info_flags(self.0) }
     }
@@ -78,9 +124,14 @@ impl<'a> ReadOptions<'a> {
     }
 }
 
-pub struct WriteOptions<'a>(pub(crate) &'a mut fuse_file_info);
+pub struct WriteOptions<'a>(pub(crate) &'a mut fuse_file_info, pub(crate) Interrupt);
 
 impl<'a> WriteOptions<'a> {
+    /// Returns the token to observe the interruption of this request.
+    pub fn interrupt(&self) -> &Interrupt {
+        &self.1
+    }
+
     pub fn flags(&self) -> c_int {
         unsafe { fuse_file_info_flags(self.0) }
     }
@@ -94,9 +145,14 @@ impl<'a> WriteOptions<'a> {
     }
 }
 
-pub struct FlushOptions<'a>(pub(crate) &'a mut fuse_file_info);
+pub struct FlushOptions<'a>(pub(crate) &'a mut fuse_file_info, pub(crate) Interrupt);
 
 impl<'a> FlushOptions<'a> {
+    /// Returns the token to observe the interruption of this request.
+    pub fn interrupt(&self) -> &Interrupt {
+        &self.1
+    }
+
     pub fn lock_owner(&self) -> u64 {
         unsafe { fuse_file_info_lock_owner(self.0) }
     }
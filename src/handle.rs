@@ -0,0 +1,220 @@
+//! Management of the file handles.
+
+use crate::snapshot::{self, Reader};
+use std::io;
+
+/// A table that maps the file handles passed to the kernel to typed objects.
+///
+/// The handle returned by `insert` is intended to be returned from `open`,
+/// `create` or `opendir`, and the kernel passes it back as `fh` to the
+/// subsequent operations on the opened file. The object should be removed
+/// in `release` or `releasedir`, which drops it.
+///
+/// Zero is never used as a handle, so it can be distinguished from the
+/// default value returned by `Operations::open`.
+#[derive(Debug)]
+pub struct HandleTable<H> {
+    slots: Vec<Option<H>>,
+    free: Vec<usize>,
+    len: usize,
+}
+
+impl<H> Default for HandleTable<H> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<H> HandleTable<H> {
+    /// Create an empty table.
+    pub fn new() -> Self {
+        Self {
+            slots: vec![],
+            free: vec![],
+            len: 0,
+        }
+    }
+
+    /// Insert an object, returning the allocated handle.
+    pub fn insert(&mut self, handle: H) -> u64 {
+        self.len += 1;
+        match self.free.pop() {
+            Some(i) => {
+                self.slots[i] = Some(handle);
+                i as u64 + 1
+            }
+            None => {
+                self.slots.push(Some(handle));
+                self.slots.len() as u64
+            }
+        }
+    }
+
+    /// Returns a reference to the object associated with the handle.
+    pub fn get(&self, fh: u64) -> Option<&H> {
+        self.slots.get(index(fh)?)?.as_ref()
+    }
+
+    /// Returns a mutable reference to the object associated with the handle.
+    pub fn get_mut(&mut self, fh: u64) -> Option<&mut H> {
+        self.slots.get_mut(index(fh)?)?.as_mut()
+    }
+
+    /// Remove the object associated with the handle, and return it.
+    ///
+    /// The handle may be reused by the subsequent `insert`.
+    pub fn remove(&mut self, fh: u64) -> Option<H> {
+        let i = index(fh)?;
+        let handle = self.slots.get_mut(i)?.take()?;
+        self.free.push(i);
+        self.len -= 1;
+        Some(handle)
+    }
+
+    /// Returns the number of objects in the table.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns whether the table is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns an iterator over the handles and the objects.
+    pub fn iter(&self) -> impl Iterator<Item = (u64, &H)> {
+        self.slots
+            .iter()
+            .enumerate()
+            .filter_map(|(i, slot)| slot.as_ref().map(|handle| (i as u64 + 1, handle)))
+    }
+
+    /// Serialize the table, so that it can be restored by `restore` in
+    /// another process taking over the session.
+    ///
+    /// The handles are preserved, since the kernel keeps passing them to
+    /// the operations on the opened files. Each object is appended to the
+    /// buffer by `encode`.
+    pub fn snapshot(&self, mut encode: impl FnMut(&H, &mut Vec<u8>)) -> Vec<u8> {
+        let mut buf = vec![];
+        snapshot::put_u64(&mut buf, self.slots.len() as u64);
+        let mut value = vec![];
+        for slot in &self.slots {
+            match slot {
+                Some(handle) => {
+                    snapshot::put_u64(&mut buf, 1);
+                    value.clear();
+                    encode(handle, &mut value);
+                    snapshot::put_bytes(&mut buf, &value);
+                }
+                None => snapshot::put_u64(&mut buf, 0),
+            }
+        }
+        buf
+    }
+
+    /// Restore a table serialized by `snapshot`, decoding each object by
+    /// `decode`.
+    pub fn restore(
+        snapshot: &[u8],
+        mut decode: impl FnMut(&[u8]) -> io::Result<H>,
+    ) -> io::Result<Self> {
+        let mut reader = Reader::new(snapshot);
+        let nslots = reader.u64()?;
+        let mut table = Self::new();
+        for i in 0..nslots {
+            let slot = match reader.u64()? {
+                0 => None,
+                1 => Some(decode(reader.bytes()?)?),
+                _ => return Err(snapshot::malformed()),
+            };
+            match slot {
+                Some(..) => table.len += 1,
+                None => table.free.push(i as usize),
+            }
+            table.slots.push(slot);
+        }
+        reader.finish()?;
+
+        // Reuse the smallest handles first.
+        table.free.reverse();
+        Ok(table)
+    }
+}
+
+fn index(fh: u64) -> Option<usize> {
+    fh.checked_sub(1).map(|i| i as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn insert_and_remove() {
+        let mut table = HandleTable::new();
+        let a = table.insert("a");
+        let b = table.insert("b");
+        assert_ne!(a, 0);
+        assert_ne!(a, b);
+        assert_eq!(table.len(), 2);
+
+        assert_eq!(table.get(a), Some(&"a"));
+        *table.get_mut(b).unwrap() = "c";
+        assert_eq!(table.get(b), Some(&"c"));
+
+        assert_eq!(table.remove(a), Some("a"));
+        assert_eq!(table.remove(a), None);
+        assert_eq!(table.get(a), None);
+        assert_eq!(table.len(), 1);
+
+        let handles: Vec<_> = table.iter().collect();
+        assert_eq!(handles, vec![(b, &"c")]);
+    }
+
+    #[test]
+    fn handles_are_reused() {
+        let mut table = HandleTable::new();
+        let a = table.insert(1);
+        table.insert(2);
+        table.remove(a);
+        assert_eq!(table.insert(3), a);
+        assert_eq!(table.get(a), Some(&3));
+    }
+
+    #[test]
+    fn snapshot_and_restore() {
+        let mut table = HandleTable::new();
+        let a = table.insert(1u64);
+        let b = table.insert(2);
+        table.remove(a);
+
+        let snapshot = table.snapshot(|&n, buf| buf.extend_from_slice(&n.to_le_bytes()));
+        let mut restored = HandleTable::restore(&snapshot, |data| {
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(data);
+            Ok(u64::from_le_bytes(bytes))
+        })
+        .unwrap();
+        assert_eq!(restored.len(), 1);
+        assert_eq!(restored.get(a), None);
+        assert_eq!(restored.get(b), Some(&2));
+        assert_eq!(restored.insert(3), a);
+
+        assert!(HandleTable::<u64>::restore(b"", |_| Ok(0)).is_err());
+    }
+
+    #[test]
+    fn invalid_handles() {
+        let mut table = HandleTable::new();
+        table.insert(());
+        assert_eq!(table.get(0), None);
+        assert_eq!(table.remove(0), None);
+        assert_eq!(table.get(42), None);
+        assert_eq!(table.remove(42), None);
+        assert!(!table.is_empty());
+
+        table.remove(1);
+        assert!(table.is_empty());
+    }
+}
@@ -0,0 +1,318 @@
+//! Handing off a mounted session to another process.
+//!
+//! The kernel sends the `INIT` request only once per mount, so the session
+//! built with `Builder::handoff` records it while serving the mount, and
+//! the process taking over the session replays it to libfuse. The reply to
+//! the replayed request is dropped instead of being written to the kernel,
+//! which has already negotiated the connection.
+
+use crate::{
+    ops::{Operations, SharedContext},
+    snapshot::{self, Reader},
+};
+use libc::{c_int, c_void, iovec, size_t, ssize_t};
+use std::{
+    fmt, io, mem,
+    os::unix::io::{AsFd, BorrowedFd, OwnedFd},
+    slice,
+    sync::{Mutex, PoisonError},
+};
+
+const FUSE_INIT: u32 = 26;
+
+/// The size of `struct fuse_in_header`.
+const IN_HEADER_LEN: usize = 40;
+
+/// The size of `struct fuse_out_header`.
+const OUT_HEADER_LEN: usize = 16;
+
+/// The unique ID of the replayed `INIT` request.
+///
+/// The kernel allocates the IDs from zero by steps of two, so it never
+/// reaches this value.
+const REPLAYED_UNIQUE: u64 = u64::MAX;
+
+/// The connection of a session handed off to another process.
+///
+/// This is created by `Session::export` and passed to `Session::resume`.
+/// It consists of the file descriptor connected to the kernel, and a
+/// snapshot of the negotiated connection and of the state returned by
+/// `Operations::export`. The file descriptor can be passed to the other
+/// process with `SCM_RIGHTS`, or inherited through `exec(2)` after clearing
+/// its `FD_CLOEXEC` flag, and the snapshot in any way.
+pub struct Handoff {
+    fd: OwnedFd,
+    init: Vec<u8>,
+    state: Vec<u8>,
+}
+
+impl fmt::Debug for Handoff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Handoff").field("fd", &self.fd).finish()
+    }
+}
+
+impl AsFd for Handoff {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+impl Handoff {
+    pub(crate) fn new(fd: OwnedFd, init: Vec<u8>, state: Vec<u8>) -> Self {
+        Self { fd, init, state }
+    }
+
+    /// Create from the parts returned by `into_parts`.
+    pub fn from_parts(fd: OwnedFd, snapshot: &[u8]) -> io::Result<Self> {
+        let mut reader = Reader::new(snapshot);
+        let init = reader.bytes()?.to_vec();
+        let state = reader.bytes()?.to_vec();
+        reader.finish()?;
+        if !is_init(&init) {
+            return Err(snapshot::malformed());
+        }
+        Ok(Self { fd, init, state })
+    }
+
+    /// Split into the file descriptor connected to the kernel, and the
+    /// snapshot to be passed along with it.
+    pub fn into_parts(self) -> (OwnedFd, Vec<u8>) {
+        let mut snapshot = vec![];
+        snapshot::put_bytes(&mut snapshot, &self.init);
+        snapshot::put_bytes(&mut snapshot, &self.state);
+        (self.fd, snapshot)
+    }
+
+    /// Returns the state of the filesystem returned by `Operations::export`
+    /// in the previous process, from which the filesystem taking over the
+    /// session is restored.
+    pub fn state(&self) -> &[u8] {
+        &self.state
+    }
+
+    pub(crate) fn into_init(self) -> (OwnedFd, Vec<u8>) {
+        (self.fd, self.init)
+    }
+}
+
+/// Returns whether `msg` is a complete `INIT` request.
+fn is_init(msg: &[u8]) -> bool {
+    msg.len() >= IN_HEADER_LEN
+        && read_u32(msg, 0) as usize == msg.len()
+        && read_u32(msg, 4) == FUSE_INIT
+}
+
+/// Rewrite the unique ID of the recorded `INIT` request, so that its reply
+/// is dropped by `writev`.
+pub(crate) fn make_replayed(init: &mut [u8]) {
+    init[8..16].copy_from_slice(&REPLAYED_UNIQUE.to_ne_bytes());
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(&buf[offset..offset + 4]);
+    u32::from_ne_bytes(bytes)
+}
+
+/// The `INIT` request received from the kernel.
+#[derive(Default)]
+pub(crate) struct Capture {
+    init: Mutex<Option<Vec<u8>>>,
+}
+
+impl Capture {
+    pub(crate) fn get(&self) -> Option<Vec<u8>> {
+        self.init
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    pub(crate) fn set(&self, init: Vec<u8>) {
+        *self.init.lock().unwrap_or_else(PoisonError::into_inner) = Some(init);
+    }
+}
+
+/// The replacement of `read(2)` installed to the session, which records
+/// the `INIT` request.
+pub(crate) unsafe extern "C" fn read<T: Operations>(
+    fd: c_int,
+    buf: *mut c_void,
+    len: size_t,
+    user_data: *mut c_void,
+) -> ssize_t {
+    let res = libc::read(fd, buf, len);
+    if res > 0 {
+        let msg = slice::from_raw_parts(buf as *const u8, res as usize);
+        if is_init(msg) {
+            SharedContext::<T>::from_user_data(user_data)
+                .capture()
+                .set(msg.to_vec());
+        }
+    }
+    res
+}
+
+/// The replacement of `writev(2)` installed to the session, which drops
+/// the reply to the replayed `INIT` request.
+pub(crate) unsafe extern "C" fn writev(
+    fd: c_int,
+    iov: *mut iovec,
+    count: c_int,
+    _user_data: *mut c_void,
+) -> ssize_t {
+    let iovs = slice::from_raw_parts(iov, count as usize);
+    if let Some(header) = iovs.first() {
+        if header.iov_len >= OUT_HEADER_LEN {
+            let header = slice::from_raw_parts(header.iov_base as *const u8, OUT_HEADER_LEN);
+            let mut unique = [0u8; mem::size_of::<u64>()];
+            unique.copy_from_slice(&header[8..16]);
+            if u64::from_ne_bytes(unique) == REPLAYED_UNIQUE {
+                return iovs.iter().map(|iov| iov.iov_len).sum::<usize>() as ssize_t;
+            }
+        }
+    }
+    libc::writev(fd, iov, count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ops::Context;
+    use libfuse_sys::helpers::fuse_conn_info_new;
+    use std::{
+        os::unix::io::{AsRawFd, FromRawFd},
+        ptr,
+    };
+
+    struct Nop;
+
+    impl Operations for Nop {}
+
+    #[derive(Default)]
+    struct Destroyed(bool);
+
+    impl Operations for Destroyed {
+        fn destroy(&mut self) -> io::Result<()> {
+            self.0 = true;
+            Ok(())
+        }
+    }
+
+    fn initialized<T: Operations>(ops: T) -> Context<T> {
+        let mut ctx = Context::new(ops, None);
+        unsafe {
+            let conn = fuse_conn_info_new();
+            assert!(!conn.is_null(), "no memory space");
+            ctx.init(&mut *conn);
+            libc::free(conn as *mut _);
+        }
+        ctx
+    }
+
+    fn init_request() -> Vec<u8> {
+        let mut msg = vec![0u8; IN_HEADER_LEN + 16];
+        let len = msg.len() as u32;
+        msg[0..4].copy_from_slice(&len.to_ne_bytes());
+        msg[4..8].copy_from_slice(&FUSE_INIT.to_ne_bytes());
+        msg[8..16].copy_from_slice(&2u64.to_ne_bytes());
+        msg
+    }
+
+    fn pipe() -> (OwnedFd, OwnedFd) {
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) }
+    }
+
+    fn send(fd: &OwnedFd, header: &mut [u8]) -> ssize_t {
+        let mut iov = [iovec {
+            iov_base: header.as_mut_ptr() as *mut c_void,
+            iov_len: header.len(),
+        }];
+        unsafe { writev(fd.as_raw_fd(), iov.as_mut_ptr(), 1, ptr::null_mut()) }
+    }
+
+    fn receive(fd: &OwnedFd, ctx: &SharedContext<Nop>) -> ssize_t {
+        let mut buf = [0u8; 256];
+        unsafe {
+            read::<Nop>(
+                fd.as_raw_fd(),
+                buf.as_mut_ptr() as *mut c_void,
+                buf.len(),
+                ctx as *const _ as *mut c_void,
+            )
+        }
+    }
+
+    #[test]
+    fn parts_round_trip() {
+        let (fd, _) = pipe();
+        let (fd, snapshot) = Handoff::new(fd, init_request(), b"state".to_vec()).into_parts();
+        let handoff = Handoff::from_parts(fd, &snapshot).unwrap();
+        assert_eq!(handoff.init, init_request());
+        assert_eq!(handoff.state(), b"state");
+
+        let (fd, _) = pipe();
+        let mut snapshot = vec![];
+        snapshot::put_bytes(&mut snapshot, &init_request()[..IN_HEADER_LEN]);
+        snapshot::put_bytes(&mut snapshot, b"");
+        assert!(Handoff::from_parts(fd, &snapshot).is_err());
+    }
+
+    #[test]
+    fn init_is_recorded() {
+        let ctx = SharedContext::new(Context::new(Nop, None));
+        let (rx, tx) = pipe();
+        let write = |msg: &[u8]| unsafe {
+            libc::write(tx.as_raw_fd(), msg.as_ptr() as *const c_void, msg.len())
+        };
+
+        let mut other = init_request();
+        other[4..8].copy_from_slice(&1u32.to_ne_bytes());
+        write(&other);
+        assert_eq!(receive(&rx, &ctx), other.len() as ssize_t);
+        assert_eq!(ctx.capture().get(), None);
+
+        write(&init_request());
+        assert_eq!(receive(&rx, &ctx), init_request().len() as ssize_t);
+        assert_eq!(ctx.capture().get(), Some(init_request()));
+    }
+
+    #[test]
+    fn reply_to_replayed_init_is_dropped() {
+        let (rx, tx) = pipe();
+
+        let mut reply = [0u8; OUT_HEADER_LEN];
+        reply[8..16].copy_from_slice(&REPLAYED_UNIQUE.to_ne_bytes());
+        assert_eq!(send(&tx, &mut reply), OUT_HEADER_LEN as ssize_t);
+
+        let mut init = init_request();
+        make_replayed(&mut init);
+        assert_eq!(init[8..16], reply[8..16]);
+
+        // The other replies are written to the kernel.
+        let mut reply = [0u8; OUT_HEADER_LEN];
+        reply[8..16].copy_from_slice(&2u64.to_ne_bytes());
+        assert_eq!(send(&tx, &mut reply), OUT_HEADER_LEN as ssize_t);
+        mem::drop(tx);
+
+        let mut buf = [0u8; 2 * OUT_HEADER_LEN];
+        let n = unsafe { libc::read(rx.as_raw_fd(), buf.as_mut_ptr() as *mut c_void, buf.len()) };
+        assert_eq!(n, OUT_HEADER_LEN as ssize_t);
+        assert_eq!(buf[..OUT_HEADER_LEN], reply);
+    }
+
+    #[test]
+    fn exported_filesystem_is_not_destroyed() {
+        let mut ctx = initialized(Destroyed::default());
+        ctx.skip_destroy();
+        ctx.destroy();
+        assert!(!ctx.ops().0);
+
+        let mut ctx = initialized(Destroyed::default());
+        ctx.destroy();
+        assert!(ctx.ops().0);
+    }
+}
@@ -0,0 +1,489 @@
+//! Management of the inodes and their lookup counts.
+
+use crate::{
+    common::{NodeId, ROOT_NODEID},
+    file::Entry,
+    snapshot::{self, Reader},
+};
+use libc::stat;
+use std::io;
+
+/// A table of inodes that tracks the lookup counts held by the kernel.
+///
+/// Every `Entry` replied to the kernel, by `lookup`, `mknod`, `mkdir`,
+/// `symlink`, `link` or `create`, must be created by `InodeTable::entry`
+/// so that the lookup count of the inode is increased. The count is
+/// decreased by `forget`, and the inode is removed once its last link
+/// is unlinked and the kernel forgets it.
+///
+/// The table also counts the links of each inode, i.e. the number of
+/// directory entries referring to it. An inode starts with one link when
+/// inserted; additional hard links are recorded by `link` and removed by
+/// `unlink`. This count is independent of `st_nlink`, which is left to
+/// the filesystem.
+///
+/// For the filesystems whose inodes are owned by another source, e.g. the
+/// host filesystem, `evict_on_forget` makes the table remove the inodes as
+/// soon as the kernel forgets them, regardless of the link counts.
+///
+/// The node IDs of removed inodes are reused with a new generation number.
+#[derive(Debug)]
+pub struct InodeTable<T> {
+    slots: Vec<Slot<T>>,
+    free: Vec<NodeId>,
+    len: usize,
+    evict_on_forget: bool,
+}
+
+#[derive(Debug)]
+struct Slot<T> {
+    generation: u64,
+    inode: Option<Inode<T>>,
+}
+
+#[derive(Debug)]
+struct Inode<T> {
+    value: T,
+    nlookup: u64,
+    nlink: u64,
+}
+
+impl<T> InodeTable<T> {
+    /// Create a new table with the root inode.
+    ///
+    /// The root inode is never removed from the table.
+    pub fn new(root: T) -> Self {
+        Self {
+            slots: vec![Slot {
+                generation: 0,
+                inode: Some(Inode {
+                    value: root,
+                    nlookup: 0,
+                    nlink: 1,
+                }),
+            }],
+            free: vec![],
+            len: 1,
+            evict_on_forget: false,
+        }
+    }
+
+    /// Remove the inodes once their lookup counts drop to zero by `forget`,
+    /// even if they are still linked.
+    ///
+    /// In this mode, an inode is expected to be looked up right after it is
+    /// inserted. The link counts are still maintained by `link` and
+    /// `unlink`, but only `forget` removes the inodes.
+    pub fn evict_on_forget(mut self, enabled: bool) -> Self {
+        self.evict_on_forget = enabled;
+        self
+    }
+
+    /// Insert an inode, returning the allocated node ID.
+    ///
+    /// The lookup count of the new inode starts from zero, and the link
+    /// count from one.
+    pub fn insert(&mut self, value: T) -> NodeId {
+        let inode = Inode {
+            value,
+            nlookup: 0,
+            nlink: 1,
+        };
+        self.len += 1;
+
+        match self.free.pop() {
+            Some(id) => {
+                let slot = &mut self.slots[index(id)];
+                slot.generation += 1;
+                slot.inode = Some(inode);
+                id
+            }
+            None => {
+                self.slots.push(Slot {
+                    generation: 0,
+                    inode: Some(inode),
+                });
+                self.slots.len() as NodeId
+            }
+        }
+    }
+
+    /// Returns a reference to the inode.
+    pub fn get(&self, id: NodeId) -> Option<&T> {
+        self.inode(id).map(|inode| &inode.value)
+    }
+
+    /// Returns a mutable reference to the inode.
+    pub fn get_mut(&mut self, id: NodeId) -> Option<&mut T> {
+        self.inode_mut(id).map(|inode| &mut inode.value)
+    }
+
+    /// Returns whether the table contains the inode.
+    pub fn contains(&self, id: NodeId) -> bool {
+        self.inode(id).is_some()
+    }
+
+    /// Returns the generation number of the inode.
+    pub fn generation(&self, id: NodeId) -> Option<u64> {
+        let slot = self.slots.get(index(id))?;
+        slot.inode.as_ref().map(|_| slot.generation)
+    }
+
+    /// Returns the lookup count of the inode.
+    pub fn nlookup(&self, id: NodeId) -> Option<u64> {
+        self.inode(id).map(|inode| inode.nlookup)
+    }
+
+    /// Returns the link count of the inode.
+    pub fn nlink(&self, id: NodeId) -> Option<u64> {
+        self.inode(id).map(|inode| inode.nlink)
+    }
+
+    /// Returns the number of inodes in the table, including the root.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns whether the table is empty. The result is always `false`,
+    /// since the table contains the root inode.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns an iterator over the node IDs and the inodes.
+    pub fn iter(&self) -> impl Iterator<Item = (NodeId, &T)> {
+        self.slots.iter().enumerate().filter_map(|(i, slot)| {
+            slot.inode
+                .as_ref()
+                .map(|inode| ((i + 1) as NodeId, &inode.value))
+        })
+    }
+
+    /// Serialize the table, so that it can be restored by `restore` in
+    /// another process taking over the session.
+    ///
+    /// The node IDs, generation numbers and lookup counts are preserved,
+    /// since the kernel keeps referring to the inodes by them. The value of
+    /// each inode is appended to the buffer by `encode`.
+    pub fn snapshot(&self, mut encode: impl FnMut(&T, &mut Vec<u8>)) -> Vec<u8> {
+        let mut buf = vec![];
+        snapshot::put_u64(&mut buf, self.evict_on_forget as u64);
+        snapshot::put_u64(&mut buf, self.slots.len() as u64);
+        let mut value = vec![];
+        for slot in &self.slots {
+            snapshot::put_u64(&mut buf, slot.generation);
+            match slot.inode {
+                Some(ref inode) => {
+                    snapshot::put_u64(&mut buf, 1);
+                    snapshot::put_u64(&mut buf, inode.nlookup);
+                    snapshot::put_u64(&mut buf, inode.nlink);
+                    value.clear();
+                    encode(&inode.value, &mut value);
+                    snapshot::put_bytes(&mut buf, &value);
+                }
+                None => snapshot::put_u64(&mut buf, 0),
+            }
+        }
+        buf
+    }
+
+    /// Restore a table serialized by `snapshot`, decoding the value of each
+    /// inode by `decode`.
+    pub fn restore(
+        snapshot: &[u8],
+        mut decode: impl FnMut(&[u8]) -> io::Result<T>,
+    ) -> io::Result<Self> {
+        let mut reader = Reader::new(snapshot);
+        let evict_on_forget = reader.u64()? != 0;
+        let nslots = reader.u64()?;
+        let mut table = Self {
+            slots: vec![],
+            free: vec![],
+            len: 0,
+            evict_on_forget,
+        };
+        for i in 0..nslots {
+            let generation = reader.u64()?;
+            let inode = match reader.u64()? {
+                0 => None,
+                1 => Some(Inode {
+                    nlookup: reader.u64()?,
+                    nlink: reader.u64()?,
+                    value: decode(reader.bytes()?)?,
+                }),
+                _ => return Err(snapshot::malformed()),
+            };
+            match inode {
+                Some(..) => table.len += 1,
+                None => table.free.push(i + ROOT_NODEID),
+            }
+            table.slots.push(Slot { generation, inode });
+        }
+        reader.finish()?;
+
+        if !table.contains(ROOT_NODEID) {
+            return Err(snapshot::malformed());
+        }
+        // Reuse the smallest node IDs first.
+        table.free.reverse();
+        Ok(table)
+    }
+
+    /// Create an `Entry` to be replied to the kernel, increasing the lookup
+    /// count of the inode.
+    ///
+    /// The node ID of `attr` is overwritten by `id`. This method returns
+    /// `None` if the inode does not exist.
+    pub fn entry(&mut self, id: NodeId, mut attr: stat) -> Option<Entry> {
+        let generation = self.generation(id)?;
+        let inode = self.inode_mut(id)?;
+        inode.nlookup += 1;
+
+        attr.st_ino = id;
+        Some(Entry {
+            nodeid: id,
+            generation,
+            attr,
+            ..Entry::default()
+        })
+    }
+
+    /// Decrease the lookup count of the inode by `nlookup`.
+    ///
+    /// If all the links of the inode have been unlinked, or the table is in
+    /// the `evict_on_forget` mode, and the inode is no longer referenced by
+    /// the kernel, the inode is removed and returned.
+    pub fn forget(&mut self, id: NodeId, nlookup: u64) -> Option<T> {
+        let evict_on_forget = self.evict_on_forget;
+        let inode = self.inode_mut(id)?;
+        inode.nlookup = inode.nlookup.saturating_sub(nlookup);
+        if (inode.nlink == 0 || evict_on_forget) && inode.nlookup == 0 {
+            self.remove(id)
+        } else {
+            None
+        }
+    }
+
+    /// Decrease the lookup counts of several inodes at once.
+    ///
+    /// The removed inodes are returned, as with `forget`.
+    pub fn forget_multi(&mut self, forgets: impl IntoIterator<Item = (NodeId, u64)>) -> Vec<T> {
+        forgets
+            .into_iter()
+            .filter_map(|(id, nlookup)| self.forget(id, nlookup))
+            .collect()
+    }
+
+    /// Increase the link count of the inode, for a new hard link.
+    ///
+    /// This method returns `false` if the inode does not exist.
+    pub fn link(&mut self, id: NodeId) -> bool {
+        match self.inode_mut(id) {
+            Some(inode) => {
+                inode.nlink += 1;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Decrease the link count of the inode, for a removed directory entry.
+    ///
+    /// Once the last link is removed, the inode is removed and returned
+    /// immediately if the kernel holds no reference to it. Otherwise, the
+    /// removal is deferred until the inode is forgotten. In the
+    /// `evict_on_forget` mode, the inode is never removed by this method.
+    pub fn unlink(&mut self, id: NodeId) -> Option<T> {
+        if id == ROOT_NODEID {
+            return None;
+        }
+
+        let evict_on_forget = self.evict_on_forget;
+        let inode = self.inode_mut(id)?;
+        inode.nlink = inode.nlink.saturating_sub(1);
+        if inode.nlink == 0 && inode.nlookup == 0 && !evict_on_forget {
+            self.remove(id)
+        } else {
+            None
+        }
+    }
+
+    fn remove(&mut self, id: NodeId) -> Option<T> {
+        if id == ROOT_NODEID {
+            return None;
+        }
+
+        let inode = self.slots.get_mut(index(id))?.inode.take()?;
+        self.free.push(id);
+        self.len -= 1;
+        Some(inode.value)
+    }
+
+    fn inode(&self, id: NodeId) -> Option<&Inode<T>> {
+        self.slots.get(index(id))?.inode.as_ref()
+    }
+
+    fn inode_mut(&mut self, id: NodeId) -> Option<&mut Inode<T>> {
+        self.slots.get_mut(index(id))?.inode.as_mut()
+    }
+}
+
+fn index(id: NodeId) -> usize {
+    id.wrapping_sub(ROOT_NODEID) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attr() -> stat {
+        unsafe { std::mem::zeroed() }
+    }
+
+    #[test]
+    fn entry_and_forget() {
+        let mut table = InodeTable::new("root");
+        let id = table.insert("file");
+        assert_eq!(table.len(), 2);
+
+        let entry = table.entry(id, attr()).unwrap();
+        assert_eq!(entry.nodeid, id);
+        assert_eq!(entry.attr.st_ino, id);
+        table.entry(id, attr()).unwrap();
+        assert_eq!(table.nlookup(id), Some(2));
+
+        // Linked inodes are kept even if the kernel forgets them.
+        assert_eq!(table.forget(id, 2), None);
+        assert_eq!(table.nlookup(id), Some(0));
+        assert_eq!(table.get(id), Some(&"file"));
+
+        assert!(table.entry(42, attr()).is_none());
+    }
+
+    #[test]
+    fn unlink_while_looked_up() {
+        let mut table = InodeTable::new("root");
+        let id = table.insert("file");
+        table.entry(id, attr()).unwrap();
+
+        assert_eq!(table.unlink(id), None);
+        assert!(table.contains(id));
+        assert_eq!(table.forget(id, 1), Some("file"));
+        assert!(!table.contains(id));
+        assert_eq!(table.len(), 1);
+    }
+
+    #[test]
+    fn unlink_not_looked_up() {
+        let mut table = InodeTable::new("root");
+        let id = table.insert("file");
+        assert_eq!(table.unlink(id), Some("file"));
+        assert!(!table.contains(id));
+    }
+
+    #[test]
+    fn hard_links() {
+        let mut table = InodeTable::new("root");
+        let id = table.insert("file");
+        assert!(table.link(id));
+        assert_eq!(table.nlink(id), Some(2));
+        table.entry(id, attr()).unwrap();
+
+        // Removing one of the links keeps the inode alive.
+        assert_eq!(table.unlink(id), None);
+        assert_eq!(table.forget(id, 1), None);
+        assert_eq!(table.get(id), Some(&"file"));
+
+        assert_eq!(table.unlink(id), Some("file"));
+        assert!(!table.link(id));
+    }
+
+    #[test]
+    fn root_is_never_removed() {
+        let mut table = InodeTable::new("root");
+        table.entry(ROOT_NODEID, attr()).unwrap();
+        assert_eq!(table.unlink(ROOT_NODEID), None);
+        assert_eq!(table.forget(ROOT_NODEID, 1), None);
+        assert_eq!(table.get(ROOT_NODEID), Some(&"root"));
+        assert!(!table.is_empty());
+    }
+
+    #[test]
+    fn node_ids_are_reused_with_new_generation() {
+        let mut table = InodeTable::new("root");
+        let id = table.insert("a");
+        assert_eq!(table.generation(id), Some(0));
+        table.unlink(id);
+        assert_eq!(table.generation(id), None);
+
+        assert_eq!(table.insert("b"), id);
+        assert_eq!(table.generation(id), Some(1));
+        assert_eq!(table.entry(id, attr()).unwrap().generation, 1);
+    }
+
+    #[test]
+    fn evict_on_forget() {
+        let mut table = InodeTable::new("root").evict_on_forget(true);
+        let id = table.insert("file");
+        table.entry(id, attr()).unwrap();
+        table.entry(id, attr()).unwrap();
+
+        assert_eq!(table.forget(id, 1), None);
+        assert_eq!(table.nlink(id), Some(1));
+        assert_eq!(table.forget(id, 1), Some("file"));
+        assert!(!table.contains(id));
+
+        assert_eq!(table.forget(ROOT_NODEID, 1), None);
+        assert!(table.contains(ROOT_NODEID));
+    }
+
+    #[test]
+    fn snapshot_and_restore() {
+        let mut table = InodeTable::new("root".to_owned()).evict_on_forget(true);
+        let a = table.insert("a".to_owned());
+        let b = table.insert("b".to_owned());
+        table.entry(a, attr()).unwrap();
+        table.entry(b, attr()).unwrap();
+        table.entry(b, attr()).unwrap();
+        table.forget(a, 1);
+
+        let snapshot = table.snapshot(|value, buf| buf.extend_from_slice(value.as_bytes()));
+        let mut restored = InodeTable::restore(&snapshot, |data| {
+            Ok(String::from_utf8(data.to_vec()).unwrap())
+        })
+        .unwrap();
+        assert_eq!(restored.len(), 2);
+        assert_eq!(restored.get(b).map(|s| &**s), Some("b"));
+        assert_eq!(restored.nlookup(b), Some(2));
+        assert_eq!(restored.nlink(b), Some(1));
+        assert!(!restored.contains(a));
+
+        // The free node IDs and the mode are restored as well.
+        assert_eq!(restored.insert("c".to_owned()), a);
+        assert_eq!(restored.generation(a), Some(1));
+        assert_eq!(restored.forget(b, 2).as_deref(), Some("b"));
+
+        assert!(
+            InodeTable::<String>::restore(&snapshot[..snapshot.len() - 1], |_| {
+                Ok(String::new())
+            })
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn forget_multi() {
+        let mut table = InodeTable::new("root");
+        let a = table.insert("a");
+        let b = table.insert("b");
+        table.entry(a, attr()).unwrap();
+        table.entry(b, attr()).unwrap();
+        table.unlink(a);
+
+        assert_eq!(table.forget_multi(vec![(a, 1), (b, 1)]), vec!["a"]);
+        assert!(table.contains(b));
+
+        let ids: Vec<_> = table.iter().map(|(id, _)| id).collect();
+        assert_eq!(ids, vec![ROOT_NODEID, b]);
+    }
+}
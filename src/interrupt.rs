@@ -0,0 +1,328 @@
+//! Interruption of the requests in progress.
+
+use libc::c_void;
+use libfuse_sys::{fuse_req_interrupt_func, fuse_req_interrupted, fuse_req_t};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fmt, mem, ptr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError,
+    },
+};
+
+thread_local! {
+    static CURRENT: RefCell<Option<Current>> = RefCell::new(None);
+}
+
+/// The request being processed on the current thread.
+struct Current {
+    req: fuse_req_t,
+    registry: Arc<Registry>,
+    state: Option<Arc<State>>,
+}
+
+/// The tokens registered to libfuse, keyed by the address of the request.
+///
+/// libfuse may call the interrupt function even after the request has been
+/// replied, so the pointer passed to it refers to this registry, which
+/// outlives the event loop, rather than to the token. The token is removed
+/// from the registry before the reply, and the late calls find nothing.
+/// The address of the request is not reused by another one meanwhile, since
+/// libfuse holds a reference to the request while calling the function.
+#[derive(Default)]
+pub(crate) struct Registry {
+    states: Mutex<HashMap<usize, Arc<State>>>,
+}
+
+impl Registry {
+    fn lock(&self) -> MutexGuard<'_, HashMap<usize, Arc<State>>> {
+        self.states.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+struct State {
+    /// The address of the request while it is in progress, or zero once it
+    /// has been replied or if it is synthesized by `testing::Harness`.
+    req: Mutex<usize>,
+    registry: Option<Arc<Registry>>,
+    registered: AtomicBool,
+    interrupted: AtomicBool,
+    callbacks: Mutex<Vec<Box<dyn FnOnce() + Send>>>,
+}
+
+impl State {
+    fn new(req: fuse_req_t, registry: Option<Arc<Registry>>) -> Self {
+        Self {
+            req: Mutex::new(req as usize),
+            registry,
+            registered: AtomicBool::new(false),
+            interrupted: AtomicBool::new(false),
+            callbacks: Mutex::default(),
+        }
+    }
+
+    fn lock_req(&self) -> MutexGuard<'_, usize> {
+        self.req.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn interrupt(&self) {
+        // The flag is set before running the callbacks, which may query it.
+        self.interrupted.store(true, Ordering::SeqCst);
+        let callbacks = mem::take(
+            &mut *self
+                .callbacks
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner),
+        );
+        for callback in callbacks {
+            callback();
+        }
+    }
+
+    /// Detach the token from the request, before it is replied.
+    fn finish(&self) {
+        let mut req = self.lock_req();
+        if *req == 0 {
+            return;
+        }
+        if unsafe { fuse_req_interrupted(*req as fuse_req_t) } != 0 {
+            self.interrupted.store(true, Ordering::SeqCst);
+        }
+        if self.registered.load(Ordering::SeqCst) {
+            if let Some(ref registry) = self.registry {
+                registry.lock().remove(&*req);
+            }
+        }
+        *req = 0;
+    }
+}
+
+/// A token to observe the interruption of a request.
+///
+/// The kernel sends an interrupt when the process waiting for the request
+/// receives a signal, e.g. by Ctrl-C. The implementation of `Operations`
+/// can abort the processing and reply `EINTR` in that case. The token is
+/// passed to the long-running operations through `ReadOptions::interrupt`,
+/// `WriteOptions::interrupt`, `FlushOptions::interrupt` and
+/// `DirBuf::interrupt`, and can be obtained by `Interrupt::current` in the
+/// other ones.
+///
+/// Note that the interrupts are received only by `Session::run_loop_mt`,
+/// since the single-threaded event loop does not read the next message
+/// until the current request is replied.
+#[derive(Clone)]
+pub struct Interrupt {
+    state: Arc<State>,
+}
+
+impl fmt::Debug for Interrupt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Interrupt")
+            .field("interrupted", &self.is_interrupted())
+            .finish()
+    }
+}
+
+impl Interrupt {
+    /// Returns the token for the request currently being processed on
+    /// this thread.
+    ///
+    /// This function returns `None` if called outside of `Operations` methods.
+    pub fn current() -> Option<Self> {
+        CURRENT.with(|current| {
+            let mut current = current.borrow_mut();
+            let Current {
+                req,
+                ref registry,
+                ref mut state,
+            } = *current.as_mut()?;
+            let state = state.get_or_insert_with(|| {
+                let registry = if !req.is_null() {
+                    Some(registry.clone())
+                } else {
+                    None
+                };
+                Arc::new(State::new(req, registry))
+            });
+            Some(Interrupt {
+                state: state.clone(),
+            })
+        })
+    }
+
+    /// Returns the token for the current request, or a token that is never
+    /// interrupted if no request is being processed.
+    pub(crate) fn current_or_detached() -> Self {
+        Self::current().unwrap_or_else(|| Interrupt {
+            state: Arc::new(State::new(ptr::null_mut(), None)),
+        })
+    }
+
+    /// Returns whether the request has been interrupted.
+    pub fn is_interrupted(&self) -> bool {
+        if self.state.interrupted.load(Ordering::SeqCst) {
+            return true;
+        }
+        let req = self.state.lock_req();
+        if *req != 0 && unsafe { fuse_req_interrupted(*req as fuse_req_t) } != 0 {
+            self.state.interrupted.store(true, Ordering::SeqCst);
+            return true;
+        }
+        false
+    }
+
+    /// Register a callback that is called when the request is interrupted.
+    ///
+    /// If the request has already been interrupted, the callback is called
+    /// immediately. The callback may be called on another thread, and must
+    /// not block.
+    pub fn on_interrupt(&self, callback: impl FnOnce() + Send + 'static) {
+        {
+            let mut callbacks = self
+                .state
+                .callbacks
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            if self.state.interrupted.load(Ordering::SeqCst) {
+                mem::drop(callbacks);
+                return callback();
+            }
+            callbacks.push(Box::new(callback));
+        }
+        self.register();
+    }
+
+    /// Ask libfuse to notify the interruption of the request, which is
+    /// otherwise only polled by `is_interrupted`.
+    fn register(&self) {
+        let registry = match self.state.registry {
+            Some(ref registry) => registry,
+            None => return,
+        };
+        let req = self.state.lock_req();
+        if *req == 0 || self.state.registered.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        registry.lock().insert(*req, self.state.clone());
+        // This calls `on_interrupt` immediately if the request has already
+        // been interrupted, so no lock other than `req` is held here.
+        unsafe {
+            fuse_req_interrupt_func(
+                *req as fuse_req_t,
+                Some(on_interrupt),
+                Arc::as_ptr(registry) as *mut c_void,
+            );
+        }
+    }
+}
+
+unsafe extern "C" fn on_interrupt(req: fuse_req_t, data: *mut c_void) {
+    let registry = &*(data as *const Registry);
+    let state = registry.lock().get(&(req as usize)).cloned();
+    if let Some(state) = state {
+        state.interrupt();
+    }
+}
+
+/// A guard that marks the request as being processed on the current thread.
+pub(crate) struct Guard(());
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        let current = CURRENT.with(|current| current.borrow_mut().take());
+        if let Some(state) = current.and_then(|current| current.state) {
+            state.finish();
+        }
+    }
+}
+
+/// Mark the request as being processed, until the guard is dropped before
+/// replying to it. `req` is null for the requests synthesized by
+/// `testing::Harness`, which are never interrupted.
+pub(crate) fn enter(req: fuse_req_t, registry: &Arc<Registry>) -> Guard {
+    CURRENT.with(|current| {
+        *current.borrow_mut() = Some(Current {
+            req,
+            registry: registry.clone(),
+            state: None,
+        })
+    });
+    Guard(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    fn counter(interrupt: &Interrupt) -> Arc<AtomicUsize> {
+        let count = Arc::new(AtomicUsize::new(0));
+        let c = count.clone();
+        interrupt.on_interrupt(move || {
+            c.fetch_add(1, Ordering::SeqCst);
+        });
+        count
+    }
+
+    #[test]
+    fn current_is_scoped_to_request() {
+        let registry = Arc::default();
+        assert!(Interrupt::current().is_none());
+        {
+            let _guard = enter(ptr::null_mut(), &registry);
+            let interrupt = Interrupt::current().unwrap();
+            assert!(!interrupt.is_interrupted());
+            assert!(Arc::ptr_eq(
+                &interrupt.state,
+                &Interrupt::current().unwrap().state
+            ));
+        }
+        assert!(Interrupt::current().is_none());
+        assert!(!Interrupt::current_or_detached().is_interrupted());
+    }
+
+    #[test]
+    fn callbacks() {
+        let interrupt = Interrupt::current_or_detached();
+        let count = counter(&interrupt);
+        assert_eq!(count.load(Ordering::SeqCst), 0);
+
+        interrupt.state.interrupt();
+        assert!(interrupt.is_interrupted());
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+
+        // Registered after the interruption.
+        let count = counter(&interrupt);
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn late_interrupt_is_ignored() {
+        let registry = Arc::new(Registry::default());
+        let data = Arc::as_ptr(&registry) as *mut c_void;
+        let (a, b) = (0x1000 as fuse_req_t, 0x2000 as fuse_req_t);
+
+        // The state is not attached to the fake requests, which must not be
+        // passed to libfuse.
+        let state = Arc::new(State::new(ptr::null_mut(), Some(registry.clone())));
+        let interrupt = Interrupt {
+            state: state.clone(),
+        };
+        let count = counter(&interrupt);
+        registry.lock().insert(a as usize, state);
+
+        unsafe { on_interrupt(b, data) };
+        assert!(!interrupt.is_interrupted());
+
+        unsafe { on_interrupt(a, data) };
+        assert!(interrupt.is_interrupted());
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+
+        // Once removed, the calls after the reply find nothing.
+        registry.lock().remove(&(a as usize));
+        unsafe { on_interrupt(a, data) };
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+    }
+}
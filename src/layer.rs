@@ -0,0 +1,431 @@
+//! Composable wrappers around `Operations`.
+
+use crate::{
+    common::{ConnectionInfo, NodeId},
+    dir::{DirBuf, DirEntries, OpenDirOptions},
+    file::{
+        Entry, //
+        FlushOptions,
+        OpenOptions,
+        ReadOptions,
+        ReleaseOptions,
+        RenameFlags,
+        SetAttrs,
+        WriteOptions,
+        XAttrFlags,
+        XAttrReply,
+    },
+    ops::{OperationResult, Operations},
+};
+use libc::{c_int, dev_t, mode_t, off_t, stat, statvfs};
+use std::{
+    borrow::Cow,
+    ffi::{CStr, CString},
+    io,
+};
+
+/// A wrapper that implements `Operations` by delegating to an inner one.
+///
+/// Every method forwards the call to the inner filesystem by default,
+/// so a layer only overrides the methods it is interested in. Since
+/// `Operations` is implemented for every `Layer`, layers can be stacked
+/// on top of each other.
+pub trait Layer {
+    /// The type of the wrapped filesystem.
+    type Inner: Operations;
+
+    /// Returns a reference to the wrapped filesystem.
+    fn inner(&self) -> &Self::Inner;
+
+    /// Returns a mutable reference to the wrapped filesystem.
+    fn inner_mut(&mut self) -> &mut Self::Inner;
+
+    fn init(&mut self, conn: &mut ConnectionInfo<'_>) {
+        self.inner_mut().init(conn)
+    }
+
+    fn destroy(&mut self) -> io::Result<()> {
+        self.inner_mut().destroy()
+    }
+
+    #[cfg(feature = "handoff")]
+    fn export(&mut self) -> io::Result<Vec<u8>> {
+        self.inner_mut().export()
+    }
+
+    fn lookup(&mut self, parent: NodeId, name: &CStr) -> OperationResult<Entry> {
+        self.inner_mut().lookup(parent, name)
+    }
+
+    fn forget(&mut self, id: NodeId, nlookup: u64) {
+        self.inner_mut().forget(id, nlookup)
+    }
+
+    fn readlink(&mut self, id: NodeId) -> OperationResult<CString> {
+        self.inner_mut().readlink(id)
+    }
+
+    fn mknod(
+        &mut self,
+        parent: NodeId,
+        name: &CStr,
+        mode: mode_t,
+        rdev: dev_t,
+    ) -> OperationResult<Entry> {
+        self.inner_mut().mknod(parent, name, mode, rdev)
+    }
+
+    fn mkdir(&mut self, parent: NodeId, name: &CStr, mode: mode_t) -> OperationResult<Entry> {
+        self.inner_mut().mkdir(parent, name, mode)
+    }
+
+    fn unlink(&mut self, parent: NodeId, name: &CStr) -> OperationResult<()> {
+        self.inner_mut().unlink(parent, name)
+    }
+
+    fn rmdir(&mut self, parent: NodeId, name: &CStr) -> OperationResult<()> {
+        self.inner_mut().rmdir(parent, name)
+    }
+
+    fn symlink(&mut self, link: &CStr, parent: NodeId, name: &CStr) -> OperationResult<Entry> {
+        self.inner_mut().symlink(link, parent, name)
+    }
+
+    fn rename(
+        &mut self,
+        oldparent: NodeId,
+        oldname: &CStr,
+        newparent: NodeId,
+        newname: &CStr,
+        flags: RenameFlags,
+    ) -> OperationResult<()> {
+        self.inner_mut()
+            .rename(oldparent, oldname, newparent, newname, flags)
+    }
+
+    fn link(&mut self, id: NodeId, newparent: NodeId, newname: &CStr) -> OperationResult<Entry> {
+        self.inner_mut().link(id, newparent, newname)
+    }
+
+    fn statfs(&mut self, id: NodeId) -> OperationResult<statvfs> {
+        self.inner_mut().statfs(id)
+    }
+
+    fn setxattr(
+        &mut self,
+        id: NodeId,
+        name: &CStr,
+        value: &[u8],
+        flags: XAttrFlags,
+    ) -> OperationResult<()> {
+        self.inner_mut().setxattr(id, name, value, flags)
+    }
+
+    fn getxattr(
+        &mut self,
+        id: NodeId,
+        name: &CStr,
+        size: usize,
+    ) -> OperationResult<XAttrReply<'_>> {
+        self.inner_mut().getxattr(id, name, size)
+    }
+
+    fn listxattr(&mut self, id: NodeId, size: usize) -> OperationResult<XAttrReply<'_>> {
+        self.inner_mut().listxattr(id, size)
+    }
+
+    fn removexattr(&mut self, id: NodeId, name: &CStr) -> OperationResult<()> {
+        self.inner_mut().removexattr(id, name)
+    }
+
+    fn access(&mut self, id: NodeId, mask: c_int) -> OperationResult<()> {
+        self.inner_mut().access(id, mask)
+    }
+
+    fn open(&mut self, id: NodeId, options: &mut OpenOptions<'_>) -> OperationResult<u64> {
+        self.inner_mut().open(id, options)
+    }
+
+    fn create(
+        &mut self,
+        parent: NodeId,
+        name: &CStr,
+        mode: mode_t,
+        options: &mut OpenOptions<'_>,
+    ) -> OperationResult<(Entry, u64)> {
+        self.inner_mut().create(parent, name, mode, options)
+    }
+
+    fn read(
+        &mut self,
+        id: NodeId,
+        off: off_t,
+        bufsize: usize,
+        opts: &mut ReadOptions<'_>,
+        fh: u64,
+    ) -> OperationResult<Cow<'_, [u8]>> {
+        self.inner_mut().read(id, off, bufsize, opts, fh)
+    }
+
+    fn write(
+        &mut self,
+        id: NodeId,
+        buf: &[u8],
+        off: off_t,
+        opts: &mut WriteOptions<'_>,
+        fh: u64,
+    ) -> OperationResult<usize> {
+        self.inner_mut().write(id, buf, off, opts, fh)
+    }
+
+    fn flush(&mut self, id: NodeId, opts: &mut FlushOptions<'_>, fh: u64) -> OperationResult<()> {
+        self.inner_mut().flush(id, opts, fh)
+    }
+
+    fn getattr(&mut self, id: NodeId, fh: Option<u64>) -> OperationResult<(stat, f64)> {
+        self.inner_mut().getattr(id, fh)
+    }
+
+    fn setattr(
+        &mut self,
+        id: NodeId,
+        attrs: &SetAttrs<'_>,
+        fh: Option<u64>,
+    ) -> OperationResult<(stat, f64)> {
+        self.inner_mut().setattr(id, attrs, fh)
+    }
+
+    fn fsync(&mut self, id: NodeId, datasync: c_int, fh: u64) -> OperationResult<()> {
+        self.inner_mut().fsync(id, datasync, fh)
+    }
+
+    fn release(
+        &mut self,
+        id: NodeId,
+        options: &mut ReleaseOptions<'_>,
+        fh: u64,
+    ) -> OperationResult<()> {
+        self.inner_mut().release(id, options, fh)
+    }
+
+    fn opendir(&mut self, id: NodeId, options: &mut OpenDirOptions) -> OperationResult<u64> {
+        self.inner_mut().opendir(id, options)
+    }
+
+    fn readdir(
+        &mut self,
+        id: NodeId,
+        offset: off_t,
+        buf: &mut DirBuf<'_>,
+        fh: u64,
+    ) -> OperationResult<()> {
+        self.inner_mut().readdir(id, offset, buf, fh)
+    }
+
+    fn readdir_entries(&mut self, id: NodeId, fh: u64) -> OperationResult<DirEntries<'_>> {
+        self.inner_mut().readdir_entries(id, fh)
+    }
+
+    fn fsyncdir(&mut self, id: NodeId, datasync: c_int, fh: u64) -> OperationResult<()> {
+        self.inner_mut().fsyncdir(id, datasync, fh)
+    }
+
+    fn releasedir(&mut self, id: NodeId, fh: u64) -> OperationResult<()> {
+        self.inner_mut().releasedir(id, fh)
+    }
+}
+
+impl<L: Layer> Operations for L {
+    fn init(&mut self, conn: &mut ConnectionInfo<'_>) {
+        Layer::init(self, conn)
+    }
+
+    fn destroy(&mut self) -> io::Result<()> {
+        Layer::destroy(self)
+    }
+
+    #[cfg(feature = "handoff")]
+    fn export(&mut self) -> io::Result<Vec<u8>> {
+        Layer::export(self)
+    }
+
+    fn lookup(&mut self, parent: NodeId, name: &CStr) -> OperationResult<Entry> {
+        Layer::lookup(self, parent, name)
+    }
+
+    fn forget(&mut self, id: NodeId, nlookup: u64) {
+        Layer::forget(self, id, nlookup)
+    }
+
+    fn readlink(&mut self, id: NodeId) -> OperationResult<CString> {
+        Layer::readlink(self, id)
+    }
+
+    fn mknod(
+        &mut self,
+        parent: NodeId,
+        name: &CStr,
+        mode: mode_t,
+        rdev: dev_t,
+    ) -> OperationResult<Entry> {
+        Layer::mknod(self, parent, name, mode, rdev)
+    }
+
+    fn mkdir(&mut self, parent: NodeId, name: &CStr, mode: mode_t) -> OperationResult<Entry> {
+        Layer::mkdir(self, parent, name, mode)
+    }
+
+    fn unlink(&mut self, parent: NodeId, name: &CStr) -> OperationResult<()> {
+        Layer::unlink(self, parent, name)
+    }
+
+    fn rmdir(&mut self, parent: NodeId, name: &CStr) -> OperationResult<()> {
+        Layer::rmdir(self, parent, name)
+    }
+
+    fn symlink(&mut self, link: &CStr, parent: NodeId, name: &CStr) -> OperationResult<Entry> {
+        Layer::symlink(self, link, parent, name)
+    }
+
+    fn rename(
+        &mut self,
+        oldparent: NodeId,
+        oldname: &CStr,
+        newparent: NodeId,
+        newname: &CStr,
+        flags: RenameFlags,
+    ) -> OperationResult<()> {
+        Layer::rename(self, oldparent, oldname, newparent, newname, flags)
+    }
+
+    fn link(&mut self, id: NodeId, newparent: NodeId, newname: &CStr) -> OperationResult<Entry> {
+        Layer::link(self, id, newparent, newname)
+    }
+
+    fn statfs(&mut self, id: NodeId) -> OperationResult<statvfs> {
+        Layer::statfs(self, id)
+    }
+
+    fn setxattr(
+        &mut self,
+        id: NodeId,
+        name: &CStr,
+        value: &[u8],
+        flags: XAttrFlags,
+    ) -> OperationResult<()> {
+        Layer::setxattr(self, id, name, value, flags)
+    }
+
+    fn getxattr(
+        &mut self,
+        id: NodeId,
+        name: &CStr,
+        size: usize,
+    ) -> OperationResult<XAttrReply<'_>> {
+        Layer::getxattr(self, id, name, size)
+    }
+
+    fn listxattr(&mut self, id: NodeId, size: usize) -> OperationResult<XAttrReply<'_>> {
+        Layer::listxattr(self, id, size)
+    }
+
+    fn removexattr(&mut self, id: NodeId, name: &CStr) -> OperationResult<()> {
+        Layer::removexattr(self, id, name)
+    }
+
+    fn access(&mut self, id: NodeId, mask: c_int) -> OperationResult<()> {
+        Layer::access(self, id, mask)
+    }
+
+    fn open(&mut self, id: NodeId, options: &mut OpenOptions<'_>) -> OperationResult<u64> {
+        Layer::open(self, id, options)
+    }
+
+    fn create(
+        &mut self,
+        parent: NodeId,
+        name: &CStr,
+        mode: mode_t,
+        options: &mut OpenOptions<'_>,
+    ) -> OperationResult<(Entry, u64)> {
+        Layer::create(self, parent, name, mode, options)
+    }
+
+    fn read(
+        &mut self,
+        id: NodeId,
+        off: off_t,
+        bufsize: usize,
+        opts: &mut ReadOptions<'_>,
+        fh: u64,
+    ) -> OperationResult<Cow<'_, [u8]>> {
+        Layer::read(self, id, off, bufsize, opts, fh)
+    }
+
+    fn write(
+        &mut self,
+        id: NodeId,
+        buf: &[u8],
+        off: off_t,
+        opts: &mut WriteOptions<'_>,
+        fh: u64,
+    ) -> OperationResult<usize> {
+        Layer::write(self, id, buf, off, opts, fh)
+    }
+
+    fn flush(&mut self, id: NodeId, opts: &mut FlushOptions<'_>, fh: u64) -> OperationResult<()> {
+        Layer::flush(self, id, opts, fh)
+    }
+
+    fn getattr(&mut self, id: NodeId, fh: Option<u64>) -> OperationResult<(stat, f64)> {
+        Layer::getattr(self, id, fh)
+    }
+
+    fn setattr(
+        &mut self,
+        id: NodeId,
+        attrs: &SetAttrs<'_>,
+        fh: Option<u64>,
+    ) -> OperationResult<(stat, f64)> {
+        Layer::setattr(self, id, attrs, fh)
+    }
+
+    fn fsync(&mut self, id: NodeId, datasync: c_int, fh: u64) -> OperationResult<()> {
+        Layer::fsync(self, id, datasync, fh)
+    }
+
+    fn release(
+        &mut self,
+        id: NodeId,
+        options: &mut ReleaseOptions<'_>,
+        fh: u64,
+    ) -> OperationResult<()> {
+        Layer::release(self, id, options, fh)
+    }
+
+    fn opendir(&mut self, id: NodeId, options: &mut OpenDirOptions) -> OperationResult<u64> {
+        Layer::opendir(self, id, options)
+    }
+
+    fn readdir(
+        &mut self,
+        id: NodeId,
+        offset: off_t,
+        buf: &mut DirBuf<'_>,
+        fh: u64,
+    ) -> OperationResult<()> {
+        Layer::readdir(self, id, offset, buf, fh)
+    }
+
+    fn readdir_entries(&mut self, id: NodeId, fh: u64) -> OperationResult<DirEntries<'_>> {
+        Layer::readdir_entries(self, id, fh)
+    }
+
+    fn fsyncdir(&mut self, id: NodeId, datasync: c_int, fh: u64) -> OperationResult<()> {
+        Layer::fsyncdir(self, id, datasync, fh)
+    }
+
+    fn releasedir(&mut self, id: NodeId, fh: u64) -> OperationResult<()> {
+        Layer::releasedir(self, id, fh)
+    }
+}
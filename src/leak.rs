@@ -0,0 +1,484 @@
+//! A layer that detects the leaks of lookup counts and file handles.
+
+use crate::{
+    common::NodeId,
+    dir::OpenDirOptions,
+    file::{Entry, OpenOptions, ReleaseOptions},
+    layer::Layer,
+    ops::{OperationResult, Operations},
+};
+use libc::{dev_t, mode_t};
+use std::{
+    collections::BTreeMap,
+    ffi::CStr,
+    fmt, io,
+    sync::{Arc, Mutex},
+};
+
+/// The open handles, counted for each pair of the node ID and the handle
+/// since the filesystem may return the same handle more than once.
+///
+/// The releases of the handles not open are recorded as negative counts.
+type Handles = BTreeMap<(NodeId, u64), i64>;
+
+#[derive(Debug, Default)]
+struct Counts {
+    nodes: BTreeMap<NodeId, i64>,
+    files: Handles,
+    dirs: Handles,
+}
+
+impl Counts {
+    fn entry(&mut self, entry: &Entry) {
+        // The kernel does not count the lookups of negative entries.
+        if entry.nodeid != 0 {
+            *self.nodes.entry(entry.nodeid).or_insert(0) += 1;
+        }
+    }
+
+    fn forget(&mut self, id: NodeId, nlookup: u64) {
+        let count = self.nodes.entry(id).or_insert(0);
+        *count -= nlookup as i64;
+        if *count == 0 {
+            self.nodes.remove(&id);
+        }
+    }
+
+    fn open(handles: &mut Handles, id: NodeId, fh: u64) {
+        *handles.entry((id, fh)).or_insert(0) += 1;
+    }
+
+    fn release(handles: &mut Handles, id: NodeId, fh: u64) {
+        let count = handles.entry((id, fh)).or_insert(0);
+        *count -= 1;
+        if *count == 0 {
+            handles.remove(&(id, fh));
+        }
+    }
+
+    fn report(&self) -> LeakReport {
+        LeakReport {
+            nodes: self.nodes.clone(),
+            files: self.files.clone(),
+            dirs: self.dirs.clone(),
+        }
+    }
+}
+
+/// A layer that tracks the lookup counts of the nodes and the open file
+/// handles, for debugging the filesystems.
+///
+/// The lookup count of a node is incremented for each entry replied by
+/// `lookup`, `mknod`, `mkdir`, `symlink`, `link` and `create`, and
+/// decremented by `forget`. The handles replied by `open`, `opendir`
+/// and `create` are tracked until `release` and `releasedir`.
+///
+/// Note that the kernel does not necessarily forget the cached nodes
+/// before unmounting, so the lookup counts remaining at `destroy` are
+/// not always leaks, and they are only logged at the debug level. The
+/// negative counts, i.e. the nodes forgotten more times than they were
+/// replied and the handles released more times than they were opened,
+/// and the handles not released are always bugs, which are logged as a
+/// warning. When driven by `testing::Harness`, `forget_all`
+/// can be called to evict the nodes before checking the report.
+///
+/// The tracking is enabled only in debug builds. In release builds,
+/// the layer just forwards the calls and the report is always empty.
+#[derive(Debug)]
+pub struct LeakCheck<T: Operations> {
+    inner: T,
+    counts: Arc<Mutex<Counts>>,
+}
+
+impl<T: Operations> LeakCheck<T> {
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            counts: Arc::default(),
+        }
+    }
+
+    /// Returns a handle to take the report while the session is running.
+    pub fn handle(&self) -> LeakHandle {
+        LeakHandle {
+            counts: self.counts.clone(),
+        }
+    }
+
+    /// Returns the lookup counts and handles outstanding at this moment.
+    pub fn report(&self) -> LeakReport {
+        self.counts.lock().unwrap().report()
+    }
+
+    /// Consume the layer, returning the inner filesystem.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    fn track(&self, f: impl FnOnce(&mut Counts)) {
+        if cfg!(debug_assertions) {
+            f(&mut self.counts.lock().unwrap());
+        }
+    }
+
+    fn track_entry(&self, res: OperationResult<Entry>) -> OperationResult<Entry> {
+        if let Ok(ref entry) = res {
+            self.track(|counts| counts.entry(entry));
+        }
+        res
+    }
+}
+
+/// A handle to take the report of a `LeakCheck` from another thread.
+#[derive(Debug, Clone)]
+pub struct LeakHandle {
+    counts: Arc<Mutex<Counts>>,
+}
+
+impl LeakHandle {
+    /// Returns the lookup counts and handles outstanding at this moment.
+    pub fn report(&self) -> LeakReport {
+        self.counts.lock().unwrap().report()
+    }
+}
+
+/// The lookup counts and handles outstanding in a `LeakCheck`.
+#[derive(Debug, Clone, Default)]
+pub struct LeakReport {
+    nodes: BTreeMap<NodeId, i64>,
+    files: Handles,
+    dirs: Handles,
+}
+
+impl LeakReport {
+    /// Returns whether nothing is outstanding.
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty() && self.files.is_empty() && self.dirs.is_empty()
+    }
+
+    /// Returns an iterator over the nodes whose lookup count is not zero,
+    /// and their counts.
+    ///
+    /// A negative count means the node has been forgotten more times than
+    /// it was replied.
+    pub fn nodes(&self) -> impl Iterator<Item = (NodeId, i64)> + '_ {
+        self.nodes.iter().map(|(&id, &count)| (id, count))
+    }
+
+    /// Returns an iterator over the nodes forgotten more times than they
+    /// were replied.
+    pub fn negative_nodes(&self) -> impl Iterator<Item = (NodeId, i64)> + '_ {
+        self.nodes().filter(|&(_, count)| count < 0)
+    }
+
+    /// Returns an iterator over the node IDs and the handles of the files
+    /// not released, and the numbers of times they remain open.
+    pub fn open_files(&self) -> impl Iterator<Item = (NodeId, u64, usize)> + '_ {
+        open_handles(&self.files)
+    }
+
+    /// Returns an iterator over the node IDs and the handles of the files
+    /// released more times than they were opened, and their counts.
+    pub fn negative_files(&self) -> impl Iterator<Item = (NodeId, u64, i64)> + '_ {
+        negative_handles(&self.files)
+    }
+
+    /// Returns an iterator over the node IDs and the handles of the
+    /// directories not released, and the numbers of times they remain open.
+    pub fn open_dirs(&self) -> impl Iterator<Item = (NodeId, u64, usize)> + '_ {
+        open_handles(&self.dirs)
+    }
+
+    /// Returns an iterator over the node IDs and the handles of the
+    /// directories released more times than they were opened, and their
+    /// counts.
+    pub fn negative_dirs(&self) -> impl Iterator<Item = (NodeId, u64, i64)> + '_ {
+        negative_handles(&self.dirs)
+    }
+
+    /// Log the report at `destroy`, warning only the definite leaks.
+    fn log(&self) {
+        for (id, count) in self.nodes() {
+            if count < 0 {
+                log::warn!("node {}: lookup count {} at destroy", id, count);
+            } else {
+                log::debug!("node {}: lookup count {} at destroy", id, count);
+            }
+        }
+        for (id, fh, count) in self.open_files() {
+            log::warn!(
+                "node {}: file handle {} not released ({} times) at destroy",
+                id,
+                fh,
+                count
+            );
+        }
+        for (id, fh, count) in self.open_dirs() {
+            log::warn!(
+                "node {}: directory handle {} not released ({} times) at destroy",
+                id,
+                fh,
+                count
+            );
+        }
+        for (id, fh, count) in self.negative_files() {
+            log::warn!(
+                "node {}: file handle {} released without open (count {}) at destroy",
+                id,
+                fh,
+                count
+            );
+        }
+        for (id, fh, count) in self.negative_dirs() {
+            log::warn!(
+                "node {}: directory handle {} released without open (count {}) at destroy",
+                id,
+                fh,
+                count
+            );
+        }
+    }
+}
+
+fn open_handles(handles: &Handles) -> impl Iterator<Item = (NodeId, u64, usize)> + '_ {
+    handles
+        .iter()
+        .filter(|&(_, &count)| count > 0)
+        .map(|(&(id, fh), &count)| (id, fh, count as usize))
+}
+
+fn negative_handles(handles: &Handles) -> impl Iterator<Item = (NodeId, u64, i64)> + '_ {
+    handles
+        .iter()
+        .filter(|&(_, &count)| count < 0)
+        .map(|(&(id, fh), &count)| (id, fh, count))
+}
+
+impl fmt::Display for LeakReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return f.write_str("no leaks");
+        }
+        let mut sep = "";
+        for (id, count) in self.nodes() {
+            write!(f, "{}node {}: lookup count {}", sep, id, count)?;
+            sep = "\n";
+        }
+        for (id, fh, count) in self.open_files() {
+            write!(
+                f,
+                "{}node {}: file handle {} not released ({} times)",
+                sep, id, fh, count
+            )?;
+            sep = "\n";
+        }
+        for (id, fh, count) in self.open_dirs() {
+            write!(
+                f,
+                "{}node {}: directory handle {} not released ({} times)",
+                sep, id, fh, count
+            )?;
+            sep = "\n";
+        }
+        for (id, fh, count) in self.negative_files() {
+            write!(
+                f,
+                "{}node {}: file handle {} released without open (count {})",
+                sep, id, fh, count
+            )?;
+            sep = "\n";
+        }
+        for (id, fh, count) in self.negative_dirs() {
+            write!(
+                f,
+                "{}node {}: directory handle {} released without open (count {})",
+                sep, id, fh, count
+            )?;
+            sep = "\n";
+        }
+        Ok(())
+    }
+}
+
+impl<T: Operations> Layer for LeakCheck<T> {
+    type Inner = T;
+
+    fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    fn inner_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    fn destroy(&mut self) -> io::Result<()> {
+        let res = self.inner.destroy();
+        self.report().log();
+        res
+    }
+
+    fn lookup(&mut self, parent: NodeId, name: &CStr) -> OperationResult<Entry> {
+        let res = self.inner.lookup(parent, name);
+        self.track_entry(res)
+    }
+
+    fn forget(&mut self, id: NodeId, nlookup: u64) {
+        self.track(|counts| counts.forget(id, nlookup));
+        self.inner.forget(id, nlookup);
+    }
+
+    fn mknod(
+        &mut self,
+        parent: NodeId,
+        name: &CStr,
+        mode: mode_t,
+        rdev: dev_t,
+    ) -> OperationResult<Entry> {
+        let res = self.inner.mknod(parent, name, mode, rdev);
+        self.track_entry(res)
+    }
+
+    fn mkdir(&mut self, parent: NodeId, name: &CStr, mode: mode_t) -> OperationResult<Entry> {
+        let res = self.inner.mkdir(parent, name, mode);
+        self.track_entry(res)
+    }
+
+    fn symlink(&mut self, link: &CStr, parent: NodeId, name: &CStr) -> OperationResult<Entry> {
+        let res = self.inner.symlink(link, parent, name);
+        self.track_entry(res)
+    }
+
+    fn link(&mut self, id: NodeId, newparent: NodeId, newname: &CStr) -> OperationResult<Entry> {
+        let res = self.inner.link(id, newparent, newname);
+        self.track_entry(res)
+    }
+
+    fn open(&mut self, id: NodeId, options: &mut OpenOptions<'_>) -> OperationResult<u64> {
+        let fh = self.inner.open(id, options)?;
+        self.track(|counts| {
+            Counts::open(&mut counts.files, id, fh);
+        });
+        Ok(fh)
+    }
+
+    fn create(
+        &mut self,
+        parent: NodeId,
+        name: &CStr,
+        mode: mode_t,
+        options: &mut OpenOptions<'_>,
+    ) -> OperationResult<(Entry, u64)> {
+        let (entry, fh) = self.inner.create(parent, name, mode, options)?;
+        self.track(|counts| {
+            counts.entry(&entry);
+            Counts::open(&mut counts.files, entry.nodeid, fh);
+        });
+        Ok((entry, fh))
+    }
+
+    fn release(
+        &mut self,
+        id: NodeId,
+        options: &mut ReleaseOptions<'_>,
+        fh: u64,
+    ) -> OperationResult<()> {
+        self.track(|counts| {
+            Counts::release(&mut counts.files, id, fh);
+        });
+        self.inner.release(id, options, fh)
+    }
+
+    fn opendir(&mut self, id: NodeId, options: &mut OpenDirOptions) -> OperationResult<u64> {
+        let fh = self.inner.opendir(id, options)?;
+        self.track(|counts| {
+            Counts::open(&mut counts.dirs, id, fh);
+        });
+        Ok(fh)
+    }
+
+    fn releasedir(&mut self, id: NodeId, fh: u64) -> OperationResult<()> {
+        self.track(|counts| {
+            Counts::release(&mut counts.dirs, id, fh);
+        });
+        self.inner.releasedir(id, fh)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{common::ROOT_NODEID, testing::Harness};
+
+    /// A filesystem with a single file, whose handle is always zero.
+    struct Single;
+
+    impl Operations for Single {
+        fn lookup(&mut self, _: NodeId, _: &CStr) -> OperationResult<Entry> {
+            Ok(Entry {
+                nodeid: 2,
+                ..Entry::default()
+            })
+        }
+    }
+
+    fn name() -> &'static CStr {
+        CStr::from_bytes_with_nul(b"file\0").unwrap()
+    }
+
+    #[test]
+    #[cfg_attr(not(debug_assertions), ignore)]
+    fn report_after_forget_all() {
+        let mut harness = Harness::new(LeakCheck::new(Single));
+        harness.lookup(ROOT_NODEID, name()).unwrap();
+        harness.lookup(ROOT_NODEID, name()).unwrap();
+        let fh = harness.open(2, libc::O_RDONLY).unwrap();
+        assert_eq!(harness.open(2, libc::O_RDONLY).unwrap(), fh);
+        harness.release(2, fh).unwrap();
+        let dh = harness.opendir(ROOT_NODEID).unwrap();
+
+        let report = harness.ops().report();
+        assert_eq!(report.nodes().collect::<Vec<_>>(), vec![(2, 2)]);
+        assert_eq!(report.open_files().collect::<Vec<_>>(), vec![(2, fh, 1)]);
+        assert_eq!(
+            report.open_dirs().collect::<Vec<_>>(),
+            vec![(ROOT_NODEID, dh, 1)]
+        );
+
+        harness.forget_all();
+        harness.release(2, fh).unwrap();
+        harness.releasedir(ROOT_NODEID, dh).unwrap();
+        let report = harness.ops().report();
+        assert!(report.is_empty(), "{}", report);
+
+        harness.forget(2, 1);
+        let report = harness.ops().report();
+        assert_eq!(report.negative_nodes().collect::<Vec<_>>(), vec![(2, -1)]);
+    }
+
+    #[test]
+    #[cfg_attr(not(debug_assertions), ignore)]
+    fn release_without_open() {
+        let mut harness = Harness::new(LeakCheck::new(Single));
+        let fh = harness.open(2, libc::O_RDONLY).unwrap();
+        harness.release(2, fh).unwrap();
+        harness.release(2, fh).unwrap();
+        harness.releasedir(ROOT_NODEID, 7).unwrap();
+
+        let report = harness.ops().report();
+        assert!(!report.is_empty());
+        assert_eq!(report.open_files().count(), 0);
+        assert_eq!(
+            report.negative_files().collect::<Vec<_>>(),
+            vec![(2, fh, -1)]
+        );
+        assert_eq!(report.open_dirs().count(), 0);
+        assert_eq!(
+            report.negative_dirs().collect::<Vec<_>>(),
+            vec![(ROOT_NODEID, 7, -1)]
+        );
+
+        // A later open balances the extra release.
+        harness.open(2, libc::O_RDONLY).unwrap();
+        assert_eq!(harness.ops().report().negative_files().count(), 0);
+    }
+}
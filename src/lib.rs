@@ -3,12 +3,31 @@
 #![warn(clippy::unimplemented)]
 
 pub mod dir;
+pub mod fault;
 pub mod file;
+pub mod handle;
+pub mod inode;
+pub mod layer;
+pub mod leak;
+pub mod passthrough;
+pub mod path;
+pub mod readonly;
 pub mod session;
+pub mod stats;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 
 mod common;
+mod dispatch;
+#[cfg(feature = "handoff")]
+mod handoff;
+mod interrupt;
+mod logging;
 mod ops;
+mod snapshot;
+mod trace;
 
 pub use crate::common::{CapabilityFlags, ConnectionInfo, NodeId, ROOT_NODEID};
+pub use crate::interrupt::Interrupt;
 pub use crate::ops::{OperationResult, Operations};
-pub use crate::session::Session;
+pub use crate::session::{Session, SessionError};
@@ -0,0 +1,87 @@
+//! Handling of the log messages emitted by libfuse.
+//!
+//! Once a session is built, the messages are forwarded to the `log` crate
+//! with the target `libfuse`, instead of being written to stderr.
+
+use libc::c_char;
+use libfuse_sys::{fuse_log_level::*, helpers::fuse_set_log_func_wrapped};
+use log::Level;
+use std::{cell::RefCell, ffi::CStr, mem, sync::Once};
+
+const TARGET: &str = "libfuse";
+
+static INSTALL: Once = Once::new();
+
+thread_local! {
+    static PARTIAL: RefCell<(Level, String)> = RefCell::new((Level::Debug, String::new()));
+    static CAPTURE: RefCell<Option<Vec<String>>> = RefCell::new(None);
+}
+
+/// Install the log handler that forwards the messages to the `log` crate.
+pub(crate) fn install() {
+    INSTALL.call_once(|| unsafe {
+        fuse_set_log_func_wrapped(Some(on_log));
+    });
+}
+
+/// Call the specified function, collecting the messages that libfuse
+/// reports in the meantime on the current thread.
+///
+/// The collected messages are also forwarded to the `log` crate.
+pub(crate) fn capture<R>(f: impl FnOnce() -> R) -> (R, Vec<String>) {
+    install();
+    CAPTURE.with(|capture| *capture.borrow_mut() = Some(vec![]));
+
+    let res = f();
+
+    let (level, partial) = PARTIAL.with(|partial| {
+        let (level, ref mut partial) = *partial.borrow_mut();
+        (level, mem::replace(partial, String::new()))
+    });
+    if !partial.is_empty() {
+        emit(level, partial);
+    }
+    let lines = CAPTURE
+        .with(|capture| capture.borrow_mut().take())
+        .unwrap_or_default();
+
+    (res, lines)
+}
+
+unsafe extern "C" fn on_log(level: Type, msg: *const c_char) {
+    let level = match level {
+        FUSE_LOG_EMERG | FUSE_LOG_ALERT | FUSE_LOG_CRIT | FUSE_LOG_ERR => Level::Error,
+        FUSE_LOG_WARNING => Level::Warn,
+        FUSE_LOG_NOTICE | FUSE_LOG_INFO => Level::Info,
+        _ => Level::Debug,
+    };
+
+    // libfuse may emit a single line by several calls of `fuse_log`,
+    // so the messages are split by newlines rather than by calls.
+    let msg = CStr::from_ptr(msg).to_string_lossy();
+    let mut lines = vec![];
+    PARTIAL.with(|partial| {
+        let (ref mut partial_level, ref mut partial) = *partial.borrow_mut();
+        *partial_level = level;
+        for c in msg.chars() {
+            if c == '\n' {
+                lines.push(mem::replace(partial, String::new()));
+            } else {
+                partial.push(c);
+            }
+        }
+    });
+
+    for line in lines {
+        emit(level, line);
+    }
+}
+
+fn emit(level: Level, line: String) {
+    log::log!(target: TARGET, level, "{}", line);
+    CAPTURE.with(|capture| {
+        if let Some(ref mut capture) = *capture.borrow_mut() {
+            capture.push(line);
+        }
+    });
+}
@@ -1,6 +1,9 @@
+#[cfg(feature = "handoff")]
+use crate::handoff;
 use crate::{
     common::{ConnectionInfo, NodeId},
-    dir::{DirBuf, OpenDirOptions},
+    dir::{DirBuf, DirEntries, OpenDirOptions},
+    dispatch::{self, Reply},
     file::{
         Entry, //
         FlushOptions,
@@ -13,6 +16,9 @@ use crate::{
         XAttrFlags,
         XAttrReply,
     },
+    interrupt,
+    stats::{self, Stats},
+    trace,
 };
 use libc::{c_char, c_int, c_uint, c_void, dev_t, mode_t, off_t, stat, statvfs};
 use libfuse_sys::{
@@ -32,7 +38,6 @@ use libfuse_sys::{
     fuse_reply_statfs,
     fuse_reply_write,
     fuse_reply_xattr,
-    fuse_req,
     fuse_req_t,
     fuse_req_userdata,
     helpers::{
@@ -48,9 +53,11 @@ use libfuse_sys::{
 };
 use std::{
     borrow::Cow,
+    cell::UnsafeCell,
     ffi::{CStr, CString},
-    mem,
+    io, mem,
     ptr::{self, NonNull},
+    sync::{Arc, Mutex, PoisonError},
 };
 
 pub type OperationResult<T> = std::result::Result<T, c_int>;
@@ -60,6 +67,38 @@ pub trait Operations {
     #[allow(unused_variables)]
     fn init(&mut self, conn: &mut ConnectionInfo<'_>) {}
 
+    /// Clean up the filesystem.
+    ///
+    /// This method is called when the filesystem is unmounted, or when
+    /// the event loop of the session ends. The pending data should be
+    /// flushed here, since the error is reported by `Session::run_loop`.
+    ///
+    /// This method is not called on the filesystem handed off to another
+    /// process by `Session::export`.
+    fn destroy(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Serialize the state referred to by the kernel, for `Session::export`.
+    ///
+    /// The kernel keeps using the node IDs, generation numbers and lookup
+    /// counts, and the file handles, after the session is handed off to
+    /// another process. The returned bytes are passed to the process taking
+    /// over the session as `Handoff::state`, from which the filesystem
+    /// should be restored before `Session::resume`. The tables kept by the
+    /// filesystem can be serialized by `InodeTable::snapshot` and
+    /// `HandleTable::snapshot`.
+    ///
+    /// The default implementation fails, so that the filesystems unaware of
+    /// the handoff are not handed off with their state lost.
+    #[cfg(feature = "handoff")]
+    fn export(&mut self) -> io::Result<Vec<u8>> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "the filesystem does not support handoff",
+        ))
+    }
+
     /// Look up a directory entry by name and get its attributes.
     #[allow(unused_variables)]
     fn lookup(&mut self, parent: NodeId, name: &CStr) -> OperationResult<Entry> {
@@ -272,7 +311,9 @@ pub trait Operations {
     }
 
     /// Read a directory.
-    #[allow(unused_variables)]
+    ///
+    /// The default implementation adds the entries returned by
+    /// `readdir_entries`, with the offsets assigned by the library.
     fn readdir(
         &mut self,
         id: NodeId,
@@ -280,6 +321,20 @@ pub trait Operations {
         buf: &mut DirBuf<'_>,
         fh: u64,
     ) -> OperationResult<()> {
+        let entries = self.readdir_entries(id, fh)?;
+        buf.add_entries(id, offset, entries);
+        Ok(())
+    }
+
+    /// Read a directory as an iterator of the entries.
+    ///
+    /// This method is called by the default implementation of `readdir`,
+    /// on every call of it, and the entries before the requested offset are
+    /// skipped. The iterator must yield the entries in a stable order, and
+    /// the entries `.` and `..` must not be included. See
+    /// `DirBuf::add_entries` for the cost of this form.
+    #[allow(unused_variables)]
+    fn readdir_entries(&mut self, id: NodeId, fh: u64) -> OperationResult<DirEntries<'_>> {
         Err(libc::ENOSYS)
     }
 
@@ -342,13 +397,11 @@ pub(super) unsafe fn assign_ops<T: Operations>(op: &mut fuse_lowlevel_ops, _: &T
 }
 
 unsafe extern "C" fn on_init<T: Operations>(user_data: *mut c_void, conn: *mut fuse_conn_info) {
-    let ctx = make_mut_unchecked(user_data as *mut Context<T>);
-    let conn = make_mut_unchecked(conn);
-    ctx.ops.init(&mut ConnectionInfo(conn));
+    SharedContext::<T>::with(user_data, |ctx| ctx.init(make_mut_unchecked(conn)));
 }
 
 unsafe extern "C" fn on_destroy<T: Operations>(user_data: *mut c_void) {
-    mem::drop(Box::from_raw(user_data as *mut Context<T>));
+    SharedContext::<T>::with(user_data, |ctx| ctx.destroy());
 }
 
 unsafe extern "C" fn on_lookup<T: Operations>(
@@ -356,19 +409,19 @@ unsafe extern "C" fn on_lookup<T: Operations>(
     parent: fuse_ino_t,
     name: *const c_char,
 ) {
-    call_with_ctx(req, |ctx: &mut Context<T>, req| {
-        match ctx.ops.lookup(parent, CStr::from_ptr(name)) {
-            Ok(entry) => ctx.reply_entry(req, entry),
-            Err(errno) => fuse_reply_err(req, errno),
-        }
-    })
+    call_with_ctx(
+        req,
+        "lookup",
+        parent,
+        None,
+        ptr::null_mut(),
+        |ops: &mut T| dispatch::lookup(ops, parent, CStr::from_ptr(name)),
+    )
 }
 
 unsafe extern "C" fn on_forget<T: Operations>(req: fuse_req_t, ino: fuse_ino_t, nlookup: u64) {
-    call_with_ctx(req, |ctx: &mut Context<T>, req| {
-        ctx.ops.forget(ino, nlookup);
-        fuse_reply_none(req);
-        0
+    call_with_ctx(req, "forget", ino, None, ptr::null_mut(), |ops: &mut T| {
+        dispatch::forget(ops, ino, nlookup)
     })
 }
 
@@ -377,12 +430,9 @@ unsafe extern "C" fn on_getattr<T: Operations>(
     ino: fuse_ino_t,
     fi: *mut fuse_file_info,
 ) {
-    call_with_ctx(req, |ctx: &mut Context<T>, req| {
-        let fi = make_mut(fi);
-        match ctx.ops.getattr(ino, fi.map(|fi| fuse_file_info_fh(fi))) {
-            Ok((stat, timeout)) => fuse_reply_attr(req, &stat, timeout),
-            Err(errno) => fuse_reply_err(req, errno),
-        }
+    let fh = file_handle(fi);
+    call_with_ctx(req, "getattr", ino, fh, fi, |ops: &mut T| {
+        dispatch::getattr(ops, ino, fh)
     })
 }
 
@@ -393,30 +443,25 @@ unsafe extern "C" fn on_setattr<T: Operations>(
     to_set: c_int,
     fi: *mut fuse_file_info,
 ) {
-    call_with_ctx(req, |ctx: &mut Context<T>, req| {
-        let fi = make_mut(fi);
-        let attr = make_ref_unchecked(attr);
-        match ctx.ops.setattr(
-            ino,
-            &SetAttrs {
-                attr: &*attr,
-                to_set,
-            },
-            fi.map(|fi| fuse_file_info_fh(fi)),
-        ) {
-            Ok((stat, timeout)) => fuse_reply_attr(req, &stat, timeout),
-            Err(errno) => fuse_reply_err(req, errno),
-        }
+    let fh = file_handle(fi);
+    call_with_ctx(req, "setattr", ino, fh, fi, |ops: &mut T| {
+        let attrs = SetAttrs {
+            attr: make_ref_unchecked(attr),
+            to_set,
+        };
+        dispatch::setattr(ops, ino, &attrs, fh)
     })
 }
 
 unsafe extern "C" fn on_readlink<T: Operations>(req: fuse_req_t, ino: fuse_ino_t) {
-    call_with_ctx(req, |ctx: &mut Context<T>, req| {
-        match ctx.ops.readlink(ino) {
-            Ok(content) => fuse_reply_readlink(req, content.as_ptr()),
-            Err(errno) => fuse_reply_err(req, errno),
-        }
-    })
+    call_with_ctx(
+        req,
+        "readlink",
+        ino,
+        None,
+        ptr::null_mut(),
+        |ops: &mut T| dispatch::readlink(ops, ino),
+    )
 }
 
 unsafe extern "C" fn on_mknod<T: Operations>(
@@ -426,12 +471,14 @@ unsafe extern "C" fn on_mknod<T: Operations>(
     mode: mode_t,
     rdev: dev_t,
 ) {
-    call_with_ctx(req, |ctx: &mut Context<T>, req| {
-        match ctx.ops.mknod(parent, CStr::from_ptr(name), mode, rdev) {
-            Ok(entry) => ctx.reply_entry(req, entry),
-            Err(errno) => fuse_reply_err(req, errno),
-        }
-    })
+    call_with_ctx(
+        req,
+        "mknod",
+        parent,
+        None,
+        ptr::null_mut(),
+        |ops: &mut T| dispatch::mknod(ops, parent, CStr::from_ptr(name), mode, rdev),
+    )
 }
 
 unsafe extern "C" fn on_mkdir<T: Operations>(
@@ -440,12 +487,14 @@ unsafe extern "C" fn on_mkdir<T: Operations>(
     name: *const c_char,
     mode: mode_t,
 ) {
-    call_with_ctx(req, |ctx: &mut Context<T>, req| {
-        match ctx.ops.mkdir(parent, CStr::from_ptr(name), mode) {
-            Ok(entry) => ctx.reply_entry(req, entry),
-            Err(errno) => fuse_reply_err(req, errno),
-        }
-    })
+    call_with_ctx(
+        req,
+        "mkdir",
+        parent,
+        None,
+        ptr::null_mut(),
+        |ops: &mut T| dispatch::mkdir(ops, parent, CStr::from_ptr(name), mode),
+    )
 }
 
 unsafe extern "C" fn on_unlink<T: Operations>(
@@ -453,12 +502,14 @@ unsafe extern "C" fn on_unlink<T: Operations>(
     parent: fuse_ino_t,
     name: *const c_char,
 ) {
-    call_with_ctx(req, |ctx: &mut Context<T>, req| {
-        match ctx.ops.unlink(parent, CStr::from_ptr(name)) {
-            Ok(()) => fuse_reply_err(req, 0),
-            Err(errno) => fuse_reply_err(req, errno),
-        }
-    })
+    call_with_ctx(
+        req,
+        "unlink",
+        parent,
+        None,
+        ptr::null_mut(),
+        |ops: &mut T| dispatch::unlink(ops, parent, CStr::from_ptr(name)),
+    )
 }
 
 unsafe extern "C" fn on_rmdir<T: Operations>(
@@ -466,12 +517,14 @@ unsafe extern "C" fn on_rmdir<T: Operations>(
     parent: fuse_ino_t,
     name: *const c_char,
 ) {
-    call_with_ctx(req, |ctx: &mut Context<T>, req| {
-        match ctx.ops.rmdir(parent, CStr::from_ptr(name)) {
-            Ok(()) => fuse_reply_err(req, 0),
-            Err(errno) => fuse_reply_err(req, errno),
-        }
-    })
+    call_with_ctx(
+        req,
+        "rmdir",
+        parent,
+        None,
+        ptr::null_mut(),
+        |ops: &mut T| dispatch::rmdir(ops, parent, CStr::from_ptr(name)),
+    )
 }
 
 unsafe extern "C" fn on_symlink<T: Operations>(
@@ -480,15 +533,14 @@ unsafe extern "C" fn on_symlink<T: Operations>(
     parent: fuse_ino_t,
     name: *const c_char,
 ) {
-    call_with_ctx(req, |ctx: &mut Context<T>, req| {
-        match ctx
-            .ops
-            .symlink(CStr::from_ptr(link), parent, CStr::from_ptr(name))
-        {
-            Ok(entry) => ctx.reply_entry(req, entry),
-            Err(errno) => fuse_reply_err(req, errno),
-        }
-    })
+    call_with_ctx(
+        req,
+        "symlink",
+        parent,
+        None,
+        ptr::null_mut(),
+        |ops: &mut T| dispatch::symlink(ops, CStr::from_ptr(link), parent, CStr::from_ptr(name)),
+    )
 }
 
 unsafe extern "C" fn on_rename<T: Operations>(
@@ -499,18 +551,23 @@ unsafe extern "C" fn on_rename<T: Operations>(
     newname: *const c_char,
     flags: c_uint,
 ) {
-    call_with_ctx(req, |ctx: &mut Context<T>, req| {
-        match ctx.ops.rename(
-            oldparent,
-            CStr::from_ptr(oldname),
-            newparent,
-            CStr::from_ptr(newname),
-            RenameFlags::from_bits_truncate(flags as c_int),
-        ) {
-            Ok(()) => fuse_reply_err(req, 0),
-            Err(errno) => fuse_reply_err(req, errno),
-        }
-    })
+    call_with_ctx(
+        req,
+        "rename",
+        oldparent,
+        None,
+        ptr::null_mut(),
+        |ops: &mut T| {
+            dispatch::rename(
+                ops,
+                oldparent,
+                CStr::from_ptr(oldname),
+                newparent,
+                CStr::from_ptr(newname),
+                RenameFlags::from_bits_truncate(flags as c_int),
+            )
+        },
+    )
 }
 
 unsafe extern "C" fn on_link<T: Operations>(
@@ -519,11 +576,8 @@ unsafe extern "C" fn on_link<T: Operations>(
     newparent: fuse_ino_t,
     newname: *const c_char,
 ) {
-    call_with_ctx(req, |ctx: &mut Context<T>, req| {
-        match ctx.ops.link(ino, newparent, CStr::from_ptr(newname)) {
-            Ok(entry) => ctx.reply_entry(req, entry),
-            Err(errno) => fuse_reply_err(req, errno),
-        }
+    call_with_ctx(req, "link", ino, None, ptr::null_mut(), |ops: &mut T| {
+        dispatch::link(ops, ino, newparent, CStr::from_ptr(newname))
     })
 }
 
@@ -532,15 +586,8 @@ unsafe extern "C" fn on_open<T: Operations>(
     ino: fuse_ino_t,
     fi: *mut fuse_file_info,
 ) {
-    call_with_ctx(req, |ctx: &mut Context<T>, req| {
-        let fi = make_mut_unchecked(fi);
-        match ctx.ops.open(ino, &mut OpenOptions(fi)) {
-            Ok(fh) => {
-                fuse_file_info_set_fh(fi, fh);
-                fuse_reply_open(req, fi)
-            }
-            Err(errno) => fuse_reply_err(req, errno),
-        }
+    call_with_ctx(req, "open", ino, None, fi, |ops: &mut T| {
+        dispatch::open(ops, ino, make_mut_unchecked(fi))
     })
 }
 
@@ -551,13 +598,8 @@ unsafe extern "C" fn on_read<T: Operations>(
     off: off_t,
     fi: *mut fuse_file_info,
 ) {
-    call_with_ctx(req, |ctx: &mut Context<T>, req| {
-        let fi = make_mut_unchecked(fi);
-        let fh = fuse_file_info_fh(fi);
-        match ctx.ops.read(ino, off, bufsize, &mut ReadOptions(fi), fh) {
-            Ok(data) => reply_buf_limited(req, &data[..std::cmp::min(data.len(), bufsize)]),
-            Err(errno) => fuse_reply_err(req, errno),
-        }
+    call_with_ctx(req, "read", ino, file_handle(fi), fi, |ops: &mut T| {
+        dispatch::read(ops, ino, off, bufsize, make_mut_unchecked(fi))
     })
 }
 
@@ -569,14 +611,9 @@ unsafe extern "C" fn on_write<T: Operations>(
     off: off_t,
     fi: *mut fuse_file_info,
 ) {
-    call_with_ctx(req, |ctx: &mut Context<T>, req| {
-        let fi = make_mut_unchecked(fi);
+    call_with_ctx(req, "write", ino, file_handle(fi), fi, |ops: &mut T| {
         let buf = std::slice::from_raw_parts(buf as *const u8, size);
-        let fh = fuse_file_info_fh(fi);
-        match ctx.ops.write(ino, &buf[..], off, &mut WriteOptions(fi), fh) {
-            Ok(count) => fuse_reply_write(req, count),
-            Err(errno) => fuse_reply_err(req, errno),
-        }
+        dispatch::write(ops, ino, buf, off, make_mut_unchecked(fi))
     })
 }
 
@@ -585,13 +622,8 @@ unsafe extern "C" fn on_flush<T: Operations>(
     ino: fuse_ino_t,
     fi: *mut fuse_file_info,
 ) {
-    call_with_ctx(req, |ctx: &mut Context<T>, req| {
-        let fi = make_mut_unchecked(fi);
-        let fh = fuse_file_info_fh(fi);
-        match ctx.ops.flush(ino, &mut FlushOptions(fi), fh) {
-            Ok(()) => fuse_reply_err(req, 0),
-            Err(errno) => fuse_reply_err(req, errno),
-        }
+    call_with_ctx(req, "flush", ino, file_handle(fi), fi, |ops: &mut T| {
+        dispatch::flush(ops, ino, make_mut_unchecked(fi))
     })
 }
 
@@ -600,13 +632,8 @@ unsafe extern "C" fn on_release<T: Operations>(
     ino: fuse_ino_t,
     fi: *mut fuse_file_info,
 ) {
-    call_with_ctx(req, |ctx: &mut Context<T>, req| {
-        let fi = make_mut_unchecked(fi);
-        let fh = fuse_file_info_fh(fi);
-        match ctx.ops.release(ino, &mut ReleaseOptions(fi), fh) {
-            Ok(()) => fuse_reply_err(req, 0),
-            Err(errno) => fuse_reply_err(req, errno),
-        }
+    call_with_ctx(req, "release", ino, file_handle(fi), fi, |ops: &mut T| {
+        dispatch::release(ops, ino, make_mut_unchecked(fi))
     })
 }
 
@@ -616,13 +643,9 @@ unsafe extern "C" fn on_fsync<T: Operations>(
     datasync: c_int,
     fi: *mut fuse_file_info,
 ) {
-    call_with_ctx(req, |ctx: &mut Context<T>, req| {
-        let fi = make_mut_unchecked(fi);
-        let fh = fuse_file_info_fh(fi);
-        match ctx.ops.fsync(ino, datasync, fh) {
-            Ok(()) => fuse_reply_err(req, 0),
-            Err(errno) => fuse_reply_err(req, errno),
-        }
+    let fh = fuse_file_info_fh(make_ref_unchecked(fi));
+    call_with_ctx(req, "fsync", ino, Some(fh), fi, |ops: &mut T| {
+        dispatch::fsync(ops, ino, datasync, fh)
     })
 }
 
@@ -631,15 +654,8 @@ unsafe extern "C" fn on_opendir<T: Operations>(
     ino: fuse_ino_t,
     fi: *mut fuse_file_info,
 ) {
-    call_with_ctx(req, |ctx: &mut Context<T>, req| {
-        let fi = make_mut_unchecked(fi);
-        match ctx.ops.opendir(ino, &mut OpenDirOptions(fi)) {
-            Ok(fh) => {
-                fuse_file_info_set_fh(fi, fh);
-                fuse_reply_open(req, fi)
-            }
-            Err(errno) => fuse_reply_err(req, errno),
-        }
+    call_with_ctx(req, "opendir", ino, None, fi, |ops: &mut T| {
+        dispatch::opendir(ops, ino, make_mut_unchecked(fi))
     })
 }
 
@@ -650,25 +666,9 @@ unsafe extern "C" fn on_readdir<T: Operations>(
     offset: off_t,
     fi: *mut fuse_file_info,
 ) {
-    call_with_ctx(req, |ctx: &mut Context<T>, req| {
-        let fi = make_mut_unchecked(fi);
-        let fh = fuse_file_info_fh(fi);
-        let mut buf = Vec::with_capacity(size);
-        buf.set_len(size);
-
-        let mut dir_buf = DirBuf {
-            req: &mut *req,
-            buf: &mut buf[..],
-            pos: 0,
-        };
-
-        let res = ctx.ops.readdir(ino, offset, &mut dir_buf, fh);
-        let DirBuf { pos, .. } = dir_buf;
-
-        match res {
-            Ok(()) => reply_buf_limited(req, &buf[..pos]),
-            Err(errno) => fuse_reply_err(req, errno),
-        }
+    let fh = fuse_file_info_fh(make_ref_unchecked(fi));
+    call_with_ctx(req, "readdir", ino, Some(fh), fi, |ops: &mut T| {
+        dispatch::readdir(ops, req, ino, size, offset, fh)
     })
 }
 
@@ -677,13 +677,9 @@ unsafe extern "C" fn on_releasedir<T: Operations>(
     ino: fuse_ino_t,
     fi: *mut fuse_file_info,
 ) {
-    call_with_ctx(req, |ctx: &mut Context<T>, req| {
-        let fi = make_mut_unchecked(fi);
-        let fh = fuse_file_info_fh(fi);
-        match ctx.ops.releasedir(ino, fh) {
-            Ok(()) => fuse_reply_err(req, 0),
-            Err(errno) => fuse_reply_err(req, errno),
-        }
+    let fh = fuse_file_info_fh(make_ref_unchecked(fi));
+    call_with_ctx(req, "releasedir", ino, Some(fh), fi, |ops: &mut T| {
+        dispatch::releasedir(ops, ino, fh)
     })
 }
 
@@ -693,20 +689,15 @@ unsafe extern "C" fn on_fsyncdir<T: Operations>(
     datasync: c_int,
     fi: *mut fuse_file_info,
 ) {
-    call_with_ctx(req, |ctx: &mut Context<T>, req| {
-        let fi = make_mut_unchecked(fi);
-        let fh = fuse_file_info_fh(fi);
-        match ctx.ops.fsyncdir(ino, datasync, fh) {
-            Ok(()) => fuse_reply_err(req, 0),
-            Err(errno) => fuse_reply_err(req, errno),
-        }
+    let fh = fuse_file_info_fh(make_ref_unchecked(fi));
+    call_with_ctx(req, "fsyncdir", ino, Some(fh), fi, |ops: &mut T| {
+        dispatch::fsyncdir(ops, ino, datasync, fh)
     })
 }
 
 unsafe extern "C" fn on_statfs<T: Operations>(req: fuse_req_t, ino: fuse_ino_t) {
-    call_with_ctx(req, |ctx: &mut Context<T>, req| match ctx.ops.statfs(ino) {
-        Ok(stat) => fuse_reply_statfs(req, &stat),
-        Err(errno) => fuse_reply_err(req, errno),
+    call_with_ctx(req, "statfs", ino, None, ptr::null_mut(), |ops: &mut T| {
+        dispatch::statfs(ops, ino)
     })
 }
 
@@ -718,18 +709,23 @@ unsafe extern "C" fn on_setxattr<T: Operations>(
     size: usize,
     flags: c_int,
 ) {
-    call_with_ctx(req, |ctx: &mut Context<T>, req| {
-        let value = std::slice::from_raw_parts(value as *const u8, size);
-        match ctx.ops.setxattr(
-            ino,
-            CStr::from_ptr(name),
-            value,
-            XAttrFlags::from_bits_truncate(flags),
-        ) {
-            Ok(()) => fuse_reply_err(req, 0),
-            Err(errno) => fuse_reply_err(req, errno),
-        }
-    })
+    call_with_ctx(
+        req,
+        "setxattr",
+        ino,
+        None,
+        ptr::null_mut(),
+        |ops: &mut T| {
+            let value = std::slice::from_raw_parts(value as *const u8, size);
+            dispatch::setxattr(
+                ops,
+                ino,
+                CStr::from_ptr(name),
+                value,
+                XAttrFlags::from_bits_truncate(flags),
+            )
+        },
+    )
 }
 
 unsafe extern "C" fn on_getxattr<T: Operations>(
@@ -738,25 +734,25 @@ unsafe extern "C" fn on_getxattr<T: Operations>(
     name: *const c_char,
     size: usize,
 ) {
-    call_with_ctx(req, |ctx: &mut Context<T>, req| {
-        match ctx.ops.getxattr(ino, CStr::from_ptr(name), size) {
-            Ok(XAttrReply::Size(size)) => fuse_reply_xattr(req, size),
-            Ok(XAttrReply::Data(ref data)) if data.len() <= size => reply_buf_limited(req, &*data),
-            Ok(XAttrReply::Data(..)) => fuse_reply_err(req, libc::ERANGE),
-            Err(errno) => fuse_reply_err(req, errno),
-        }
-    })
+    call_with_ctx(
+        req,
+        "getxattr",
+        ino,
+        None,
+        ptr::null_mut(),
+        |ops: &mut T| dispatch::getxattr(ops, ino, CStr::from_ptr(name), size),
+    )
 }
 
 unsafe extern "C" fn on_listxattr<T: Operations>(req: fuse_req_t, ino: fuse_ino_t, size: usize) {
-    call_with_ctx(req, |ctx: &mut Context<T>, req| {
-        match ctx.ops.listxattr(ino, size) {
-            Ok(XAttrReply::Size(size)) => fuse_reply_xattr(req, size),
-            Ok(XAttrReply::Data(ref data)) if data.len() <= size => reply_buf_limited(req, &*data),
-            Ok(XAttrReply::Data(..)) => fuse_reply_err(req, libc::ERANGE),
-            Err(errno) => fuse_reply_err(req, errno),
-        }
-    })
+    call_with_ctx(
+        req,
+        "listxattr",
+        ino,
+        None,
+        ptr::null_mut(),
+        |ops: &mut T| dispatch::listxattr(ops, ino, size),
+    )
 }
 
 unsafe extern "C" fn on_removexattr<T: Operations>(
@@ -764,20 +760,19 @@ unsafe extern "C" fn on_removexattr<T: Operations>(
     ino: fuse_ino_t,
     name: *const c_char,
 ) {
-    call_with_ctx(req, |ctx: &mut Context<T>, req| {
-        match ctx.ops.removexattr(ino, CStr::from_ptr(name)) {
-            Ok(()) => fuse_reply_err(req, 0),
-            Err(errno) => fuse_reply_err(req, errno),
-        }
-    })
+    call_with_ctx(
+        req,
+        "removexattr",
+        ino,
+        None,
+        ptr::null_mut(),
+        |ops: &mut T| dispatch::removexattr(ops, ino, CStr::from_ptr(name)),
+    )
 }
 
 unsafe extern "C" fn on_access<T: Operations>(req: fuse_req_t, ino: fuse_ino_t, mask: c_int) {
-    call_with_ctx(req, |ctx: &mut Context<T>, req| {
-        match ctx.ops.access(ino, mask) {
-            Ok(()) => fuse_reply_err(req, 0),
-            Err(errno) => fuse_reply_err(req, errno),
-        }
+    call_with_ctx(req, "access", ino, None, ptr::null_mut(), |ops: &mut T| {
+        dispatch::access(ops, ino, mask)
     })
 }
 
@@ -788,26 +783,81 @@ unsafe extern "C" fn on_create<T: Operations>(
     mode: mode_t,
     fi: *mut fuse_file_info,
 ) {
-    call_with_ctx(req, |ctx: &mut Context<T>, req| {
-        let fi = make_mut_unchecked(fi);
-        match ctx
-            .ops
-            .create(parent, CStr::from_ptr(name), mode, &mut OpenOptions(fi))
-        {
-            Ok((entry, fh)) => {
-                fuse_file_info_set_fh(fi, fh);
-                ctx.reply_create(req, entry, fi)
-            }
-            Err(errno) => fuse_reply_err(req, errno),
-        }
+    call_with_ctx(req, "create", parent, None, fi, |ops: &mut T| {
+        dispatch::create(
+            ops,
+            parent,
+            CStr::from_ptr(name),
+            mode,
+            make_mut_unchecked(fi),
+        )
     })
 }
 
 // ==== helpers ====
 
+/// The context passed to libfuse as the user data of the session.
+///
+/// The callbacks are called from the worker threads when the session runs
+/// the multi-threaded event loop, so they take the lock before accessing
+/// the context. The requests are therefore processed one at a time.
+pub(crate) struct SharedContext<T: Operations> {
+    lock: Mutex<()>,
+    ctx: UnsafeCell<Context<T>>,
+    #[cfg(feature = "handoff")]
+    capture: handoff::Capture,
+}
+
+impl<T: Operations> SharedContext<T> {
+    pub(crate) fn new(ctx: Context<T>) -> Self {
+        Self {
+            lock: Mutex::new(()),
+            ctx: UnsafeCell::new(ctx),
+            #[cfg(feature = "handoff")]
+            capture: handoff::Capture::default(),
+        }
+    }
+
+    #[cfg(feature = "handoff")]
+    pub(crate) unsafe fn from_user_data<'a>(user_data: *mut c_void) -> &'a Self {
+        make_ref_unchecked(user_data as *const Self)
+    }
+
+    /// Returns the `INIT` request recorded for `Session::export`.
+    ///
+    /// This is accessed by the worker threads without taking the lock.
+    #[cfg(feature = "handoff")]
+    pub(crate) fn capture(&self) -> &handoff::Capture {
+        &self.capture
+    }
+
+    /// Returns a pointer to the context without taking the lock.
+    ///
+    /// The pointer must not be dereferenced while the event loop is running.
+    pub(crate) fn get(&self) -> *mut Context<T> {
+        self.ctx.get()
+    }
+
+    pub(crate) fn into_inner(self) -> Context<T> {
+        self.ctx.into_inner()
+    }
+
+    /// Call `f` with the context pointed by `user_data`, holding the lock.
+    unsafe fn with<R>(user_data: *mut c_void, f: impl FnOnce(&mut Context<T>) -> R) -> R {
+        let this = make_ref_unchecked(user_data as *const Self);
+        let _lock = this.lock.lock().unwrap_or_else(PoisonError::into_inner);
+        f(&mut *this.ctx.get())
+    }
+}
+
 pub(crate) struct Context<T: Operations> {
     ops: T,
     entry_buf: NonNull<fuse_entry_param>,
+    initialized: bool,
+    destroyed: bool,
+    destroy_error: Option<io::Error>,
+    stats: Option<Arc<Stats>>,
+    interrupts: Arc<interrupt::Registry>,
 }
 
 impl<T: Operations> Drop for Context<T> {
@@ -819,48 +869,194 @@ impl<T: Operations> Drop for Context<T> {
 }
 
 impl<T: Operations> Context<T> {
-    pub(crate) fn new(ops: T) -> Self {
+    pub(crate) fn new(ops: T, stats: Option<Arc<Stats>>) -> Self {
         Self {
             ops,
             entry_buf: NonNull::new(unsafe { fuse_entry_param_new() }).expect("no memory space"),
+            initialized: false,
+            destroyed: false,
+            destroy_error: None,
+            stats,
+            interrupts: Arc::default(),
         }
     }
 
-    unsafe fn fill_entry(&mut self, entry: Entry) -> &fuse_entry_param {
-        let buf = self.entry_buf.as_mut();
-        fuse_entry_param_ino(buf, entry.nodeid);
-        fuse_entry_param_generation(buf, entry.generation);
-        fuse_entry_param_attr(buf, &entry.attr);
-        fuse_entry_param_attr_timeout(buf, entry.attr_timeout);
-        fuse_entry_param_entry_timeout(buf, entry.entry_timeout);
-        buf
+    #[cfg(feature = "handoff")]
+    pub(crate) fn is_initialized(&self) -> bool {
+        self.initialized
+    }
+
+    #[cfg(feature = "handoff")]
+    pub(crate) fn export(&mut self) -> io::Result<Vec<u8>> {
+        self.ops.export()
+    }
+
+    /// Prevent `Operations::destroy` from being called, since the filesystem
+    /// has been handed off to another process.
+    #[cfg(feature = "handoff")]
+    pub(crate) fn skip_destroy(&mut self) {
+        self.destroyed = true;
+    }
+
+    pub(crate) fn ops(&self) -> &T {
+        &self.ops
+    }
+
+    pub(crate) fn ops_mut(&mut self) -> &mut T {
+        &mut self.ops
+    }
+
+    pub(crate) fn stats(&self) -> Option<&Arc<Stats>> {
+        self.stats.as_ref()
     }
 
-    fn reply_entry(&mut self, req: &mut fuse_req, entry: Entry) -> c_int {
-        unsafe { fuse_reply_entry(req, self.fill_entry(entry)) }
+    pub(crate) fn init(&mut self, conn: &mut fuse_conn_info) {
+        self.ops.init(&mut ConnectionInfo(conn));
+        self.initialized = true;
     }
 
-    fn reply_create(&mut self, req: &mut fuse_req, entry: Entry, fi: &mut fuse_file_info) -> c_int {
-        unsafe { fuse_reply_create(req, self.fill_entry(entry), fi) }
+    /// Call `Operations::destroy` if the filesystem has been initialized
+    /// and not destroyed yet.
+    pub(crate) fn destroy(&mut self) {
+        if self.initialized && !self.destroyed {
+            self.destroyed = true;
+            if let Err(err) = self.ops.destroy() {
+                self.destroy_error = Some(err);
+            }
+        }
+    }
+
+    pub(crate) fn take_destroy_error(&mut self) -> Option<io::Error> {
+        self.destroy_error.take()
+    }
+
+    pub(crate) fn into_ops(self) -> T {
+        let mut this = mem::ManuallyDrop::new(self);
+        unsafe {
+            libc::free(this.entry_buf.as_ptr() as *mut _);
+            mem::drop(this.destroy_error.take());
+            mem::drop(this.stats.take());
+            mem::drop(ptr::read(&this.interrupts));
+            ptr::read(&this.ops)
+        }
+    }
+
+    /// Process a request by the reply-agnostic body `f`, and pass the
+    /// reply to `send`.
+    ///
+    /// The request is traced and measured, and can be interrupted while
+    /// `f` is running. `req` is null if the request is not received from
+    /// libfuse, i.e. it is synthesized by `testing::Harness`.
+    pub(crate) fn dispatch<F, S, R>(
+        &mut self,
+        op: &'static str,
+        req: fuse_req_t,
+        ino: NodeId,
+        fh: Option<u64>,
+        f: F,
+        send: S,
+    ) -> R
+    where
+        F: FnOnce(&mut T) -> OperationResult<Reply<'_>>,
+        S: FnOnce(OperationResult<Reply<'_>>) -> R,
+    {
+        let _span = trace::enter(op, req, ino, fh);
+        let _stats = stats::enter(self.stats.clone(), op);
+        let res = {
+            let _guard = interrupt::enter(req, &self.interrupts);
+            f(&mut self.ops)
+        };
+
+        match res {
+            Ok(Reply::Open(fh)) | Ok(Reply::Create(_, fh)) => trace::record_fh(fh),
+            Ok(Reply::Write(size)) => {
+                trace::record_size(size);
+                stats::record_size(size);
+            }
+            Ok(Reply::Buf(ref data)) => {
+                trace::record_size(data.len());
+                stats::record_size(data.len());
+            }
+            Ok(..) => (),
+            Err(errno) => {
+                trace::record_errno(errno);
+                stats::record_errno(errno);
+            }
+        }
+
+        send(res)
     }
 }
 
 unsafe fn call_with_ctx<T: Operations>(
     req: fuse_req_t,
-    f: impl FnOnce(&mut Context<T>, &mut fuse_req) -> c_int,
+    op: &'static str,
+    ino: fuse_ino_t,
+    fh: Option<u64>,
+    fi: *mut fuse_file_info,
+    f: impl FnOnce(&mut T) -> OperationResult<Reply<'_>>,
 ) {
-    let req = make_mut_unchecked(req);
-    let ctx = make_mut_unchecked(fuse_req_userdata(req) as *mut Context<T>);
-    f(ctx, req);
+    SharedContext::<T>::with(fuse_req_userdata(req), |ctx| {
+        let entry_buf = ctx.entry_buf;
+        ctx.dispatch(op, req, ino, fh, f, |res| {
+            send_reply(req, fi, entry_buf, res)
+        });
+    })
 }
 
-unsafe fn reply_buf_limited(req: &mut fuse_req, buf: &[u8]) -> c_int {
-    match buf.len() {
-        0 => fuse_reply_buf(req, ptr::null_mut(), 0),
-        n => fuse_reply_buf(req, buf.as_ptr() as *const c_char, n),
+/// Send the reply to libfuse.
+///
+/// `fi` must be valid if the reply is `Open` or `Create`.
+unsafe fn send_reply(
+    req: fuse_req_t,
+    fi: *mut fuse_file_info,
+    entry_buf: NonNull<fuse_entry_param>,
+    res: OperationResult<Reply<'_>>,
+) -> c_int {
+    match res {
+        Ok(Reply::None) => {
+            fuse_reply_none(req);
+            0
+        }
+        Ok(Reply::Ok) => fuse_reply_err(req, 0),
+        Ok(Reply::Entry(entry)) => fuse_reply_entry(req, fill_entry(entry_buf, &entry)),
+        Ok(Reply::Create(entry, fh)) => {
+            let fi = make_mut_unchecked(fi);
+            fuse_file_info_set_fh(fi, fh);
+            fuse_reply_create(req, fill_entry(entry_buf, &entry), fi)
+        }
+        Ok(Reply::Attr(attr, timeout)) => fuse_reply_attr(req, &attr, timeout),
+        Ok(Reply::Readlink(link)) => fuse_reply_readlink(req, link.as_ptr()),
+        Ok(Reply::Open(fh)) => {
+            let fi = make_mut_unchecked(fi);
+            fuse_file_info_set_fh(fi, fh);
+            fuse_reply_open(req, fi)
+        }
+        Ok(Reply::Write(count)) => fuse_reply_write(req, count),
+        Ok(Reply::Buf(data)) => match data.len() {
+            0 => fuse_reply_buf(req, ptr::null_mut(), 0),
+            n => fuse_reply_buf(req, data.as_ptr() as *const c_char, n),
+        },
+        Ok(Reply::Statfs(stat)) => fuse_reply_statfs(req, &stat),
+        Ok(Reply::Xattr(size)) => fuse_reply_xattr(req, size),
+        Err(errno) => fuse_reply_err(req, errno),
     }
 }
 
+unsafe fn fill_entry(mut buf: NonNull<fuse_entry_param>, entry: &Entry) -> *const fuse_entry_param {
+    let buf = buf.as_mut();
+    fuse_entry_param_ino(buf, entry.nodeid);
+    fuse_entry_param_generation(buf, entry.generation);
+    fuse_entry_param_attr(buf, &entry.attr);
+    fuse_entry_param_attr_timeout(buf, entry.attr_timeout);
+    fuse_entry_param_entry_timeout(buf, entry.entry_timeout);
+    buf
+}
+
+unsafe fn file_handle(fi: *mut fuse_file_info) -> Option<u64> {
+    make_mut(fi).map(|fi| fuse_file_info_fh(fi))
+}
+
 fn make_mut<'a, T>(ptr: *mut T) -> Option<&'a mut T> {
     if !ptr.is_null() {
         Some(unsafe { &mut *ptr })
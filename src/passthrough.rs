@@ -0,0 +1,721 @@
+//! A filesystem that mirrors a directory on the host.
+
+use crate::{
+    common::{NodeId, ROOT_NODEID},
+    dir::{DirBuf, OpenDirOptions},
+    file::{
+        Entry, //
+        FlushOptions,
+        OpenOptions,
+        ReadOptions,
+        ReleaseOptions,
+        RenameFlags,
+        SetAttrs,
+        WriteOptions,
+        XAttrFlags,
+        XAttrReply,
+    },
+    handle::HandleTable,
+    inode::InodeTable,
+    ops::{OperationResult, Operations},
+};
+use libc::{c_int, c_uint, dev_t, ino_t, mode_t, off_t, stat, statvfs, timespec};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    ffi::{CStr, CString},
+    fs::File,
+    io, mem,
+    os::unix::{
+        ffi::OsStrExt,
+        io::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    },
+    path::Path,
+    ptr::NonNull,
+};
+
+/// A filesystem that mirrors an existing directory on the host, in the
+/// same manner as `passthrough_ll` in the examples of libfuse.
+///
+/// Each inode holds a file descriptor opened with `O_PATH`, and the
+/// operations are performed by `openat`/`fstatat`-style calls relative
+/// to it, so that the renames on the host do not confuse the filesystem.
+/// The inode numbers reported by `getattr` and `readdir` are those of the
+/// host, which differ from the node IDs.
+///
+/// `Passthrough` can also be wrapped by another implementation of
+/// `Operations`, which overrides some of the operations.
+pub struct Passthrough {
+    inodes: InodeTable<Inode>,
+    ids: HashMap<(dev_t, ino_t), NodeId>,
+    files: HandleTable<File>,
+    dirs: HandleTable<DirHandle>,
+    timeout: f64,
+}
+
+struct Inode {
+    fd: OwnedFd,
+    dev: dev_t,
+    ino: ino_t,
+}
+
+/// An opened directory, read lazily by `readdir` as in `passthrough_ll`.
+struct DirHandle {
+    dp: NonNull<libc::DIR>,
+    /// The position of the directory stream, as returned by `telldir`.
+    offset: off_t,
+}
+
+// The directory stream is accessed only through `&mut Passthrough`.
+unsafe impl Send for DirHandle {}
+
+impl Drop for DirHandle {
+    fn drop(&mut self) {
+        unsafe {
+            libc::closedir(self.dp.as_ptr());
+        }
+    }
+}
+
+impl Passthrough {
+    /// Create a filesystem that mirrors the specified directory.
+    pub fn new(root: impl AsRef<Path>) -> io::Result<Self> {
+        let path = CString::new(root.as_ref().as_os_str().as_bytes())?;
+        let fd = unsafe { libc::open(path.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let attr = stat_fd(fd.as_raw_fd()).map_err(io::Error::from_raw_os_error)?;
+        if (attr.st_mode & libc::S_IFMT) != libc::S_IFDIR {
+            return Err(io::Error::from_raw_os_error(libc::ENOTDIR));
+        }
+
+        let mut ids = HashMap::new();
+        ids.insert((attr.st_dev, attr.st_ino), ROOT_NODEID);
+
+        Ok(Self {
+            // The inodes are owned by the host filesystem, so they are
+            // dropped as soon as the kernel forgets them.
+            inodes: InodeTable::new(Inode {
+                fd,
+                dev: attr.st_dev,
+                ino: attr.st_ino,
+            })
+            .evict_on_forget(true),
+            ids,
+            files: HandleTable::new(),
+            dirs: HandleTable::new(),
+            timeout: 0.0,
+        })
+    }
+
+    /// Set the timeout in seconds for which the names and attributes
+    /// are cached by the kernel.
+    ///
+    /// The default is zero, since the host directory may be modified
+    /// outside of the filesystem.
+    pub fn timeout(mut self, timeout: f64) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    fn fd(&self, id: NodeId) -> OperationResult<RawFd> {
+        let inode = self.inodes.get(id).ok_or(libc::ENOENT)?;
+        Ok(inode.fd.as_raw_fd())
+    }
+
+    fn file(&self, fh: u64) -> OperationResult<&File> {
+        self.files.get(fh).ok_or(libc::EBADF)
+    }
+
+    fn entry(&mut self, parent: NodeId, name: &CStr) -> OperationResult<Entry> {
+        let fd = cvt(unsafe {
+            libc::openat(
+                self.fd(parent)?,
+                name.as_ptr(),
+                libc::O_PATH | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+            )
+        })?;
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        let attr = stat_fd(fd.as_raw_fd())?;
+
+        let id = match self.ids.get(&(attr.st_dev, attr.st_ino)) {
+            Some(&id) => id,
+            None => {
+                let id = self.inodes.insert(Inode {
+                    fd,
+                    dev: attr.st_dev,
+                    ino: attr.st_ino,
+                });
+                self.ids.insert((attr.st_dev, attr.st_ino), id);
+                id
+            }
+        };
+
+        let mut entry = self.inodes.entry(id, attr).ok_or(libc::ENOENT)?;
+        entry.attr.st_ino = attr.st_ino;
+        entry.attr_timeout = self.timeout;
+        entry.entry_timeout = self.timeout;
+        Ok(entry)
+    }
+
+    fn getattr_inner(&self, id: NodeId) -> OperationResult<(stat, f64)> {
+        let attr = stat_fd(self.fd(id)?)?;
+        Ok((attr, self.timeout))
+    }
+}
+
+impl Operations for Passthrough {
+    fn lookup(&mut self, parent: NodeId, name: &CStr) -> OperationResult<Entry> {
+        self.entry(parent, name)
+    }
+
+    fn forget(&mut self, id: NodeId, nlookup: u64) {
+        if let Some(inode) = self.inodes.forget(id, nlookup) {
+            self.ids.remove(&(inode.dev, inode.ino));
+        }
+    }
+
+    fn readlink(&mut self, id: NodeId) -> OperationResult<CString> {
+        let mut buf = vec![0u8; libc::PATH_MAX as usize + 1];
+        let len = unsafe {
+            libc::readlinkat(
+                self.fd(id)?,
+                empty().as_ptr(),
+                buf.as_mut_ptr() as *mut _,
+                buf.len(),
+            )
+        };
+        if len == -1 {
+            return Err(errno());
+        }
+        if len as usize == buf.len() {
+            return Err(libc::ENAMETOOLONG);
+        }
+        buf.truncate(len as usize);
+        CString::new(buf).map_err(|_| libc::EIO)
+    }
+
+    fn mknod(
+        &mut self,
+        parent: NodeId,
+        name: &CStr,
+        mode: mode_t,
+        rdev: dev_t,
+    ) -> OperationResult<Entry> {
+        cvt(unsafe { libc::mknodat(self.fd(parent)?, name.as_ptr(), mode, rdev) })?;
+        self.entry(parent, name)
+    }
+
+    fn mkdir(&mut self, parent: NodeId, name: &CStr, mode: mode_t) -> OperationResult<Entry> {
+        cvt(unsafe { libc::mkdirat(self.fd(parent)?, name.as_ptr(), mode) })?;
+        self.entry(parent, name)
+    }
+
+    fn unlink(&mut self, parent: NodeId, name: &CStr) -> OperationResult<()> {
+        cvt(unsafe { libc::unlinkat(self.fd(parent)?, name.as_ptr(), 0) })?;
+        Ok(())
+    }
+
+    fn rmdir(&mut self, parent: NodeId, name: &CStr) -> OperationResult<()> {
+        cvt(unsafe { libc::unlinkat(self.fd(parent)?, name.as_ptr(), libc::AT_REMOVEDIR) })?;
+        Ok(())
+    }
+
+    fn symlink(&mut self, link: &CStr, parent: NodeId, name: &CStr) -> OperationResult<Entry> {
+        cvt(unsafe { libc::symlinkat(link.as_ptr(), self.fd(parent)?, name.as_ptr()) })?;
+        self.entry(parent, name)
+    }
+
+    fn rename(
+        &mut self,
+        oldparent: NodeId,
+        oldname: &CStr,
+        newparent: NodeId,
+        newname: &CStr,
+        flags: RenameFlags,
+    ) -> OperationResult<()> {
+        let olddirfd = self.fd(oldparent)?;
+        let newdirfd = self.fd(newparent)?;
+        if flags.is_empty() {
+            cvt(unsafe { libc::renameat(olddirfd, oldname.as_ptr(), newdirfd, newname.as_ptr()) })?;
+        } else {
+            let res = unsafe {
+                libc::syscall(
+                    libc::SYS_renameat2,
+                    olddirfd,
+                    oldname.as_ptr(),
+                    newdirfd,
+                    newname.as_ptr(),
+                    flags.bits() as c_uint,
+                )
+            };
+            if res == -1 {
+                return Err(errno());
+            }
+        }
+        Ok(())
+    }
+
+    fn link(&mut self, id: NodeId, newparent: NodeId, newname: &CStr) -> OperationResult<Entry> {
+        cvt(unsafe {
+            libc::linkat(
+                libc::AT_FDCWD,
+                proc_path(self.fd(id)?).as_ptr(),
+                self.fd(newparent)?,
+                newname.as_ptr(),
+                libc::AT_SYMLINK_FOLLOW,
+            )
+        })?;
+        self.entry(newparent, newname)
+    }
+
+    fn statfs(&mut self, id: NodeId) -> OperationResult<statvfs> {
+        let mut st: statvfs = unsafe { mem::zeroed() };
+        cvt(unsafe { libc::fstatvfs(self.fd(id)?, &mut st) })?;
+        Ok(st)
+    }
+
+    fn setxattr(
+        &mut self,
+        id: NodeId,
+        name: &CStr,
+        value: &[u8],
+        flags: XAttrFlags,
+    ) -> OperationResult<()> {
+        cvt(unsafe {
+            libc::setxattr(
+                proc_path(self.fd(id)?).as_ptr(),
+                name.as_ptr(),
+                value.as_ptr() as *const _,
+                value.len(),
+                flags.bits(),
+            )
+        })?;
+        Ok(())
+    }
+
+    fn getxattr(
+        &mut self,
+        id: NodeId,
+        name: &CStr,
+        size: usize,
+    ) -> OperationResult<XAttrReply<'_>> {
+        let path = proc_path(self.fd(id)?);
+        let mut buf = vec![0u8; size];
+        let len = unsafe {
+            libc::getxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                buf.as_mut_ptr() as *mut _,
+                buf.len(),
+            )
+        };
+        if len == -1 {
+            return Err(errno());
+        }
+        if size == 0 {
+            return Ok(XAttrReply::Size(len as usize));
+        }
+        buf.truncate(len as usize);
+        Ok(XAttrReply::Data(buf.into()))
+    }
+
+    fn listxattr(&mut self, id: NodeId, size: usize) -> OperationResult<XAttrReply<'_>> {
+        let path = proc_path(self.fd(id)?);
+        let mut buf = vec![0u8; size];
+        let len = unsafe { libc::listxattr(path.as_ptr(), buf.as_mut_ptr() as *mut _, buf.len()) };
+        if len == -1 {
+            return Err(errno());
+        }
+        if size == 0 {
+            return Ok(XAttrReply::Size(len as usize));
+        }
+        buf.truncate(len as usize);
+        Ok(XAttrReply::Data(buf.into()))
+    }
+
+    fn removexattr(&mut self, id: NodeId, name: &CStr) -> OperationResult<()> {
+        let path = proc_path(self.fd(id)?);
+        cvt(unsafe { libc::removexattr(path.as_ptr(), name.as_ptr()) })?;
+        Ok(())
+    }
+
+    fn access(&mut self, id: NodeId, mask: c_int) -> OperationResult<()> {
+        let path = proc_path(self.fd(id)?);
+        cvt(unsafe { libc::faccessat(libc::AT_FDCWD, path.as_ptr(), mask, 0) })?;
+        Ok(())
+    }
+
+    fn open(&mut self, id: NodeId, options: &mut OpenOptions<'_>) -> OperationResult<u64> {
+        let path = proc_path(self.fd(id)?);
+        let flags = (options.flags() & !libc::O_NOFOLLOW) | libc::O_CLOEXEC;
+        let fd = cvt(unsafe { libc::open(path.as_ptr(), flags) })?;
+        Ok(self.files.insert(unsafe { File::from_raw_fd(fd) }))
+    }
+
+    fn create(
+        &mut self,
+        parent: NodeId,
+        name: &CStr,
+        mode: mode_t,
+        options: &mut OpenOptions<'_>,
+    ) -> OperationResult<(Entry, u64)> {
+        let flags = (options.flags() & !libc::O_NOFOLLOW) | libc::O_CREAT | libc::O_CLOEXEC;
+        let fd = cvt(unsafe {
+            libc::openat(self.fd(parent)?, name.as_ptr(), flags, c_uint::from(mode))
+        })?;
+        let file = unsafe { File::from_raw_fd(fd) };
+
+        let entry = self.entry(parent, name)?;
+        Ok((entry, self.files.insert(file)))
+    }
+
+    fn read(
+        &mut self,
+        _: NodeId,
+        off: off_t,
+        bufsize: usize,
+        _: &mut ReadOptions<'_>,
+        fh: u64,
+    ) -> OperationResult<Cow<'_, [u8]>> {
+        let file = self.file(fh)?;
+        let mut buf = vec![0u8; bufsize];
+        let len =
+            unsafe { libc::pread(file.as_raw_fd(), buf.as_mut_ptr() as *mut _, buf.len(), off) };
+        if len == -1 {
+            return Err(errno());
+        }
+        buf.truncate(len as usize);
+        Ok(buf.into())
+    }
+
+    fn write(
+        &mut self,
+        _: NodeId,
+        buf: &[u8],
+        off: off_t,
+        _: &mut WriteOptions<'_>,
+        fh: u64,
+    ) -> OperationResult<usize> {
+        let file = self.file(fh)?;
+        let len =
+            unsafe { libc::pwrite(file.as_raw_fd(), buf.as_ptr() as *const _, buf.len(), off) };
+        if len == -1 {
+            return Err(errno());
+        }
+        Ok(len as usize)
+    }
+
+    fn flush(&mut self, _: NodeId, _: &mut FlushOptions<'_>, fh: u64) -> OperationResult<()> {
+        // Closing a duplicate of the descriptor reports the deferred
+        // errors, as `close(2)` on the kernel side does.
+        let fd = cvt(unsafe { libc::dup(self.file(fh)?.as_raw_fd()) })?;
+        cvt(unsafe { libc::close(fd) })?;
+        Ok(())
+    }
+
+    fn getattr(&mut self, id: NodeId, _: Option<u64>) -> OperationResult<(stat, f64)> {
+        self.getattr_inner(id)
+    }
+
+    fn setattr(
+        &mut self,
+        id: NodeId,
+        attrs: &SetAttrs<'_>,
+        fh: Option<u64>,
+    ) -> OperationResult<(stat, f64)> {
+        let fd = self.fd(id)?;
+        let file = match fh {
+            Some(fh) => Some(self.file(fh)?.as_raw_fd()),
+            None => None,
+        };
+        let path = proc_path(fd);
+
+        if let Some(mode) = attrs.mode() {
+            cvt(unsafe {
+                match file {
+                    Some(file) => libc::fchmod(file, mode),
+                    None => libc::chmod(path.as_ptr(), mode),
+                }
+            })?;
+        }
+
+        if attrs.uid().is_some() || attrs.gid().is_some() {
+            let uid = attrs.uid().unwrap_or(!0);
+            let gid = attrs.gid().unwrap_or(!0);
+            cvt(unsafe {
+                libc::fchownat(
+                    fd,
+                    empty().as_ptr(),
+                    uid,
+                    gid,
+                    libc::AT_EMPTY_PATH | libc::AT_SYMLINK_NOFOLLOW,
+                )
+            })?;
+        }
+
+        if let Some(size) = attrs.size() {
+            cvt(unsafe {
+                match file {
+                    Some(file) => libc::ftruncate(file, size),
+                    None => libc::truncate(path.as_ptr(), size),
+                }
+            })?;
+        }
+
+        if attrs.atime().is_some() || attrs.mtime().is_some() {
+            let omit = timespec {
+                tv_sec: 0,
+                tv_nsec: libc::UTIME_OMIT,
+            };
+            let times = [attrs.atime().unwrap_or(omit), attrs.mtime().unwrap_or(omit)];
+            cvt(unsafe {
+                match file {
+                    Some(file) => libc::futimens(file, times.as_ptr()),
+                    None => libc::utimensat(libc::AT_FDCWD, path.as_ptr(), times.as_ptr(), 0),
+                }
+            })?;
+        }
+
+        self.getattr_inner(id)
+    }
+
+    fn fsync(&mut self, _: NodeId, datasync: c_int, fh: u64) -> OperationResult<()> {
+        let fd = self.file(fh)?.as_raw_fd();
+        cvt(unsafe {
+            if datasync != 0 {
+                libc::fdatasync(fd)
+            } else {
+                libc::fsync(fd)
+            }
+        })?;
+        Ok(())
+    }
+
+    fn release(&mut self, _: NodeId, _: &mut ReleaseOptions<'_>, fh: u64) -> OperationResult<()> {
+        self.files.remove(fh);
+        Ok(())
+    }
+
+    fn opendir(&mut self, id: NodeId, _: &mut OpenDirOptions) -> OperationResult<u64> {
+        let path = proc_path(self.fd(id)?);
+        let fd = cvt(unsafe {
+            libc::open(
+                path.as_ptr(),
+                libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC,
+            )
+        })?;
+        let dp = match NonNull::new(unsafe { libc::fdopendir(fd) }) {
+            Some(dp) => dp,
+            None => {
+                let errno = errno();
+                unsafe {
+                    libc::close(fd);
+                }
+                return Err(errno);
+            }
+        };
+        Ok(self.dirs.insert(DirHandle { dp, offset: 0 }))
+    }
+
+    fn readdir(
+        &mut self,
+        _: NodeId,
+        offset: off_t,
+        buf: &mut DirBuf<'_>,
+        fh: u64,
+    ) -> OperationResult<()> {
+        let dir = self.dirs.get_mut(fh).ok_or(libc::EBADF)?;
+        read_dir(dir, offset, buf)
+    }
+
+    fn fsyncdir(&mut self, _: NodeId, datasync: c_int, fh: u64) -> OperationResult<()> {
+        let dir = self.dirs.get(fh).ok_or(libc::EBADF)?;
+        let fd = unsafe { libc::dirfd(dir.dp.as_ptr()) };
+        cvt(unsafe {
+            if datasync != 0 {
+                libc::fdatasync(fd)
+            } else {
+                libc::fsync(fd)
+            }
+        })?;
+        Ok(())
+    }
+
+    fn releasedir(&mut self, _: NodeId, fh: u64) -> OperationResult<()> {
+        self.dirs.remove(fh);
+        Ok(())
+    }
+}
+
+/// Read the entries of the opened directory after `offset`, including
+/// `.` and `..`, until the buffer is filled.
+///
+/// The offsets of the entries are the positions of the directory stream,
+/// so the entries added or removed after `opendir` may or may not be
+/// returned, as with `readdir(3)`.
+fn read_dir(dir: &mut DirHandle, offset: off_t, buf: &mut DirBuf<'_>) -> OperationResult<()> {
+    let dp = dir.dp.as_ptr();
+    if offset != dir.offset {
+        unsafe { libc::seekdir(dp, offset) };
+        dir.offset = offset;
+    }
+
+    loop {
+        set_errno(0);
+        let dent = unsafe { libc::readdir(dp) };
+        if dent.is_null() {
+            return match errno() {
+                0 => Ok(()),
+                errno => Err(errno),
+            };
+        }
+        let dent = unsafe { &*dent };
+
+        let mut attr: stat = unsafe { mem::zeroed() };
+        attr.st_ino = dent.d_ino;
+        attr.st_mode = mode_t::from(dent.d_type) << 12;
+        let name = unsafe { CStr::from_ptr(dent.d_name.as_ptr()) };
+        if buf.add(name, &attr, dent.d_off) {
+            // Rewind, so that the entry is read again by the next call.
+            unsafe { libc::seekdir(dp, dir.offset) };
+            return Ok(());
+        }
+        dir.offset = dent.d_off;
+    }
+}
+
+fn stat_fd(fd: RawFd) -> OperationResult<stat> {
+    let mut attr: stat = unsafe { mem::zeroed() };
+    cvt(unsafe {
+        libc::fstatat(
+            fd,
+            empty().as_ptr(),
+            &mut attr,
+            libc::AT_EMPTY_PATH | libc::AT_SYMLINK_NOFOLLOW,
+        )
+    })?;
+    Ok(attr)
+}
+
+/// Returns the path that reopens the file referred by the `O_PATH` descriptor.
+fn proc_path(fd: RawFd) -> CString {
+    CString::new(format!("/proc/self/fd/{}", fd)).unwrap()
+}
+
+fn empty() -> &'static CStr {
+    CStr::from_bytes_with_nul(b"\0").unwrap()
+}
+
+fn cvt(ret: c_int) -> OperationResult<c_int> {
+    if ret == -1 {
+        Err(errno())
+    } else {
+        Ok(ret)
+    }
+}
+
+fn errno() -> c_int {
+    io::Error::last_os_error()
+        .raw_os_error()
+        .unwrap_or(libc::EIO)
+}
+
+fn set_errno(errno: c_int) {
+    unsafe {
+        *libc::__errno_location() = errno;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::Harness;
+    use std::{env, fs, os::unix::fs::MetadataExt, path::PathBuf, process};
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path = env::temp_dir().join(format!("libfuse-{}-{}", name, process::id()));
+            fs::create_dir(&path).unwrap();
+            TempDir(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn host_inode_numbers_and_eviction() {
+        let dir = TempDir::new("passthrough");
+        fs::write(dir.0.join("file"), b"data").unwrap();
+        let ino = fs::metadata(dir.0.join("file")).unwrap().ino();
+        let name = CString::new("file").unwrap();
+
+        let mut harness = Harness::new(Passthrough::new(&dir.0).unwrap());
+        let entry = harness.lookup(ROOT_NODEID, &name).unwrap();
+        assert_eq!(entry.attr.st_ino, ino);
+        assert_eq!(harness.getattr(entry.nodeid, None).unwrap().st_ino, ino);
+        let entries = harness.read_dir(ROOT_NODEID).unwrap();
+        let dirent = entries.iter().find(|dirent| dirent.name == name).unwrap();
+        assert_eq!(dirent.ino, ino);
+
+        // The inode is dropped once forgotten, and looked up again.
+        harness.lookup(ROOT_NODEID, &name).unwrap();
+        harness.forget_all();
+        assert!(!harness.ops().inodes.contains(entry.nodeid));
+        assert_eq!(harness.ops().ids.len(), 1);
+        let entry = harness.lookup(ROOT_NODEID, &name).unwrap();
+        assert_eq!(entry.attr.st_ino, ino);
+    }
+
+    #[test]
+    fn readdir_is_lazy() {
+        let dir = TempDir::new("passthrough-readdir");
+        for name in &["a", "b", "c"] {
+            fs::write(dir.0.join(name), b"").unwrap();
+        }
+
+        let mut harness = Harness::new(Passthrough::new(&dir.0).unwrap());
+        let fh = harness.opendir(ROOT_NODEID).unwrap();
+
+        // The entries created after `opendir` are seen.
+        fs::write(dir.0.join("d"), b"").unwrap();
+
+        // The entry not fitting in the buffer is returned by the next call.
+        let mut names = vec![];
+        let mut offset = 0;
+        loop {
+            let chunk = harness.readdir(ROOT_NODEID, fh, offset, 40).unwrap();
+            assert!(chunk.len() <= 1);
+            match chunk.last() {
+                Some(entry) => offset = entry.offset,
+                None => break,
+            }
+            names.extend(chunk.into_iter().map(|entry| entry.name));
+        }
+        names.sort();
+        let expected: Vec<_> = [".", "..", "a", "b", "c", "d"]
+            .iter()
+            .map(|name| CString::new(*name).unwrap())
+            .collect();
+        assert_eq!(names, expected);
+
+        // Seeking back to an offset returns the same entries again.
+        let all = harness.readdir(ROOT_NODEID, fh, 0, 4096).unwrap();
+        assert_eq!(all.len(), 6);
+        let rest = harness
+            .readdir(ROOT_NODEID, fh, all[2].offset, 4096)
+            .unwrap();
+        assert_eq!(rest[..], all[3..]);
+
+        harness.releasedir(ROOT_NODEID, fh).unwrap();
+    }
+}
@@ -0,0 +1,991 @@
+//! Path-based filesystem API.
+//!
+//! `PathFilesystem` receives the path of files instead of node IDs, in the
+//! same manner as the high-level API of libfuse (`fuse.h`). `PathAdapter`
+//! maintains the mapping between node IDs and paths, and implements
+//! `Operations` on top of it.
+
+#[cfg(feature = "handoff")]
+use crate::snapshot::{self, Reader};
+use crate::{
+    common::{ConnectionInfo, NodeId, ROOT_NODEID},
+    dir::{DirBuf, OpenDirOptions},
+    file::{
+        Entry, //
+        FlushOptions,
+        OpenOptions,
+        ReadOptions,
+        ReleaseOptions,
+        RenameFlags,
+        SetAttrs,
+        WriteOptions,
+        XAttrFlags,
+        XAttrReply,
+    },
+    ops::{OperationResult, Operations},
+};
+use libc::{c_int, dev_t, mode_t, off_t, stat, statvfs};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    ffi::{CStr, CString, OsStr, OsString},
+    io,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+/// A filesystem whose operations are addressed by paths.
+///
+/// The paths passed to the methods are absolute, with the root of
+/// the filesystem as `/`.
+pub trait PathFilesystem {
+    /// Initialize the filesystem.
+    #[allow(unused_variables)]
+    fn init(&mut self, conn: &mut ConnectionInfo<'_>) {}
+
+    /// Clean up the filesystem.
+    fn destroy(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Serialize the state of the filesystem, for `Session::export`.
+    ///
+    /// The returned bytes are restored by the closure passed to
+    /// `PathAdapter::restore`.
+    #[cfg(feature = "handoff")]
+    fn export(&mut self) -> io::Result<Vec<u8>> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "the filesystem does not support handoff",
+        ))
+    }
+
+    /// Get file attributes.
+    ///
+    /// This method is also used to look up the directory entries, and
+    /// must return `ENOENT` if the file does not exist.
+    #[allow(unused_variables)]
+    fn getattr(&mut self, path: &Path, fh: Option<u64>) -> OperationResult<stat> {
+        Err(libc::ENOSYS)
+    }
+
+    /// Set file attributes.
+    #[allow(unused_variables)]
+    fn setattr(
+        &mut self,
+        path: &Path,
+        attrs: &SetAttrs<'_>,
+        fh: Option<u64>,
+    ) -> OperationResult<stat> {
+        Err(libc::ENOSYS)
+    }
+
+    /// Read a symbolic link.
+    #[allow(unused_variables)]
+    fn readlink(&mut self, path: &Path) -> OperationResult<CString> {
+        Err(libc::ENOSYS)
+    }
+
+    /// Create a file node.
+    #[allow(unused_variables)]
+    fn mknod(&mut self, path: &Path, mode: mode_t, rdev: dev_t) -> OperationResult<()> {
+        Err(libc::ENOSYS)
+    }
+
+    /// Create a directory.
+    #[allow(unused_variables)]
+    fn mkdir(&mut self, path: &Path, mode: mode_t) -> OperationResult<()> {
+        Err(libc::ENOSYS)
+    }
+
+    /// Remove a file.
+    #[allow(unused_variables)]
+    fn unlink(&mut self, path: &Path) -> OperationResult<()> {
+        Err(libc::ENOSYS)
+    }
+
+    /// Remove a directory.
+    #[allow(unused_variables)]
+    fn rmdir(&mut self, path: &Path) -> OperationResult<()> {
+        Err(libc::ENOSYS)
+    }
+
+    /// Create a symbolic link at `path` which points to `link`.
+    #[allow(unused_variables)]
+    fn symlink(&mut self, link: &CStr, path: &Path) -> OperationResult<()> {
+        Err(libc::ENOSYS)
+    }
+
+    /// Rename a file.
+    #[allow(unused_variables)]
+    fn rename(&mut self, from: &Path, to: &Path, flags: RenameFlags) -> OperationResult<()> {
+        Err(libc::ENOSYS)
+    }
+
+    /// Create a hard link.
+    #[allow(unused_variables)]
+    fn link(&mut self, from: &Path, to: &Path) -> OperationResult<()> {
+        Err(libc::ENOSYS)
+    }
+
+    /// Get file system statistics.
+    #[allow(unused_variables)]
+    fn statfs(&mut self, path: &Path) -> OperationResult<statvfs> {
+        Err(libc::ENOSYS)
+    }
+
+    /// Set an extended attribute.
+    #[allow(unused_variables)]
+    fn setxattr(
+        &mut self,
+        path: &Path,
+        name: &CStr,
+        value: &[u8],
+        flags: XAttrFlags,
+    ) -> OperationResult<()> {
+        Err(libc::ENOSYS)
+    }
+
+    /// Get an extended attribute.
+    #[allow(unused_variables)]
+    fn getxattr(
+        &mut self,
+        path: &Path,
+        name: &CStr,
+        size: usize,
+    ) -> OperationResult<XAttrReply<'_>> {
+        Err(libc::ENOSYS)
+    }
+
+    /// List extended attribute names.
+    #[allow(unused_variables)]
+    fn listxattr(&mut self, path: &Path, size: usize) -> OperationResult<XAttrReply<'_>> {
+        Err(libc::ENOSYS)
+    }
+
+    /// Remove an extended attribute.
+    #[allow(unused_variables)]
+    fn removexattr(&mut self, path: &Path, name: &CStr) -> OperationResult<()> {
+        Err(libc::ENOSYS)
+    }
+
+    /// Check file access permissions.
+    #[allow(unused_variables)]
+    fn access(&mut self, path: &Path, mask: c_int) -> OperationResult<()> {
+        Err(libc::ENOSYS)
+    }
+
+    /// Open a file.
+    #[allow(unused_variables)]
+    fn open(&mut self, path: &Path, options: &mut OpenOptions<'_>) -> OperationResult<u64> {
+        Ok(0)
+    }
+
+    /// Create and open a file.
+    #[allow(unused_variables)]
+    fn create(
+        &mut self,
+        path: &Path,
+        mode: mode_t,
+        options: &mut OpenOptions<'_>,
+    ) -> OperationResult<u64> {
+        Err(libc::ENOSYS)
+    }
+
+    /// Read data from an opened file.
+    #[allow(unused_variables)]
+    fn read(
+        &mut self,
+        path: &Path,
+        off: off_t,
+        bufsize: usize,
+        opts: &mut ReadOptions<'_>,
+        fh: u64,
+    ) -> OperationResult<Cow<'_, [u8]>> {
+        Err(libc::ENOSYS)
+    }
+
+    /// Write data to a file.
+    #[allow(unused_variables)]
+    fn write(
+        &mut self,
+        path: &Path,
+        buf: &[u8],
+        off: off_t,
+        opts: &mut WriteOptions<'_>,
+        fh: u64,
+    ) -> OperationResult<usize> {
+        Err(libc::ENOSYS)
+    }
+
+    /// Flush an opened file.
+    #[allow(unused_variables)]
+    fn flush(&mut self, path: &Path, opts: &mut FlushOptions<'_>, fh: u64) -> OperationResult<()> {
+        Ok(())
+    }
+
+    /// Synchronize the file contents.
+    #[allow(unused_variables)]
+    fn fsync(&mut self, path: &Path, datasync: c_int, fh: u64) -> OperationResult<()> {
+        Err(libc::ENOSYS)
+    }
+
+    /// Release an opened file.
+    ///
+    /// The path is `None` if the file has been removed in the meantime.
+    #[allow(unused_variables)]
+    fn release(
+        &mut self,
+        path: Option<&Path>,
+        options: &mut ReleaseOptions<'_>,
+        fh: u64,
+    ) -> OperationResult<()> {
+        Ok(())
+    }
+
+    /// Open a directory.
+    #[allow(unused_variables)]
+    fn opendir(&mut self, path: &Path, options: &mut OpenDirOptions) -> OperationResult<u64> {
+        Ok(0)
+    }
+
+    /// Read a directory.
+    #[allow(unused_variables)]
+    fn readdir(
+        &mut self,
+        path: &Path,
+        offset: off_t,
+        buf: &mut DirBuf<'_>,
+        fh: u64,
+    ) -> OperationResult<()> {
+        Err(libc::ENOSYS)
+    }
+
+    /// Synchronize the directory contents.
+    #[allow(unused_variables)]
+    fn fsyncdir(&mut self, path: &Path, datasync: c_int, fh: u64) -> OperationResult<()> {
+        Err(libc::ENOSYS)
+    }
+
+    /// Release an opened directory.
+    ///
+    /// The path is `None` if the directory has been removed in the meantime.
+    #[allow(unused_variables)]
+    fn releasedir(&mut self, path: Option<&Path>, fh: u64) -> OperationResult<()> {
+        Ok(())
+    }
+}
+
+/// An adapter that implements `Operations` for a `PathFilesystem`.
+///
+/// The adapter assigns a node ID to each path looked up by the kernel,
+/// and keeps it until the kernel forgets it. The node IDs follow the
+/// renames, so that the subsequent operations are passed the new path.
+///
+/// Once a file is removed or replaced by `rename`, the operations on the
+/// remaining node fail with `ENOENT`, as with the `hard_remove` option
+/// of the high-level API of libfuse.
+pub struct PathAdapter<T: PathFilesystem> {
+    fs: T,
+    nodes: NodeTable,
+    attr_timeout: f64,
+    entry_timeout: f64,
+}
+
+impl<T: PathFilesystem> PathAdapter<T> {
+    pub fn new(fs: T) -> Self {
+        Self {
+            fs,
+            nodes: NodeTable::new(),
+            attr_timeout: 0.0,
+            entry_timeout: 0.0,
+        }
+    }
+
+    /// Set the timeout in seconds for which the attributes are cached.
+    pub fn attr_timeout(mut self, timeout: f64) -> Self {
+        self.attr_timeout = timeout;
+        self
+    }
+
+    /// Set the timeout in seconds for which the names are cached.
+    pub fn entry_timeout(mut self, timeout: f64) -> Self {
+        self.entry_timeout = timeout;
+        self
+    }
+
+    /// Returns a reference to the underlying filesystem.
+    pub fn get_ref(&self) -> &T {
+        &self.fs
+    }
+
+    /// Returns a mutable reference to the underlying filesystem.
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.fs
+    }
+
+    /// Consume the adapter, returning the underlying filesystem.
+    pub fn into_inner(self) -> T {
+        self.fs
+    }
+
+    /// Restore the adapter from the state exported by `Operations::export`,
+    /// i.e. `Handoff::state`.
+    ///
+    /// The node IDs known to the kernel are restored, and the underlying
+    /// filesystem is created by `restore_fs` from the state returned by
+    /// `PathFilesystem::export`. The timeouts are not part of the state.
+    #[cfg(feature = "handoff")]
+    pub fn restore(
+        state: &[u8],
+        restore_fs: impl FnOnce(&[u8]) -> io::Result<T>,
+    ) -> io::Result<Self> {
+        let mut reader = Reader::new(state);
+        let nodes = NodeTable::restore(reader.bytes()?)?;
+        let fs = restore_fs(reader.bytes()?)?;
+        reader.finish()?;
+        Ok(Self {
+            fs,
+            nodes,
+            attr_timeout: 0.0,
+            entry_timeout: 0.0,
+        })
+    }
+
+    fn entry(&mut self, parent: NodeId, name: &CStr) -> OperationResult<Entry> {
+        let name = OsStr::from_bytes(name.to_bytes());
+        let path = self.nodes.path(parent)?.join(name);
+        let mut attr = self.fs.getattr(&path, None)?;
+
+        let nodeid = self.nodes.lookup(parent, name);
+        attr.st_ino = nodeid;
+
+        Ok(Entry {
+            nodeid,
+            attr,
+            attr_timeout: self.attr_timeout,
+            entry_timeout: self.entry_timeout,
+            ..Entry::default()
+        })
+    }
+
+    fn child_path(&self, parent: NodeId, name: &CStr) -> OperationResult<PathBuf> {
+        let path = self.nodes.path(parent)?;
+        Ok(path.join(OsStr::from_bytes(name.to_bytes())))
+    }
+}
+
+impl<T: PathFilesystem> Operations for PathAdapter<T> {
+    fn init(&mut self, conn: &mut ConnectionInfo<'_>) {
+        self.fs.init(conn)
+    }
+
+    fn destroy(&mut self) -> io::Result<()> {
+        self.fs.destroy()
+    }
+
+    #[cfg(feature = "handoff")]
+    fn export(&mut self) -> io::Result<Vec<u8>> {
+        let mut state = vec![];
+        snapshot::put_bytes(&mut state, &self.nodes.snapshot());
+        snapshot::put_bytes(&mut state, &self.fs.export()?);
+        Ok(state)
+    }
+
+    fn lookup(&mut self, parent: NodeId, name: &CStr) -> OperationResult<Entry> {
+        self.entry(parent, name)
+    }
+
+    fn forget(&mut self, id: NodeId, nlookup: u64) {
+        self.nodes.forget(id, nlookup);
+    }
+
+    fn readlink(&mut self, id: NodeId) -> OperationResult<CString> {
+        let path = self.nodes.path(id)?;
+        self.fs.readlink(&path)
+    }
+
+    fn mknod(
+        &mut self,
+        parent: NodeId,
+        name: &CStr,
+        mode: mode_t,
+        rdev: dev_t,
+    ) -> OperationResult<Entry> {
+        let path = self.child_path(parent, name)?;
+        self.fs.mknod(&path, mode, rdev)?;
+        self.entry(parent, name)
+    }
+
+    fn mkdir(&mut self, parent: NodeId, name: &CStr, mode: mode_t) -> OperationResult<Entry> {
+        let path = self.child_path(parent, name)?;
+        self.fs.mkdir(&path, mode)?;
+        self.entry(parent, name)
+    }
+
+    fn unlink(&mut self, parent: NodeId, name: &CStr) -> OperationResult<()> {
+        let path = self.child_path(parent, name)?;
+        self.fs.unlink(&path)?;
+        self.nodes
+            .remove(parent, OsStr::from_bytes(name.to_bytes()));
+        Ok(())
+    }
+
+    fn rmdir(&mut self, parent: NodeId, name: &CStr) -> OperationResult<()> {
+        let path = self.child_path(parent, name)?;
+        self.fs.rmdir(&path)?;
+        self.nodes
+            .remove(parent, OsStr::from_bytes(name.to_bytes()));
+        Ok(())
+    }
+
+    fn symlink(&mut self, link: &CStr, parent: NodeId, name: &CStr) -> OperationResult<Entry> {
+        let path = self.child_path(parent, name)?;
+        self.fs.symlink(link, &path)?;
+        self.entry(parent, name)
+    }
+
+    fn rename(
+        &mut self,
+        oldparent: NodeId,
+        oldname: &CStr,
+        newparent: NodeId,
+        newname: &CStr,
+        flags: RenameFlags,
+    ) -> OperationResult<()> {
+        let from = self.child_path(oldparent, oldname)?;
+        let to = self.child_path(newparent, newname)?;
+        self.fs.rename(&from, &to, flags)?;
+        self.nodes.rename(
+            oldparent,
+            OsStr::from_bytes(oldname.to_bytes()),
+            newparent,
+            OsStr::from_bytes(newname.to_bytes()),
+            flags.contains(RenameFlags::EXCHANGE),
+        );
+        Ok(())
+    }
+
+    fn link(&mut self, id: NodeId, newparent: NodeId, newname: &CStr) -> OperationResult<Entry> {
+        let from = self.nodes.path(id)?;
+        let to = self.child_path(newparent, newname)?;
+        self.fs.link(&from, &to)?;
+        self.entry(newparent, newname)
+    }
+
+    fn statfs(&mut self, id: NodeId) -> OperationResult<statvfs> {
+        let path = self.nodes.path(id)?;
+        self.fs.statfs(&path)
+    }
+
+    fn setxattr(
+        &mut self,
+        id: NodeId,
+        name: &CStr,
+        value: &[u8],
+        flags: XAttrFlags,
+    ) -> OperationResult<()> {
+        let path = self.nodes.path(id)?;
+        self.fs.setxattr(&path, name, value, flags)
+    }
+
+    fn getxattr(
+        &mut self,
+        id: NodeId,
+        name: &CStr,
+        size: usize,
+    ) -> OperationResult<XAttrReply<'_>> {
+        let path = self.nodes.path(id)?;
+        self.fs.getxattr(&path, name, size)
+    }
+
+    fn listxattr(&mut self, id: NodeId, size: usize) -> OperationResult<XAttrReply<'_>> {
+        let path = self.nodes.path(id)?;
+        self.fs.listxattr(&path, size)
+    }
+
+    fn removexattr(&mut self, id: NodeId, name: &CStr) -> OperationResult<()> {
+        let path = self.nodes.path(id)?;
+        self.fs.removexattr(&path, name)
+    }
+
+    fn access(&mut self, id: NodeId, mask: c_int) -> OperationResult<()> {
+        let path = self.nodes.path(id)?;
+        self.fs.access(&path, mask)
+    }
+
+    fn open(&mut self, id: NodeId, options: &mut OpenOptions<'_>) -> OperationResult<u64> {
+        let path = self.nodes.path(id)?;
+        self.fs.open(&path, options)
+    }
+
+    fn create(
+        &mut self,
+        parent: NodeId,
+        name: &CStr,
+        mode: mode_t,
+        options: &mut OpenOptions<'_>,
+    ) -> OperationResult<(Entry, u64)> {
+        let path = self.child_path(parent, name)?;
+        let fh = self.fs.create(&path, mode, options)?;
+        match self.entry(parent, name) {
+            Ok(entry) => Ok((entry, fh)),
+            Err(errno) => {
+                let _ = self
+                    .fs
+                    .release(Some(&path), &mut ReleaseOptions(&mut *options.0), fh);
+                Err(errno)
+            }
+        }
+    }
+
+    fn read(
+        &mut self,
+        id: NodeId,
+        off: off_t,
+        bufsize: usize,
+        opts: &mut ReadOptions<'_>,
+        fh: u64,
+    ) -> OperationResult<Cow<'_, [u8]>> {
+        let path = self.nodes.path(id)?;
+        self.fs.read(&path, off, bufsize, opts, fh)
+    }
+
+    fn write(
+        &mut self,
+        id: NodeId,
+        buf: &[u8],
+        off: off_t,
+        opts: &mut WriteOptions<'_>,
+        fh: u64,
+    ) -> OperationResult<usize> {
+        let path = self.nodes.path(id)?;
+        self.fs.write(&path, buf, off, opts, fh)
+    }
+
+    fn flush(&mut self, id: NodeId, opts: &mut FlushOptions<'_>, fh: u64) -> OperationResult<()> {
+        let path = self.nodes.path(id)?;
+        self.fs.flush(&path, opts, fh)
+    }
+
+    fn getattr(&mut self, id: NodeId, fh: Option<u64>) -> OperationResult<(stat, f64)> {
+        let path = self.nodes.path(id)?;
+        let mut attr = self.fs.getattr(&path, fh)?;
+        attr.st_ino = id;
+        Ok((attr, self.attr_timeout))
+    }
+
+    fn setattr(
+        &mut self,
+        id: NodeId,
+        attrs: &SetAttrs<'_>,
+        fh: Option<u64>,
+    ) -> OperationResult<(stat, f64)> {
+        let path = self.nodes.path(id)?;
+        let mut attr = self.fs.setattr(&path, attrs, fh)?;
+        attr.st_ino = id;
+        Ok((attr, self.attr_timeout))
+    }
+
+    fn fsync(&mut self, id: NodeId, datasync: c_int, fh: u64) -> OperationResult<()> {
+        let path = self.nodes.path(id)?;
+        self.fs.fsync(&path, datasync, fh)
+    }
+
+    fn release(
+        &mut self,
+        id: NodeId,
+        options: &mut ReleaseOptions<'_>,
+        fh: u64,
+    ) -> OperationResult<()> {
+        let path = self.nodes.path(id).ok();
+        self.fs.release(path.as_deref(), options, fh)
+    }
+
+    fn opendir(&mut self, id: NodeId, options: &mut OpenDirOptions) -> OperationResult<u64> {
+        let path = self.nodes.path(id)?;
+        self.fs.opendir(&path, options)
+    }
+
+    fn readdir(
+        &mut self,
+        id: NodeId,
+        offset: off_t,
+        buf: &mut DirBuf<'_>,
+        fh: u64,
+    ) -> OperationResult<()> {
+        let path = self.nodes.path(id)?;
+        self.fs.readdir(&path, offset, buf, fh)
+    }
+
+    fn fsyncdir(&mut self, id: NodeId, datasync: c_int, fh: u64) -> OperationResult<()> {
+        let path = self.nodes.path(id)?;
+        self.fs.fsyncdir(&path, datasync, fh)
+    }
+
+    fn releasedir(&mut self, id: NodeId, fh: u64) -> OperationResult<()> {
+        let path = self.nodes.path(id).ok();
+        self.fs.releasedir(path.as_deref(), fh)
+    }
+}
+
+struct Node {
+    parent: NodeId,
+    name: OsString,
+    nlookup: u64,
+    removed: bool,
+}
+
+/// The mapping between node IDs and the names in their parent directories.
+struct NodeTable {
+    nodes: HashMap<NodeId, Node>,
+    names: HashMap<(NodeId, OsString), NodeId>,
+    next_id: NodeId,
+}
+
+impl NodeTable {
+    fn new() -> Self {
+        Self {
+            nodes: HashMap::new(),
+            names: HashMap::new(),
+            next_id: ROOT_NODEID + 1,
+        }
+    }
+
+    fn path(&self, id: NodeId) -> OperationResult<PathBuf> {
+        let mut names = vec![];
+        let mut id = id;
+        while id != ROOT_NODEID {
+            let node = self.nodes.get(&id).ok_or(libc::ENOENT)?;
+            if node.removed {
+                return Err(libc::ENOENT);
+            }
+            names.push(&*node.name);
+            id = node.parent;
+        }
+
+        let mut path = PathBuf::from("/");
+        path.extend(names.into_iter().rev());
+        Ok(path)
+    }
+
+    /// Look up the node of the specified name, increasing its lookup count.
+    fn lookup(&mut self, parent: NodeId, name: &OsStr) -> NodeId {
+        let key = (parent, name.to_os_string());
+        if let Some(&id) = self.names.get(&key) {
+            if let Some(node) = self.nodes.get_mut(&id) {
+                node.nlookup += 1;
+                return id;
+            }
+        }
+
+        let id = self.next_id;
+        self.next_id += 1;
+        self.nodes.insert(
+            id,
+            Node {
+                parent,
+                name: name.to_os_string(),
+                nlookup: 1,
+                removed: false,
+            },
+        );
+        self.names.insert(key, id);
+        id
+    }
+
+    fn forget(&mut self, id: NodeId, nlookup: u64) {
+        if id == ROOT_NODEID {
+            return;
+        }
+
+        let remove = match self.nodes.get_mut(&id) {
+            Some(node) => {
+                node.nlookup = node.nlookup.saturating_sub(nlookup);
+                node.nlookup == 0
+            }
+            None => false,
+        };
+
+        if remove {
+            let node = self.nodes.remove(&id).unwrap();
+            let key = (node.parent, node.name);
+            if self.names.get(&key) == Some(&id) {
+                self.names.remove(&key);
+            }
+        }
+    }
+
+    /// Detach the node of the specified name from the directory tree.
+    fn remove(&mut self, parent: NodeId, name: &OsStr) {
+        if let Some(id) = self.names.remove(&(parent, name.to_os_string())) {
+            if let Some(node) = self.nodes.get_mut(&id) {
+                node.removed = true;
+            }
+        }
+    }
+
+    fn rename(
+        &mut self,
+        oldparent: NodeId,
+        oldname: &OsStr,
+        newparent: NodeId,
+        newname: &OsStr,
+        exchange: bool,
+    ) {
+        let old = self.names.remove(&(oldparent, oldname.to_os_string()));
+        let new = self.names.remove(&(newparent, newname.to_os_string()));
+
+        if let Some(id) = new {
+            if exchange {
+                self.attach(id, oldparent, oldname);
+            } else if let Some(node) = self.nodes.get_mut(&id) {
+                node.removed = true;
+            }
+        }
+
+        if let Some(id) = old {
+            self.attach(id, newparent, newname);
+        }
+    }
+
+    fn attach(&mut self, id: NodeId, parent: NodeId, name: &OsStr) {
+        if let Some(node) = self.nodes.get_mut(&id) {
+            node.parent = parent;
+            node.name = name.to_os_string();
+            self.names.insert((parent, name.to_os_string()), id);
+        }
+    }
+
+    #[cfg(feature = "handoff")]
+    fn snapshot(&self) -> Vec<u8> {
+        let mut ids: Vec<_> = self.nodes.keys().copied().collect();
+        ids.sort();
+
+        let mut buf = vec![];
+        snapshot::put_u64(&mut buf, self.next_id);
+        snapshot::put_u64(&mut buf, ids.len() as u64);
+        for id in ids {
+            let node = &self.nodes[&id];
+            snapshot::put_u64(&mut buf, id);
+            snapshot::put_u64(&mut buf, node.parent);
+            snapshot::put_bytes(&mut buf, node.name.as_bytes());
+            snapshot::put_u64(&mut buf, node.nlookup);
+            snapshot::put_u64(&mut buf, node.removed as u64);
+        }
+        buf
+    }
+
+    #[cfg(feature = "handoff")]
+    fn restore(snapshot: &[u8]) -> io::Result<Self> {
+        let mut reader = Reader::new(snapshot);
+        let mut table = Self::new();
+        table.next_id = reader.u64()?;
+        for _ in 0..reader.u64()? {
+            let id = reader.u64()?;
+            let node = Node {
+                parent: reader.u64()?,
+                name: OsStr::from_bytes(reader.bytes()?).to_os_string(),
+                nlookup: reader.u64()?,
+                removed: reader.u64()? != 0,
+            };
+            if id == ROOT_NODEID || id >= table.next_id {
+                return Err(snapshot::malformed());
+            }
+            if !node.removed {
+                table.names.insert((node.parent, node.name.clone()), id);
+            }
+            table.nodes.insert(id, node);
+        }
+        reader.finish()?;
+        Ok(table)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn lookup(nodes: &mut NodeTable, parent: NodeId, name: &str) -> NodeId {
+        nodes.lookup(parent, OsStr::new(name))
+    }
+
+    #[test]
+    fn lookup_and_forget() {
+        let mut nodes = NodeTable::new();
+        let a = lookup(&mut nodes, ROOT_NODEID, "a");
+        assert_eq!(lookup(&mut nodes, ROOT_NODEID, "a"), a);
+        assert_eq!(nodes.path(a), Ok(PathBuf::from("/a")));
+        assert_eq!(nodes.path(ROOT_NODEID), Ok(PathBuf::from("/")));
+
+        let b = lookup(&mut nodes, a, "b");
+        assert_eq!(nodes.path(b), Ok(PathBuf::from("/a/b")));
+
+        nodes.forget(a, 1);
+        assert_eq!(nodes.path(a), Ok(PathBuf::from("/a")));
+        nodes.forget(a, 1);
+        assert_eq!(nodes.path(a), Err(libc::ENOENT));
+        assert_ne!(lookup(&mut nodes, ROOT_NODEID, "a"), a);
+
+        // The root is never forgotten.
+        nodes.forget(ROOT_NODEID, 1);
+        assert_eq!(nodes.path(ROOT_NODEID), Ok(PathBuf::from("/")));
+    }
+
+    #[test]
+    fn rename_follows_children() {
+        let mut nodes = NodeTable::new();
+        let dir = lookup(&mut nodes, ROOT_NODEID, "dir");
+        let file = lookup(&mut nodes, dir, "file");
+
+        nodes.rename(
+            ROOT_NODEID,
+            OsStr::new("dir"),
+            ROOT_NODEID,
+            OsStr::new("new"),
+            false,
+        );
+        assert_eq!(nodes.path(dir), Ok(PathBuf::from("/new")));
+        assert_eq!(nodes.path(file), Ok(PathBuf::from("/new/file")));
+        assert_eq!(lookup(&mut nodes, ROOT_NODEID, "new"), dir);
+    }
+
+    #[test]
+    fn rename_over_existing_node() {
+        let mut nodes = NodeTable::new();
+        let a = lookup(&mut nodes, ROOT_NODEID, "a");
+        let b = lookup(&mut nodes, ROOT_NODEID, "b");
+
+        nodes.rename(
+            ROOT_NODEID,
+            OsStr::new("a"),
+            ROOT_NODEID,
+            OsStr::new("b"),
+            false,
+        );
+        assert_eq!(nodes.path(a), Ok(PathBuf::from("/b")));
+        assert_eq!(nodes.path(b), Err(libc::ENOENT));
+        assert_eq!(lookup(&mut nodes, ROOT_NODEID, "b"), a);
+
+        // Forgetting the replaced node does not detach the new one.
+        nodes.forget(b, 1);
+        assert_eq!(lookup(&mut nodes, ROOT_NODEID, "b"), a);
+    }
+
+    #[test]
+    fn rename_exchange() {
+        let mut nodes = NodeTable::new();
+        let a = lookup(&mut nodes, ROOT_NODEID, "a");
+        let dir = lookup(&mut nodes, ROOT_NODEID, "dir");
+        let b = lookup(&mut nodes, dir, "b");
+
+        nodes.rename(ROOT_NODEID, OsStr::new("a"), dir, OsStr::new("b"), true);
+        assert_eq!(nodes.path(a), Ok(PathBuf::from("/dir/b")));
+        assert_eq!(nodes.path(b), Ok(PathBuf::from("/a")));
+        assert_eq!(lookup(&mut nodes, ROOT_NODEID, "a"), b);
+        assert_eq!(lookup(&mut nodes, dir, "b"), a);
+    }
+
+    #[test]
+    fn unlink_while_looked_up() {
+        let mut nodes = NodeTable::new();
+        let a = lookup(&mut nodes, ROOT_NODEID, "a");
+
+        nodes.remove(ROOT_NODEID, OsStr::new("a"));
+        assert_eq!(nodes.path(a), Err(libc::ENOENT));
+
+        // A new file of the same name gets a new node.
+        let new = lookup(&mut nodes, ROOT_NODEID, "a");
+        assert_ne!(new, a);
+        nodes.forget(a, 1);
+        assert_eq!(nodes.path(new), Ok(PathBuf::from("/a")));
+        assert_eq!(lookup(&mut nodes, ROOT_NODEID, "a"), new);
+    }
+
+    #[cfg(feature = "handoff")]
+    #[test]
+    fn snapshot_and_restore() {
+        let mut nodes = NodeTable::new();
+        let dir = lookup(&mut nodes, ROOT_NODEID, "dir");
+        let file = lookup(&mut nodes, dir, "file");
+        let removed = lookup(&mut nodes, ROOT_NODEID, "removed");
+        nodes.remove(ROOT_NODEID, OsStr::new("removed"));
+
+        let mut restored = NodeTable::restore(&nodes.snapshot()).unwrap();
+        assert_eq!(restored.path(file), Ok(PathBuf::from("/dir/file")));
+        assert_eq!(restored.path(removed), Err(libc::ENOENT));
+        assert_eq!(lookup(&mut restored, dir, "file"), file);
+
+        // The lookup counts and the next node ID are restored as well.
+        restored.forget(file, 1);
+        assert_eq!(restored.path(file), Ok(PathBuf::from("/dir/file")));
+        restored.forget(file, 1);
+        assert_eq!(restored.path(file), Err(libc::ENOENT));
+        assert!(lookup(&mut restored, ROOT_NODEID, "new") > removed);
+
+        assert!(NodeTable::restore(b"").is_err());
+    }
+
+    /// A flat filesystem whose files are tagged by `st_size`.
+    #[derive(Default)]
+    struct Files(BTreeMap<PathBuf, i64>);
+
+    impl PathFilesystem for Files {
+        fn getattr(&mut self, path: &Path, _: Option<u64>) -> OperationResult<stat> {
+            let size = *self.0.get(path).ok_or(libc::ENOENT)?;
+            let mut attr: stat = unsafe { std::mem::zeroed() };
+            attr.st_mode = libc::S_IFREG | 0o644;
+            attr.st_size = size;
+            Ok(attr)
+        }
+
+        fn unlink(&mut self, path: &Path) -> OperationResult<()> {
+            self.0.remove(path).map(drop).ok_or(libc::ENOENT)
+        }
+
+        fn rename(&mut self, from: &Path, to: &Path, flags: RenameFlags) -> OperationResult<()> {
+            let size = self.0.remove(from).ok_or(libc::ENOENT)?;
+            if let Some(other) = self.0.insert(to.into(), size) {
+                if flags.contains(RenameFlags::EXCHANGE) {
+                    self.0.insert(from.into(), other);
+                }
+            }
+            Ok(())
+        }
+    }
+
+    fn cstr(name: &str) -> CString {
+        CString::new(name).unwrap()
+    }
+
+    #[test]
+    fn adapter_passes_paths() {
+        let mut files = Files::default();
+        files.0.insert("/a".into(), 1);
+        files.0.insert("/b".into(), 2);
+        let mut fs = PathAdapter::new(files);
+
+        let a = fs.lookup(ROOT_NODEID, &cstr("a")).unwrap();
+        let b = fs.lookup(ROOT_NODEID, &cstr("b")).unwrap();
+        assert_eq!(a.attr.st_ino, a.nodeid);
+        assert_eq!(fs.getattr(b.nodeid, None).unwrap().0.st_size, 2);
+        assert_eq!(fs.lookup(ROOT_NODEID, &cstr("c")).err(), Some(libc::ENOENT));
+
+        fs.rename(
+            ROOT_NODEID,
+            &cstr("a"),
+            ROOT_NODEID,
+            &cstr("b"),
+            RenameFlags::EXCHANGE,
+        )
+        .unwrap();
+        assert_eq!(fs.getattr(a.nodeid, None).unwrap().0.st_size, 1);
+        assert_eq!(fs.getattr(b.nodeid, None).unwrap().0.st_size, 2);
+        assert_eq!(fs.get_ref().0.get(Path::new("/b")), Some(&1));
+
+        fs.unlink(ROOT_NODEID, &cstr("b")).unwrap();
+        assert_eq!(fs.getattr(a.nodeid, None).err(), Some(libc::ENOENT));
+        assert_eq!(fs.getattr(b.nodeid, None).unwrap().0.st_size, 2);
+    }
+}
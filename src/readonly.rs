@@ -0,0 +1,246 @@
+//! A layer that makes a filesystem read-only.
+
+use crate::{
+    common::NodeId,
+    file::{Entry, OpenOptions, RenameFlags, SetAttrs, WriteOptions, XAttrFlags},
+    layer::Layer,
+    ops::{OperationResult, Operations},
+};
+use libc::{c_int, dev_t, mode_t, off_t, stat, statvfs};
+use std::ffi::CStr;
+
+/// A layer that rejects all operations modifying the filesystem with `EROFS`.
+///
+/// The other operations are forwarded to the inner filesystem, and
+/// `ST_RDONLY` is set to the result of `statfs`. Opening a file is
+/// rejected if it requests the write access or the truncation.
+#[derive(Debug)]
+pub struct ReadOnly<T: Operations> {
+    inner: T,
+}
+
+impl<T: Operations> ReadOnly<T> {
+    /// Create a layer that makes `inner` read-only.
+    pub fn new(inner: T) -> Self {
+        Self { inner }
+    }
+
+    /// Consume the layer, returning the inner filesystem.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: Operations> Layer for ReadOnly<T> {
+    type Inner = T;
+
+    fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    fn inner_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    fn mknod(&mut self, _: NodeId, _: &CStr, _: mode_t, _: dev_t) -> OperationResult<Entry> {
+        Err(libc::EROFS)
+    }
+
+    fn mkdir(&mut self, _: NodeId, _: &CStr, _: mode_t) -> OperationResult<Entry> {
+        Err(libc::EROFS)
+    }
+
+    fn unlink(&mut self, _: NodeId, _: &CStr) -> OperationResult<()> {
+        Err(libc::EROFS)
+    }
+
+    fn rmdir(&mut self, _: NodeId, _: &CStr) -> OperationResult<()> {
+        Err(libc::EROFS)
+    }
+
+    fn symlink(&mut self, _: &CStr, _: NodeId, _: &CStr) -> OperationResult<Entry> {
+        Err(libc::EROFS)
+    }
+
+    fn rename(
+        &mut self,
+        _: NodeId,
+        _: &CStr,
+        _: NodeId,
+        _: &CStr,
+        _: RenameFlags,
+    ) -> OperationResult<()> {
+        Err(libc::EROFS)
+    }
+
+    fn link(&mut self, _: NodeId, _: NodeId, _: &CStr) -> OperationResult<Entry> {
+        Err(libc::EROFS)
+    }
+
+    fn statfs(&mut self, id: NodeId) -> OperationResult<statvfs> {
+        let mut st = self.inner.statfs(id)?;
+        st.f_flag |= libc::ST_RDONLY;
+        Ok(st)
+    }
+
+    fn setxattr(&mut self, _: NodeId, _: &CStr, _: &[u8], _: XAttrFlags) -> OperationResult<()> {
+        Err(libc::EROFS)
+    }
+
+    fn removexattr(&mut self, _: NodeId, _: &CStr) -> OperationResult<()> {
+        Err(libc::EROFS)
+    }
+
+    fn access(&mut self, id: NodeId, mask: c_int) -> OperationResult<()> {
+        if (mask & libc::W_OK) != 0 {
+            return Err(libc::EROFS);
+        }
+        self.inner.access(id, mask)
+    }
+
+    fn open(&mut self, id: NodeId, options: &mut OpenOptions<'_>) -> OperationResult<u64> {
+        let flags = options.flags();
+        if (flags & libc::O_ACCMODE) != libc::O_RDONLY || (flags & libc::O_TRUNC) != 0 {
+            return Err(libc::EROFS);
+        }
+        self.inner.open(id, options)
+    }
+
+    fn create(
+        &mut self,
+        _: NodeId,
+        _: &CStr,
+        _: mode_t,
+        _: &mut OpenOptions<'_>,
+    ) -> OperationResult<(Entry, u64)> {
+        Err(libc::EROFS)
+    }
+
+    fn write(
+        &mut self,
+        _: NodeId,
+        _: &[u8],
+        _: off_t,
+        _: &mut WriteOptions<'_>,
+        _: u64,
+    ) -> OperationResult<usize> {
+        Err(libc::EROFS)
+    }
+
+    fn setattr(
+        &mut self,
+        _: NodeId,
+        _: &SetAttrs<'_>,
+        _: Option<u64>,
+    ) -> OperationResult<(stat, f64)> {
+        Err(libc::EROFS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        common::ROOT_NODEID,
+        testing::{Harness, SetAttr},
+    };
+    use std::{ffi::CString, mem};
+
+    /// A filesystem accepting every operation, which counts the opens.
+    #[derive(Default)]
+    struct Writable {
+        opened: usize,
+    }
+
+    impl Operations for Writable {
+        fn open(&mut self, _: NodeId, _: &mut OpenOptions<'_>) -> OperationResult<u64> {
+            self.opened += 1;
+            Ok(1)
+        }
+
+        fn create(
+            &mut self,
+            _: NodeId,
+            _: &CStr,
+            _: mode_t,
+            _: &mut OpenOptions<'_>,
+        ) -> OperationResult<(Entry, u64)> {
+            Ok((Entry::default(), 1))
+        }
+
+        fn write(
+            &mut self,
+            _: NodeId,
+            data: &[u8],
+            _: off_t,
+            _: &mut WriteOptions<'_>,
+            _: u64,
+        ) -> OperationResult<usize> {
+            Ok(data.len())
+        }
+
+        fn setattr(
+            &mut self,
+            _: NodeId,
+            _: &SetAttrs<'_>,
+            _: Option<u64>,
+        ) -> OperationResult<(stat, f64)> {
+            Ok((unsafe { mem::zeroed() }, 0.0))
+        }
+
+        fn statfs(&mut self, _: NodeId) -> OperationResult<statvfs> {
+            let mut st: statvfs = unsafe { mem::zeroed() };
+            st.f_flag = libc::ST_NOSUID;
+            Ok(st)
+        }
+    }
+
+    fn harness() -> Harness<ReadOnly<Writable>> {
+        Harness::new(ReadOnly::new(Writable::default()))
+    }
+
+    #[test]
+    fn modifications_are_rejected() {
+        let mut harness = harness();
+        let name = CString::new("file").unwrap();
+        assert_eq!(
+            harness.write(ROOT_NODEID, 1, 0, b"data").err(),
+            Some(libc::EROFS)
+        );
+        assert_eq!(
+            harness
+                .setattr(ROOT_NODEID, &SetAttr::new().mode(0o600), None)
+                .err(),
+            Some(libc::EROFS)
+        );
+        assert_eq!(
+            harness
+                .create(ROOT_NODEID, &name, 0o644, libc::O_WRONLY)
+                .err(),
+            Some(libc::EROFS)
+        );
+    }
+
+    #[test]
+    fn open_for_writing_is_rejected() {
+        let mut harness = harness();
+        for &flags in &[libc::O_WRONLY, libc::O_RDWR, libc::O_RDONLY | libc::O_TRUNC] {
+            assert_eq!(harness.open(ROOT_NODEID, flags).err(), Some(libc::EROFS));
+        }
+        assert_eq!(harness.ops().inner().opened, 0);
+    }
+
+    #[test]
+    fn open_for_reading_is_forwarded() {
+        let mut harness = harness();
+        assert_eq!(harness.open(ROOT_NODEID, libc::O_RDONLY), Ok(1));
+        assert_eq!(harness.ops().inner().opened, 1);
+    }
+
+    #[test]
+    fn statfs_is_read_only() {
+        let mut harness = harness();
+        let st = harness.statfs(ROOT_NODEID).unwrap();
+        assert_eq!(st.f_flag, libc::ST_NOSUID | libc::ST_RDONLY);
+    }
+}
@@ -1,7 +1,21 @@
-use super::ops::{assign_ops, Context, Operations};
-use libc::{c_char, c_int};
+use super::{
+    logging,
+    ops::{assign_ops, Context, Operations, SharedContext},
+    stats::Stats,
+};
+use libc::{c_char, c_int, c_uint, c_void};
 use libfuse_sys::{
-    fuse_remove_signal_handlers, //
+    fuse_args, //
+    fuse_cmdline_help,
+    fuse_daemonize,
+    fuse_lowlevel_help,
+    fuse_lowlevel_version,
+    fuse_opt,
+    fuse_opt_free_args,
+    fuse_opt_key::FUSE_OPT_KEY_OPT,
+    fuse_opt_parse,
+    fuse_pkgversion,
+    fuse_remove_signal_handlers,
     fuse_session,
     fuse_session_destroy,
     fuse_session_fd,
@@ -9,22 +23,50 @@ use libfuse_sys::{
     fuse_session_mount,
     fuse_session_unmount,
     fuse_set_signal_handlers,
-    helpers::{fuse_ll_ops_new, fuse_session_new_wrapped},
+    helpers::{
+        fuse_ll_ops_new, //
+        fuse_parse_cmdline_wrapped,
+        fuse_session_loop_mt_wrapped,
+        fuse_session_new_wrapped,
+    },
 };
+
+#[cfg(feature = "handoff")]
+use crate::handoff;
+#[cfg(feature = "handoff")]
+pub use crate::handoff::Handoff;
+#[cfg(feature = "custom-io")]
+use libfuse_sys::helpers::fuse_session_custom_io_fd;
+#[cfg(feature = "handoff")]
+use libfuse_sys::helpers::{fuse_session_custom_io_with, fuse_session_process_mem};
+#[cfg(feature = "handoff")]
+use std::os::unix::io::FromRawFd;
 use std::{
-    ffi::CString,
-    io,
-    marker::PhantomData,
-    os::unix::ffi::OsStrExt,
-    os::unix::io::RawFd,
+    env, error,
+    ffi::{CStr, CString, NulError, OsStr, OsString},
+    fmt, fs, io, mem,
+    os::unix::ffi::{OsStrExt, OsStringExt},
+    os::unix::io::{IntoRawFd, OwnedFd, RawFd},
     path::{Path, PathBuf},
-    ptr::NonNull,
+    ptr::{self, NonNull},
+    sync::Arc,
 };
 
+/// The default of `Builder::max_idle_threads`, the same as libfuse.
+const DEFAULT_MAX_IDLE_THREADS: usize = 10;
+
 #[derive(Debug)]
 pub struct Builder {
     fsname: String,
     options: Vec<String>,
+    mount_options: MountOptions,
+    clone_fd: bool,
+    max_idle_threads: Option<usize>,
+    #[cfg(feature = "handoff")]
+    handoff: bool,
+    stats: bool,
+    #[cfg(feature = "custom-io")]
+    custom_io: Option<OwnedFd>,
 }
 
 impl Builder {
@@ -32,9 +74,133 @@ impl Builder {
         Self {
             fsname: fsname.into(),
             options: vec![],
+            mount_options: MountOptions::default(),
+            clone_fd: false,
+            max_idle_threads: None,
+            #[cfg(feature = "handoff")]
+            handoff: false,
+            stats: false,
+            #[cfg(feature = "custom-io")]
+            custom_io: None,
         }
     }
 
+    /// Create a `Builder` from the command line arguments.
+    ///
+    /// The first argument is used as the program name, and the generic
+    /// FUSE options (`-f`, `-d`, `-s`, `-o opt[,opt...]`, `-h`/`--help`
+    /// and `-V`/`--version`) are recognized. The options given by `-o`
+    /// are passed to `O` first, and those not consumed are passed to
+    /// libfuse when building the session.
+    pub fn from_args<O: CustomOptions>(
+        args: impl IntoIterator<Item = impl Into<OsString>>,
+    ) -> Result<CommandLine<O>, SessionError> {
+        let args = args
+            .into_iter()
+            .map(|arg| CString::new(arg.into().into_vec()))
+            .collect::<Result<Vec<_>, _>>()?;
+        if args.is_empty() {
+            return Err(SessionError::InvalidOption(
+                "missing the program name".into(),
+            ));
+        }
+
+        let mut c_args: Vec<*mut c_char> = args.iter().map(|arg| arg.as_ptr() as *mut _).collect();
+        let mut fuse_args = fuse_args {
+            argc: c_args.len() as c_int,
+            argv: c_args.as_mut_ptr(),
+            allocated: 0,
+        };
+
+        // Extract the filesystem specific options at first.
+        let mut parser = OptionParser {
+            options: O::default(),
+            error: None,
+        };
+        let opts_end = fuse_opt {
+            templ: ptr::null(),
+            offset: 0,
+            value: 0,
+        };
+        let (res, messages) = logging::capture(|| unsafe {
+            fuse_opt_parse(
+                &mut fuse_args,
+                &mut parser as *mut OptionParser<O> as *mut c_void,
+                &opts_end,
+                Some(parse_custom_option::<O>),
+            )
+        });
+        if res != 0 {
+            unsafe { fuse_opt_free_args(&mut fuse_args) };
+            return Err(match parser.error {
+                Some(msg) => SessionError::InvalidOption(msg),
+                None => SessionError::from_new_messages(messages),
+            });
+        }
+
+        let (mut foreground, mut singlethread, mut debug) = (0, 0, 0);
+        let (mut show_help, mut show_version, mut clone_fd) = (0, 0, 0);
+        let mut max_idle_threads: c_uint = 0;
+        let mut mountpoint: *mut c_char = ptr::null_mut();
+        let (res, messages) = logging::capture(|| unsafe {
+            fuse_parse_cmdline_wrapped(
+                &mut fuse_args,
+                &mut foreground,
+                &mut singlethread,
+                &mut debug,
+                &mut show_help,
+                &mut show_version,
+                &mut clone_fd,
+                &mut max_idle_threads,
+                &mut mountpoint,
+            )
+        });
+
+        let remains: Vec<String> = (0..fuse_args.argc as usize)
+            .map(|i| unsafe {
+                CStr::from_ptr(*fuse_args.argv.add(i))
+                    .to_string_lossy()
+                    .into_owned()
+            })
+            .collect();
+        let mountpoint = if !mountpoint.is_null() {
+            unsafe {
+                let path = PathBuf::from(OsStr::from_bytes(CStr::from_ptr(mountpoint).to_bytes()));
+                libc::free(mountpoint as *mut _);
+                Some(path)
+            }
+        } else {
+            None
+        };
+        unsafe { fuse_opt_free_args(&mut fuse_args) };
+
+        if res != 0 {
+            return Err(SessionError::from_new_messages(messages));
+        }
+
+        let mut builder = Builder::new(remains[0].clone()).options(remains.into_iter().skip(1));
+        builder.clone_fd = clone_fd != 0;
+        builder.max_idle_threads = match max_idle_threads {
+            0 => None,
+            n => Some(n as usize),
+        };
+
+        Ok(CommandLine {
+            builder,
+            mountpoint,
+            foreground: foreground != 0,
+            singlethread: singlethread != 0,
+            debug: debug != 0,
+            show_help: show_help != 0,
+            show_version: show_version != 0,
+            options: parser.options,
+        })
+    }
+
+    /// Enable the debug output of libfuse.
+    ///
+    /// The trace of requests is forwarded to the `log` crate at the debug
+    /// level, with the target `libfuse`.
     pub fn debug(self, enabled: bool) -> Self {
         if enabled {
             self.options(vec!["-o", "debug"])
@@ -48,9 +214,126 @@ impl Builder {
         self
     }
 
+    /// Allow all users to access the filesystem.
+    ///
+    /// This option conflicts with `allow_root`.
+    pub fn allow_other(mut self, enabled: bool) -> Self {
+        self.mount_options.allow_other = enabled;
+        self
+    }
+
+    /// Allow the root user to access the filesystem, in addition to the
+    /// user who mounted it.
+    ///
+    /// This option conflicts with `allow_other`.
+    pub fn allow_root(mut self, enabled: bool) -> Self {
+        self.mount_options.allow_root = enabled;
+        self
+    }
+
+    /// Let the kernel perform the permission checks based on the file mode.
+    pub fn default_permissions(mut self, enabled: bool) -> Self {
+        self.mount_options.default_permissions = enabled;
+        self
+    }
+
+    /// Mount the filesystem as read-only.
+    pub fn ro(mut self, enabled: bool) -> Self {
+        self.mount_options.ro = enabled;
+        self
+    }
+
+    /// Unmount the filesystem automatically when the process exits.
+    ///
+    /// The sessions mounted with this option cannot be handed off by
+    /// `Session::export`.
+    pub fn auto_unmount(mut self, enabled: bool) -> Self {
+        self.mount_options.auto_unmount = enabled;
+        self
+    }
+
+    /// Set the name of the filesystem shown in the mount table.
+    pub fn fsname(mut self, fsname: impl Into<String>) -> Self {
+        self.mount_options.fsname = Some(fsname.into());
+        self
+    }
+
+    /// Set the subtype of the filesystem shown in the mount table.
+    pub fn subtype(mut self, subtype: impl Into<String>) -> Self {
+        self.mount_options.subtype = Some(subtype.into());
+        self
+    }
+
+    /// Set the maximum size of read requests.
+    pub fn max_read(mut self, max_read: u32) -> Self {
+        self.mount_options.max_read = Some(max_read);
+        self
+    }
+
+    /// Set the maximum number of idle worker threads.
+    ///
+    /// This value is only used by `Session::run_loop_mt`, and defaults to
+    /// 10 as in libfuse.
+    pub fn max_idle_threads(mut self, max_idle_threads: usize) -> Self {
+        self.max_idle_threads = Some(max_idle_threads);
+        self
+    }
+
+    /// Use a separate device file descriptor for each worker thread.
+    ///
+    /// This value is only used by `Session::run_loop_mt`.
+    pub fn clone_fd(mut self, enabled: bool) -> Self {
+        self.clone_fd = enabled;
+        self
+    }
+
+    /// Prepare the session to be handed off to another process by
+    /// `Session::export`.
+    ///
+    /// The session records the `INIT` request sent by the kernel, by reading
+    /// and writing the messages through `fuse_session_custom_io` instead of
+    /// the default I/O of libfuse. This requires libfuse 3.14 or later.
+    #[cfg(feature = "handoff")]
+    pub fn handoff(mut self, enabled: bool) -> Self {
+        self.handoff = enabled;
+        self
+    }
+
+    /// Collect the statistics of the dispatched operations.
+    ///
+    /// The statistics can be obtained by `Session::stats`.
+    pub fn stats(mut self, enabled: bool) -> Self {
+        self.stats = enabled;
+        self
+    }
+
+    /// Communicate through the specified file descriptor instead of
+    /// mounting with `/dev/fuse`.
+    ///
+    /// The messages are exchanged over `fd` by `read(2)` and `writev(2)`, so
+    /// it must preserve the message boundaries, e.g. a socket created by
+    /// `socketpair(2)` with `SOCK_SEQPACKET`. The session built with this
+    /// option is ready to run the event loop without calling `mount`.
+    ///
+    /// This requires libfuse 3.14 or later.
+    #[cfg(feature = "custom-io")]
+    pub fn custom_io(mut self, fd: OwnedFd) -> Self {
+        self.custom_io = Some(fd);
+        self
+    }
+
     /// Build a new `Session` using the specified filesystem operations.
-    pub fn build<T: Operations>(self, ops: T) -> io::Result<Session<T>> {
+    ///
+    /// This method also installs the log handler of libfuse, which forwards
+    /// its messages to the `log` crate.
+    pub fn build<T: Operations>(self, ops: T) -> Result<Session<T>, SessionError> {
+        self.validate()?;
+
         let mut args = vec![CString::new(self.fsname)?];
+        if let Some(mount_options) = self.mount_options.to_option_string() {
+            args.push(CString::new("-o")?);
+            args.push(CString::new(mount_options)?);
+        }
         args.extend(
             self.options
                 .into_iter()
@@ -59,40 +342,477 @@ impl Builder {
         );
 
         let c_args: Vec<*const c_char> = args.iter().map(|arg| arg.as_ptr()).collect();
-        let se;
+        let (se, messages);
+        let stats = if self.stats {
+            Some(Arc::new(Stats::new()))
+        } else {
+            None
+        };
+        let ctx = Box::into_raw(Box::new(SharedContext::new(Context::new(ops, stats))));
         unsafe {
             let fops = fuse_ll_ops_new();
             if fops.is_null() {
-                return Err(io::Error::from_raw_os_error(libc::ENOMEM));
-            }
-            assign_ops(&mut *fops, &ops);
-            se = fuse_session_new_wrapped(
-                c_args.len() as c_int,
-                c_args.as_ptr(),
-                fops,
-                Box::into_raw(Box::new(Context::new(ops))) as *mut _,
-            );
+                mem::drop(Box::from_raw(ctx));
+                return Err(io::Error::from_raw_os_error(libc::ENOMEM).into());
+            }
+            assign_ops(&mut *fops, (*(*ctx).get()).ops());
+            let (res, lines) = logging::capture(|| {
+                fuse_session_new_wrapped(
+                    c_args.len() as c_int,
+                    c_args.as_ptr(),
+                    fops,
+                    ctx as *mut _,
+                )
+            });
             libc::free(fops as *mut _);
+            se = res;
+            messages = lines;
         };
         if se.is_null() {
-            return Err(io::ErrorKind::Other.into());
+            unsafe {
+                mem::drop(Box::from_raw(ctx));
+            }
+            return Err(SessionError::from_new_messages(messages));
         }
 
+        #[cfg(feature = "custom-io")]
+        let custom_io = match self.custom_io {
+            Some(fd) => {
+                let fd = fd.into_raw_fd();
+                let res = unsafe { fuse_session_custom_io_fd(se, fd) };
+                if res != 0 {
+                    unsafe {
+                        libc::close(fd);
+                        fuse_session_destroy(se);
+                        mem::drop(Box::from_raw(ctx));
+                    }
+                    return Err(io::Error::from_raw_os_error(-res).into());
+                }
+                true
+            }
+            None => false,
+        };
+        #[cfg(not(feature = "custom-io"))]
+        let custom_io = false;
+
+        #[cfg(feature = "handoff")]
+        let handoff = self.handoff;
+        #[cfg(not(feature = "handoff"))]
+        let handoff = false;
+
         Ok(Session {
             se: unsafe { NonNull::new_unchecked(se) },
+            ctx: unsafe { NonNull::new_unchecked(ctx) },
             set_signal_handlers: false,
             mountpoint: None,
-            _marker: PhantomData,
+            fd_mount: None,
+            resumed: false,
+            custom_io,
+            clone_fd: self.clone_fd,
+            max_idle_threads: self.max_idle_threads,
+            handoff,
+            #[cfg(feature = "handoff")]
+            auto_unmount: self.mount_options.auto_unmount,
+            #[cfg(feature = "handoff")]
+            exported: false,
         })
     }
+
+    /// Check the typed options before passing them to libfuse.
+    fn validate(&self) -> Result<(), SessionError> {
+        fn invalid_input(msg: String) -> SessionError {
+            SessionError::InvalidOption(msg)
+        }
+
+        let opts = &self.mount_options;
+
+        if opts.allow_other && opts.allow_root {
+            return Err(invalid_input(
+                "'allow_other' and 'allow_root' are mutually exclusive".into(),
+            ));
+        }
+
+        for (name, value) in &[("fsname", &opts.fsname), ("subtype", &opts.subtype)] {
+            if let Some(value) = value {
+                if value.is_empty() {
+                    return Err(invalid_input(format!("'{}' must not be empty", name)));
+                }
+                if value.contains(&[',', '\\', '\0'][..]) {
+                    return Err(invalid_input(format!(
+                        "'{}' contains an invalid character: {:?}",
+                        name, value
+                    )));
+                }
+            }
+        }
+
+        if opts.max_read == Some(0) {
+            return Err(invalid_input("'max_read' must be positive".into()));
+        }
+
+        if self.max_idle_threads == Some(0) {
+            return Err(invalid_input("'max_idle_threads' must be positive".into()));
+        }
+
+        // Reject the typed options that are also given through `options`.
+        let mut iter = self.options.iter();
+        while let Some(arg) = iter.next() {
+            let values = match arg.as_str() {
+                "-o" => match iter.next() {
+                    Some(values) => values.as_str(),
+                    None => return Err(invalid_input("missing argument for '-o'".into())),
+                },
+                arg if arg.starts_with("-o") => &arg[2..],
+                _ => continue,
+            };
+            for value in values.split(',') {
+                let name = value.split('=').next().unwrap_or("");
+                if opts.is_specified(name) {
+                    return Err(invalid_input(format!(
+                        "the option '{}' is specified more than once",
+                        name
+                    )));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The mount options passed to `fuse_session_new`.
+#[derive(Debug, Default)]
+struct MountOptions {
+    allow_other: bool,
+    allow_root: bool,
+    default_permissions: bool,
+    ro: bool,
+    auto_unmount: bool,
+    fsname: Option<String>,
+    subtype: Option<String>,
+    max_read: Option<u32>,
+}
+
+impl MountOptions {
+    fn is_specified(&self, name: &str) -> bool {
+        match name {
+            "allow_other" => self.allow_other,
+            "allow_root" => self.allow_root,
+            "default_permissions" => self.default_permissions,
+            "ro" => self.ro,
+            "auto_unmount" => self.auto_unmount,
+            "fsname" => self.fsname.is_some(),
+            "subtype" => self.subtype.is_some(),
+            "max_read" => self.max_read.is_some(),
+            _ => false,
+        }
+    }
+
+    fn to_option_string(&self) -> Option<String> {
+        let mut opts = vec![];
+        for &(enabled, name) in &[
+            (self.allow_other, "allow_other"),
+            (self.allow_root, "allow_root"),
+            (self.default_permissions, "default_permissions"),
+            (self.ro, "ro"),
+            (self.auto_unmount, "auto_unmount"),
+        ] {
+            if enabled {
+                opts.push(name.to_owned());
+            }
+        }
+        if let Some(ref fsname) = self.fsname {
+            opts.push(format!("fsname={}", fsname));
+        }
+        if let Some(ref subtype) = self.subtype {
+            opts.push(format!("subtype={}", subtype));
+        }
+        if let Some(max_read) = self.max_read {
+            opts.push(format!("max_read={}", max_read));
+        }
+
+        if !opts.is_empty() {
+            Some(opts.join(","))
+        } else {
+            None
+        }
+    }
+}
+
+/// The filesystem specific options given by `-o` in the command line.
+pub trait CustomOptions: Default {
+    /// Parse an option of the form `name` or `name=value`.
+    ///
+    /// Returns `Ok(true)` if the option is consumed, or `Ok(false)` if
+    /// it should be passed to libfuse.
+    fn parse(&mut self, name: &str, value: Option<&str>) -> Result<bool, String>;
+}
+
+impl CustomOptions for () {
+    fn parse(&mut self, _: &str, _: Option<&str>) -> Result<bool, String> {
+        Ok(false)
+    }
+}
+
+struct OptionParser<O> {
+    options: O,
+    error: Option<String>,
+}
+
+unsafe extern "C" fn parse_custom_option<O: CustomOptions>(
+    data: *mut c_void,
+    arg: *const c_char,
+    key: c_int,
+    _: *mut fuse_args,
+) -> c_int {
+    let parser = &mut *(data as *mut OptionParser<O>);
+    let arg = CStr::from_ptr(arg).to_string_lossy();
+
+    // The arguments other than the ones given by `-o` are kept as is.
+    if key != FUSE_OPT_KEY_OPT || arg.starts_with('-') {
+        return 1;
+    }
+
+    let mut iter = arg.splitn(2, '=');
+    let name = iter.next().unwrap_or("");
+    let value = iter.next();
+    match parser.options.parse(name, value) {
+        Ok(true) => 0,
+        Ok(false) => 1,
+        Err(msg) => {
+            parser.error = Some(msg);
+            -1
+        }
+    }
+}
+
+/// The result of parsing the command line arguments.
+#[derive(Debug)]
+pub struct CommandLine<O = ()> {
+    /// The builder configured with the remaining options.
+    pub builder: Builder,
+
+    /// The mountpoint, if specified.
+    pub mountpoint: Option<PathBuf>,
+
+    /// Whether to stay in the foreground (`-f`).
+    pub foreground: bool,
+
+    /// Whether to use the single threaded event loop (`-s`).
+    pub singlethread: bool,
+
+    /// Whether the debug output is enabled (`-d`).
+    pub debug: bool,
+
+    /// Whether the help message was requested (`-h`).
+    pub show_help: bool,
+
+    /// Whether the version information was requested (`-V`).
+    pub show_version: bool,
+
+    /// The filesystem specific options.
+    pub options: O,
+}
+
+impl<O> CommandLine<O> {
+    /// Print the usage of the generic FUSE options to stdout.
+    pub fn print_help(&self) {
+        println!("usage: {} [options] <mountpoint>\n", self.builder.fsname);
+        unsafe {
+            fuse_cmdline_help();
+            fuse_lowlevel_help();
+        }
+    }
+
+    /// Print the version of libfuse to stdout.
+    pub fn print_version(&self) {
+        let version = unsafe { CStr::from_ptr(fuse_pkgversion()) };
+        println!("FUSE library version {}", version.to_string_lossy());
+        unsafe {
+            fuse_lowlevel_version();
+        }
+    }
+}
+
+/// The error type returned when creating or mounting a session.
+#[derive(Debug)]
+pub enum SessionError {
+    /// An invalid or unknown option was given.
+    InvalidOption(String),
+
+    /// The permission to open `/dev/fuse` was denied.
+    PermissionDenied,
+
+    /// `fusermount3`, which is required for mounting as an unprivileged
+    /// user, was not found.
+    FusermountNotFound,
+
+    /// The mountpoint is neither a directory nor a regular file.
+    NotADirectory(PathBuf),
+
+    /// The mountpoint is already in use.
+    AlreadyMounted(PathBuf),
+
+    /// An I/O error occurred before calling libfuse.
+    Io(io::Error),
+
+    /// Other failures, with the messages reported by libfuse.
+    Other(Vec<String>),
+}
+
+impl SessionError {
+    fn from_new_messages(messages: Vec<String>) -> Self {
+        let invalid = messages.iter().find(|msg| {
+            msg.contains("unknown option")
+                || msg.contains("invalid argument")
+                || msg.contains("missing argument")
+        });
+        match invalid {
+            Some(msg) => SessionError::InvalidOption(msg.clone()),
+            None => SessionError::Other(messages),
+        }
+    }
+
+    /// Classify the failure of `fuse_session_mount`, from the `errno` left
+    /// by it if any, and the messages reported by libfuse.
+    fn from_mount_failure(
+        mountpoint: PathBuf,
+        errno: Option<c_int>,
+        messages: Vec<String>,
+    ) -> Self {
+        let contains = |pat: &str| messages.iter().any(|msg| msg.contains(pat));
+
+        match errno {
+            // Opening `/dev/fuse` and mount(2) may fail with the same errno.
+            Some(libc::EACCES) | Some(libc::EPERM) if contains("/dev/fuse") => {
+                return SessionError::PermissionDenied;
+            }
+            Some(libc::ENOTDIR) => return SessionError::NotADirectory(mountpoint),
+            Some(libc::EBUSY) => return SessionError::AlreadyMounted(mountpoint),
+            Some(libc::EBADF) => {
+                return SessionError::Io(io::Error::from_raw_os_error(libc::EBADF));
+            }
+            _ => (),
+        }
+
+        // The errno may be lost, e.g. when libfuse cleans up after the
+        // failure, so fall back to the messages.
+        if contains("/dev/fuse") && contains("Permission denied") {
+            return SessionError::PermissionDenied;
+        }
+        if contains("Not a directory") {
+            return SessionError::NotADirectory(mountpoint);
+        }
+        if contains("Device or resource busy") {
+            return SessionError::AlreadyMounted(mountpoint);
+        }
+        if contains("Invalid file descriptor") {
+            return SessionError::Io(io::Error::from_raw_os_error(libc::EBADF));
+        }
+        // The failure of executing fusermount3 is reported by the forked
+        // process and does not reach the log handler.
+        if unsafe { libc::geteuid() } != 0 && !fusermount_exists() {
+            return SessionError::FusermountNotFound;
+        }
+
+        SessionError::Other(messages)
+    }
+}
+
+/// Call `f`, returning the `errno` set by it if any.
+fn with_errno<R>(f: impl FnOnce() -> R) -> (R, Option<c_int>) {
+    unsafe {
+        *libc::__errno_location() = 0;
+    }
+    let res = f();
+    let errno = io::Error::last_os_error()
+        .raw_os_error()
+        .filter(|&errno| errno != 0);
+    (res, errno)
+}
+
+fn fusermount_exists() -> bool {
+    let paths = env::var_os("PATH").unwrap_or_default();
+    env::split_paths(&paths)
+        .chain(Some(PathBuf::from("/usr/bin")))
+        .any(|dir| dir.join("fusermount3").is_file())
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SessionError::InvalidOption(msg) => write!(f, "invalid option: {}", msg),
+            SessionError::PermissionDenied => f.write_str("permission denied to open /dev/fuse"),
+            SessionError::FusermountNotFound => f.write_str("fusermount3 is not found"),
+            SessionError::NotADirectory(path) => {
+                write!(f, "the mountpoint is not a directory: {}", path.display())
+            }
+            SessionError::AlreadyMounted(path) => {
+                write!(f, "the mountpoint is already mounted: {}", path.display())
+            }
+            SessionError::Io(err) => err.fmt(f),
+            SessionError::Other(messages) if messages.is_empty() => {
+                f.write_str("libfuse reported an unknown error")
+            }
+            SessionError::Other(messages) => f.write_str(&messages.join("\n")),
+        }
+    }
+}
+
+impl error::Error for SessionError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            SessionError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for SessionError {
+    fn from(err: io::Error) -> Self {
+        SessionError::Io(err)
+    }
+}
+
+impl From<NulError> for SessionError {
+    fn from(err: NulError) -> Self {
+        SessionError::InvalidOption(err.to_string())
+    }
+}
+
+impl From<SessionError> for io::Error {
+    fn from(err: SessionError) -> Self {
+        let kind = match err {
+            SessionError::Io(err) => return err,
+            SessionError::InvalidOption(..) | SessionError::NotADirectory(..) => {
+                io::ErrorKind::InvalidInput
+            }
+            SessionError::PermissionDenied => io::ErrorKind::PermissionDenied,
+            SessionError::FusermountNotFound => io::ErrorKind::NotFound,
+            SessionError::AlreadyMounted(..) => io::ErrorKind::AlreadyExists,
+            SessionError::Other(..) => io::ErrorKind::Other,
+        };
+        io::Error::new(kind, err)
+    }
 }
 
 /// The session for operating a filesystem.
 pub struct Session<T: Operations> {
     se: NonNull<fuse_session>,
+    ctx: NonNull<SharedContext<T>>,
     set_signal_handlers: bool,
     mountpoint: Option<PathBuf>,
-    _marker: PhantomData<T>,
+    /// The path of the form `/dev/fd/N` passed to libfuse by `mount_fd`.
+    fd_mount: Option<PathBuf>,
+    resumed: bool,
+    custom_io: bool,
+    clone_fd: bool,
+    max_idle_threads: Option<usize>,
+    handoff: bool,
+    #[cfg(feature = "handoff")]
+    auto_unmount: bool,
+    #[cfg(feature = "handoff")]
+    exported: bool,
 }
 
 impl<T: Operations> Session<T> {
@@ -121,16 +841,219 @@ impl<T: Operations> Session<T> {
     }
 
     /// Mount this session to the specified mountpoint.
-    pub fn mount(&mut self, mountpoint: impl AsRef<Path>) -> io::Result<()> {
+    pub fn mount(&mut self, mountpoint: impl AsRef<Path>) -> Result<(), SessionError> {
+        self.ensure_not_connected()?;
+
         let mountpoint = mountpoint.as_ref().to_path_buf();
 
-        let c_mountpoint = CString::new(mountpoint.as_os_str().as_bytes())?;
-        let res = unsafe { fuse_session_mount(self.se.as_ptr(), c_mountpoint.as_ptr()) };
+        let metadata = fs::metadata(&mountpoint)?;
+        if !metadata.is_dir() && !metadata.is_file() {
+            return Err(SessionError::NotADirectory(mountpoint));
+        }
+
+        self.mount_inner(&mountpoint)?;
+        self.mountpoint = Some(mountpoint);
+        Ok(())
+    }
+
+    /// Mount this session using a file descriptor of `/dev/fuse` that the
+    /// caller has already opened and mounted.
+    ///
+    /// The file descriptor is passed to libfuse as the mountpoint of the form
+    /// `/dev/fd/N`, which requires libfuse 3.3 or later. The ownership of the
+    /// descriptor moves to the session, and `unmount` does not unmount the
+    /// filesystem since it is the responsibility of the process performing
+    /// the mount. `mountpoint` returns `None` for the session mounted by
+    /// this method.
+    pub fn mount_fd(&mut self, fd: OwnedFd) -> Result<(), SessionError> {
+        self.ensure_not_connected()?;
+
+        let fd = fd.into_raw_fd();
+        let path = PathBuf::from(format!("/dev/fd/{}", fd));
+        if let Err(err) = self.mount_inner(&path) {
+            unsafe {
+                libc::close(fd);
+            }
+            return Err(err);
+        }
+        self.fd_mount = Some(path);
+        Ok(())
+    }
+
+    fn ensure_not_connected(&self) -> Result<(), SessionError> {
+        if let Some(mountpoint) = self.mountpoint.as_ref().or(self.fd_mount.as_ref()) {
+            return Err(SessionError::AlreadyMounted(mountpoint.to_path_buf()));
+        }
+        if self.resumed {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "The session has been resumed from another process.",
+            )
+            .into());
+        }
+        #[cfg(feature = "handoff")]
+        {
+            if self.exported {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "The session has been handed off.",
+                )
+                .into());
+            }
+        }
+        if self.custom_io {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "The session uses a custom transport.",
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Returns whether the session is ready to communicate with the kernel.
+    fn is_connected(&self) -> bool {
+        self.mountpoint.is_some() || self.fd_mount.is_some() || self.resumed || self.custom_io
+    }
+
+    fn mount_inner(&mut self, mountpoint: &Path) -> Result<(), SessionError> {
+        let c_mountpoint = CString::new(mountpoint.as_os_str().as_bytes())
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        let ((res, errno), messages) = logging::capture(|| {
+            with_errno(|| unsafe { fuse_session_mount(self.se.as_ptr(), c_mountpoint.as_ptr()) })
+        });
+        if res != 0 {
+            return Err(SessionError::from_mount_failure(
+                mountpoint.to_path_buf(),
+                errno,
+                messages,
+            ));
+        }
+
+        #[cfg(feature = "handoff")]
+        {
+            if self.handoff {
+                let se = self.se.as_ptr();
+                if let Err(err) = self.install_handoff_io(unsafe { fuse_session_fd(se) }) {
+                    unsafe { fuse_session_unmount(se) };
+                    return Err(err.into());
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Replace the I/O of libfuse by the one recording the `INIT` request.
+    #[cfg(feature = "handoff")]
+    fn install_handoff_io(&mut self, fd: RawFd) -> io::Result<()> {
+        let res = unsafe {
+            fuse_session_custom_io_with(self.se.as_ptr(), fd, handoff::writev, handoff::read::<T>)
+        };
         if res != 0 {
+            return Err(io::Error::from_raw_os_error(-res));
+        }
+        Ok(())
+    }
+
+    /// Hand off the connection to another process, without unmounting the
+    /// filesystem.
+    ///
+    /// The returned `Handoff` holds a duplicate of the file descriptor
+    /// connected to the kernel, the `INIT` request that the kernel sent when
+    /// mounting, which `resume` replays in the other process, and the state
+    /// returned by `Operations::export`. The requests not read yet are left
+    /// to the other process.
+    ///
+    /// The event loop must have exited, e.g. by a signal handled by
+    /// `set_signal_handlers`. After this method returns, the session is
+    /// disconnected and is not unmounted when dropped.
+    ///
+    /// The session must be built with `Builder::handoff`, and initialized
+    /// by the kernel. The sessions mounted with `auto_unmount` cannot be
+    /// handed off, since `fusermount3` unmounts the filesystem once this
+    /// process exits.
+    #[cfg(feature = "handoff")]
+    pub fn export(&mut self) -> io::Result<Handoff> {
+        if !self.handoff {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "The session is not built with 'handoff'.",
+            ));
+        }
+        if self.auto_unmount {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "The session is mounted with 'auto_unmount'.",
+            ));
+        }
+        let init = match unsafe { self.ctx.as_ref() }.capture().get() {
+            Some(init) if self.is_connected() => init,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "The session has not been initialized by the kernel.",
+                ))
+            }
+        };
+        let state = self.ctx_mut().export()?;
+
+        let fd =
+            unsafe { libc::fcntl(fuse_session_fd(self.se.as_ptr()), libc::F_DUPFD_CLOEXEC, 0) };
+        if fd == -1 {
             return Err(io::Error::last_os_error());
         }
+        self.mountpoint = None;
+        self.fd_mount = None;
+        self.resumed = false;
+        self.exported = true;
+        self.ctx_mut().skip_destroy();
 
-        self.mountpoint = Some(mountpoint);
+        Ok(Handoff::new(
+            unsafe { OwnedFd::from_raw_fd(fd) },
+            init,
+            state,
+        ))
+    }
+
+    /// Take over the connection handed off by `export` in another process.
+    ///
+    /// The filesystem passed to `Builder::build` should have been restored
+    /// from `Handoff::state`, and the session built with the same options
+    /// as the exported one. The recorded `INIT` request is replayed to
+    /// libfuse, which calls `Operations::init` without replying to the
+    /// kernel, and the event loop can be run immediately.
+    ///
+    /// The resumed session is not unmounted by `unmount` or when dropped,
+    /// since its mountpoint is unknown to this process. It can be handed off
+    /// again by `export`.
+    #[cfg(feature = "handoff")]
+    pub fn resume(&mut self, handoff: Handoff) -> Result<(), SessionError> {
+        self.ensure_not_connected()?;
+
+        let (fd, mut init) = handoff.into_init();
+        let fd = fd.into_raw_fd();
+        if let Err(err) = self.install_handoff_io(fd) {
+            unsafe {
+                libc::close(fd);
+            }
+            return Err(err.into());
+        }
+        self.handoff = true;
+        self.resumed = true;
+        unsafe { self.ctx.as_ref() }.capture().set(init.clone());
+
+        handoff::make_replayed(&mut init);
+        let ((), messages) = logging::capture(|| unsafe {
+            fuse_session_process_mem(
+                self.se.as_ptr(),
+                init.as_mut_ptr() as *mut c_void,
+                init.len(),
+            )
+        });
+        if !self.ctx().is_initialized() {
+            return Err(SessionError::Other(messages));
+        }
 
         Ok(())
     }
@@ -139,6 +1062,10 @@ impl<T: Operations> Session<T> {
         self.mountpoint.as_ref().map(|path| &**path)
     }
 
+    /// Unmount the filesystem mounted by `mount`.
+    ///
+    /// The session mounted by `mount_fd` is not unmounted, since the
+    /// process that performed the mount is responsible for it.
     pub fn unmount(&mut self) {
         unsafe {
             if let Some(_) = self.mountpoint.take() {
@@ -147,27 +1074,159 @@ impl<T: Operations> Session<T> {
         }
     }
 
+    /// Detach the process from the terminal and continue in the background.
+    ///
+    /// The session must have been mounted before calling this method, so that
+    /// the failure of mounting is reported through the exit status of the
+    /// invoked process. On success, the original process exits with status `0`
+    /// and this method returns in the forked child, whose stdio is redirected
+    /// to `/dev/null`. If `foreground` is `true`, the process is not forked
+    /// and only the working directory is changed to `/`.
+    ///
+    /// Since this method calls `fork(2)`, it should be called before any
+    /// threads are spawned.
+    pub fn daemonize(&mut self, foreground: bool) -> io::Result<()> {
+        if !self.is_connected() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "The session has not mounted yet.",
+            ));
+        }
+
+        let (res, messages) = logging::capture(|| unsafe { fuse_daemonize(foreground as c_int) });
+        if res != 0 {
+            let msg = if !messages.is_empty() {
+                messages.join("\n")
+            } else {
+                "failed to daemonize the process".into()
+            };
+            return Err(io::Error::new(io::ErrorKind::Other, msg));
+        }
+
+        Ok(())
+    }
+
+    /// Returns whether the worker threads use the cloned device file descriptors.
+    pub fn clone_fd(&self) -> bool {
+        self.clone_fd
+    }
+
+    /// Returns the maximum number of idle worker threads, if specified.
+    pub fn max_idle_threads(&self) -> Option<usize> {
+        self.max_idle_threads
+    }
+
+    /// Returns the collector of the statistics, if enabled by `Builder::stats`.
+    ///
+    /// The returned handle remains valid while the event loop is running.
+    pub fn stats(&self) -> Option<Arc<Stats>> {
+        self.ctx().stats().cloned()
+    }
+
     /// Returns the *raw* file descriptor for communication with the kernel.
     pub fn raw_fd(&self) -> Option<RawFd> {
-        if self.mountpoint.is_some() {
+        if self.is_connected() {
             Some(unsafe { fuse_session_fd(self.se.as_ptr()) })
         } else {
             None
         }
     }
 
+    /// Returns a reference to the filesystem operations.
+    pub fn get_ref(&self) -> &T {
+        self.ctx().ops()
+    }
+
+    /// Returns a mutable reference to the filesystem operations.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.ctx_mut().ops_mut()
+    }
+
+    // The event loop runs only while the session is mutably borrowed, so
+    // no callback accesses the context through these references.
+    fn ctx(&self) -> &Context<T> {
+        unsafe { &*self.ctx.as_ref().get() }
+    }
+
+    fn ctx_mut(&mut self) -> &mut Context<T> {
+        unsafe { &mut *self.ctx.as_ref().get() }
+    }
+
+    /// Consume this session and return the filesystem operations.
+    ///
+    /// The session is unmounted and destroyed before returning, so
+    /// `Operations::destroy` has already been called on the returned value
+    /// if the filesystem was initialized.
+    pub fn into_inner(self) -> T {
+        let mut this = mem::ManuallyDrop::new(self);
+        unsafe { this.destroy().into_ops() }
+    }
+
+    /// Unmount and destroy the session, and take back the context.
+    ///
+    /// This method must be called only once.
+    unsafe fn destroy(&mut self) -> Context<T> {
+        self.unmount();
+        self.remove_signal_handlers();
+        fuse_session_destroy(self.se.as_ptr());
+
+        let mut ctx = Box::from_raw(self.ctx.as_ptr()).into_inner();
+        if let Some(err) = ctx.take_destroy_error() {
+            log::error!("failed to destroy the filesystem: {}", err);
+        }
+        ctx
+    }
+
     /// Enter a single threaded, blocking event loop.
     ///
     /// When the event loop exits as a result of receiving a signal,
     /// this method returns the code of its signal.
+    ///
+    /// `Operations::destroy` is called before returning if the filesystem
+    /// has been initialized, and its error is returned from this method.
+    /// The session built with `Builder::handoff` calls it when dropped
+    /// instead, unless handed off by `export`.
     pub fn run_loop(&mut self) -> io::Result<c_int> {
-        if self.mountpoint.is_none() {
+        self.run_loop_with(|se| unsafe { fuse_session_loop(se) })
+    }
+
+    /// Enter a multi-threaded, blocking event loop.
+    ///
+    /// The requests are received by a pool of worker threads, configured by
+    /// `Builder::clone_fd` and `Builder::max_idle_threads`. Since the methods
+    /// of `Operations` take `&mut self`, they are still called one at a
+    /// time, but the other workers keep receiving the requests meanwhile,
+    /// e.g. the interrupts of the request in progress.
+    ///
+    /// The return value is the same as `run_loop`.
+    pub fn run_loop_mt(&mut self) -> io::Result<c_int>
+    where
+        T: Send,
+    {
+        let clone_fd = self.clone_fd as c_int;
+        let max_idle_threads = self.max_idle_threads.unwrap_or(DEFAULT_MAX_IDLE_THREADS) as c_uint;
+        self.run_loop_with(|se| unsafe {
+            fuse_session_loop_mt_wrapped(se, clone_fd, max_idle_threads)
+        })
+    }
+
+    fn run_loop_with(&mut self, f: impl FnOnce(*mut fuse_session) -> c_int) -> io::Result<c_int> {
+        if !self.is_connected() {
             return Err(io::Error::new(
                 io::ErrorKind::Other,
                 "The session has not mounted yet.",
             ));
         }
-        let res = unsafe { fuse_session_loop(self.se.as_ptr()) };
+        let res = f(self.se.as_ptr());
+
+        if !self.handoff {
+            let ctx = self.ctx_mut();
+            ctx.destroy();
+            if let Some(err) = ctx.take_destroy_error() {
+                return Err(err);
+            }
+        }
+
         match res {
             0 => Ok(0),
             signo if signo > 0 => Ok(signo),
@@ -178,10 +1237,85 @@ impl<T: Operations> Session<T> {
 
 impl<T: Operations> Drop for Session<T> {
     fn drop(&mut self) {
-        self.unmount();
-        self.remove_signal_handlers();
         unsafe {
-            fuse_session_destroy(self.se.as_ptr());
+            mem::drop(self.destroy());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages(lines: &[&str]) -> Vec<String> {
+        lines.iter().map(|&line| line.to_owned()).collect()
+    }
+
+    fn mount_failure(errno: Option<c_int>, lines: &[&str]) -> SessionError {
+        SessionError::from_mount_failure(PathBuf::from("/mnt"), errno, messages(lines))
+    }
+
+    #[test]
+    fn mount_failure_from_errno() {
+        match mount_failure(
+            Some(libc::EACCES),
+            &["fuse: failed to open /dev/fuse: Permission denied"],
+        ) {
+            SessionError::PermissionDenied => (),
+            err => panic!("unexpected error: {:?}", err),
+        }
+        match mount_failure(Some(libc::ENOTDIR), &[]) {
+            SessionError::NotADirectory(path) => assert_eq!(path, Path::new("/mnt")),
+            err => panic!("unexpected error: {:?}", err),
+        }
+        match mount_failure(Some(libc::EBUSY), &[]) {
+            SessionError::AlreadyMounted(path) => assert_eq!(path, Path::new("/mnt")),
+            err => panic!("unexpected error: {:?}", err),
+        }
+        match mount_failure(
+            Some(libc::EBADF),
+            &["fuse: Invalid file descriptor /dev/fd/42"],
+        ) {
+            SessionError::Io(err) => assert_eq!(err.raw_os_error(), Some(libc::EBADF)),
+            err => panic!("unexpected error: {:?}", err),
+        }
+    }
+
+    #[test]
+    fn mount_failure_from_messages() {
+        match mount_failure(None, &["fuse: failed to open /dev/fuse: Permission denied"]) {
+            SessionError::PermissionDenied => (),
+            err => panic!("unexpected error: {:?}", err),
+        }
+        match mount_failure(None, &["fuse: bad mount point `/mnt': Not a directory"]) {
+            SessionError::NotADirectory(..) => (),
+            err => panic!("unexpected error: {:?}", err),
+        }
+        match mount_failure(
+            Some(libc::EINVAL),
+            &["fuse: mount failed: Device or resource busy"],
+        ) {
+            SessionError::AlreadyMounted(..) => (),
+            err => panic!("unexpected error: {:?}", err),
+        }
+
+        // mount(2) may fail with EACCES for the mountpoint.
+        let err = mount_failure(
+            Some(libc::EACCES),
+            &["fuse: mount failed: Permission denied"],
+        );
+        assert!(!matches!(err, SessionError::PermissionDenied));
+    }
+
+    #[test]
+    fn invalid_options() {
+        match SessionError::from_new_messages(messages(&["fuse: unknown option(s): `-o foo'"])) {
+            SessionError::InvalidOption(msg) => assert!(msg.contains("-o foo")),
+            err => panic!("unexpected error: {:?}", err),
+        }
+        match SessionError::from_new_messages(messages(&["fuse: failed to allocate fuse object"])) {
+            SessionError::Other(lines) => assert_eq!(lines.len(), 1),
+            err => panic!("unexpected error: {:?}", err),
         }
     }
 }
@@ -0,0 +1,93 @@
+//! A binary encoding of the state handed off to another process.
+//!
+//! The snapshots consist of little-endian `u64` integers and byte strings
+//! prefixed by their lengths. They are only read by the same version of
+//! this library, so the format is not versioned.
+
+use std::io;
+
+pub(crate) fn put_u64(buf: &mut Vec<u8>, value: u64) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+pub(crate) fn put_bytes(buf: &mut Vec<u8>, data: &[u8]) {
+    put_u64(buf, data.len() as u64);
+    buf.extend_from_slice(data);
+}
+
+/// A cursor over a snapshot.
+pub(crate) struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+
+    pub(crate) fn u64(&mut self) -> io::Result<u64> {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    pub(crate) fn bytes(&mut self) -> io::Result<&'a [u8]> {
+        let len = self.u64()?;
+        if len > self.buf.len() as u64 {
+            return Err(malformed());
+        }
+        self.take(len as usize)
+    }
+
+    /// Ensure that the whole snapshot has been read.
+    pub(crate) fn finish(self) -> io::Result<()> {
+        if !self.buf.is_empty() {
+            return Err(malformed());
+        }
+        Ok(())
+    }
+
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.buf.len() < len {
+            return Err(malformed());
+        }
+        let (data, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(data)
+    }
+}
+
+pub(crate) fn malformed() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "malformed snapshot")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut buf = vec![];
+        put_u64(&mut buf, 42);
+        put_bytes(&mut buf, b"foo");
+
+        let mut reader = Reader::new(&buf);
+        assert_eq!(reader.u64().unwrap(), 42);
+        assert_eq!(reader.bytes().unwrap(), b"foo");
+        reader.finish().unwrap();
+    }
+
+    #[test]
+    fn truncated() {
+        let mut buf = vec![];
+        put_bytes(&mut buf, b"foo");
+        buf.pop();
+
+        let mut reader = Reader::new(&buf);
+        assert_eq!(
+            reader.bytes().unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        assert!(Reader::new(&buf).finish().is_err());
+    }
+}
@@ -0,0 +1,170 @@
+//! Statistics of the dispatched operations.
+
+use libc::c_int;
+use std::{
+    cell::Cell,
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// The upper bounds of the latency buckets.
+///
+/// The operations slower than the last bound are counted in an
+/// additional bucket.
+pub const LATENCY_BUCKETS: [Duration; 6] = [
+    Duration::from_micros(10),
+    Duration::from_micros(100),
+    Duration::from_millis(1),
+    Duration::from_millis(10),
+    Duration::from_millis(100),
+    Duration::from_secs(1),
+];
+
+thread_local! {
+    static OUTCOME: Cell<(c_int, usize)> = Cell::new((0, 0));
+}
+
+/// A collector of the statistics, enabled by `Builder::stats`.
+///
+/// The collector is shared with the event loop, so that the statistics
+/// can be scraped from another thread while the loop is running.
+#[derive(Debug, Default)]
+pub struct Stats {
+    ops: Mutex<BTreeMap<&'static str, OpStats>>,
+}
+
+impl Stats {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Take a snapshot of the current statistics.
+    pub fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            ops: self.ops.lock().unwrap().clone(),
+        }
+    }
+
+    /// Reset all statistics to zero.
+    pub fn reset(&self) {
+        self.ops.lock().unwrap().clear();
+    }
+
+    fn record(&self, op: &'static str, elapsed: Duration, errno: c_int, size: usize) {
+        let mut ops = self.ops.lock().unwrap();
+        let stats = ops.entry(op).or_default();
+        stats.count += 1;
+        if errno != 0 {
+            *stats.errors.entry(errno).or_insert(0) += 1;
+        } else {
+            stats.bytes += size as u64;
+        }
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|&bound| elapsed <= bound)
+            .unwrap_or_else(|| LATENCY_BUCKETS.len());
+        stats.latency[bucket] += 1;
+        stats.total_latency += elapsed;
+    }
+}
+
+/// A snapshot of the statistics.
+#[derive(Debug, Clone, Default)]
+pub struct StatsSnapshot {
+    ops: BTreeMap<&'static str, OpStats>,
+}
+
+impl StatsSnapshot {
+    /// Returns the statistics of the operation, e.g. `"lookup"`.
+    pub fn get(&self, op: &str) -> Option<&OpStats> {
+        self.ops.get(op)
+    }
+
+    /// Returns an iterator over the names of the operations called at
+    /// least once and their statistics.
+    pub fn iter(&self) -> impl Iterator<Item = (&'static str, &OpStats)> {
+        self.ops.iter().map(|(&op, stats)| (op, stats))
+    }
+
+    /// Returns the total number of bytes replied by `read`.
+    pub fn bytes_read(&self) -> u64 {
+        self.get("read").map_or(0, |stats| stats.bytes)
+    }
+
+    /// Returns the total number of bytes written by `write`.
+    pub fn bytes_written(&self) -> u64 {
+        self.get("write").map_or(0, |stats| stats.bytes)
+    }
+}
+
+/// The statistics of an operation.
+#[derive(Debug, Clone, Default)]
+pub struct OpStats {
+    count: u64,
+    errors: BTreeMap<c_int, u64>,
+    bytes: u64,
+    latency: [u64; LATENCY_BUCKETS.len() + 1],
+    total_latency: Duration,
+}
+
+impl OpStats {
+    /// Returns the number of calls.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Returns the number of calls failed with each error number.
+    pub fn errors(&self) -> &BTreeMap<c_int, u64> {
+        &self.errors
+    }
+
+    /// Returns the total size of the replied data, or of the written data
+    /// for `write`.
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// Returns the number of calls in each latency bucket.
+    ///
+    /// The i-th element counts the calls slower than `LATENCY_BUCKETS[i - 1]`
+    /// and not slower than `LATENCY_BUCKETS[i]`, and the last element counts
+    /// those slower than all bounds.
+    pub fn latency(&self) -> &[u64] {
+        &self.latency[..]
+    }
+
+    /// Returns the total time spent by the calls.
+    pub fn total_latency(&self) -> Duration {
+        self.total_latency
+    }
+}
+
+/// A guard that measures a dispatched operation.
+pub(crate) struct Guard {
+    stats: Option<(Arc<Stats>, &'static str, Instant)>,
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        if let Some((stats, op, start)) = self.stats.take() {
+            let (errno, size) = OUTCOME.with(|outcome| outcome.get());
+            stats.record(op, start.elapsed(), errno, size);
+        }
+    }
+}
+
+pub(crate) fn enter(stats: Option<Arc<Stats>>, op: &'static str) -> Guard {
+    OUTCOME.with(|outcome| outcome.set((0, 0)));
+    Guard {
+        stats: stats.map(|stats| (stats, op, Instant::now())),
+    }
+}
+
+pub(crate) fn record_errno(errno: c_int) {
+    OUTCOME.with(|outcome| outcome.set((errno, 0)));
+}
+
+pub(crate) fn record_size(size: usize) {
+    OUTCOME.with(|outcome| outcome.set((0, size)));
+}
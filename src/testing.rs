@@ -0,0 +1,1003 @@
+//! Utilities for testing the implementations of `Operations`.
+//!
+//! `Harness` drives a filesystem in-process with synthetic requests, so
+//! that it can be tested without `/dev/fuse` or a mountpoint. For the
+//! end-to-end tests, `mount_tmp` mounts a filesystem on a temporary
+//! directory, and the checks in `posix` can be run against it.
+//!
+//! This module is available with the `testing` feature.
+
+pub mod posix;
+
+use crate::{
+    common::{NodeId, ROOT_NODEID},
+    dispatch::{self, Reply},
+    file::{
+        Entry, //
+        FileType,
+        RenameFlags,
+        SetAttrs,
+        XAttrFlags,
+        XAttrReply,
+    },
+    ops::{Context, OperationResult, Operations},
+    session::Builder,
+    stats::Stats,
+};
+use libc::{c_int, dev_t, gid_t, mode_t, off_t, stat, statvfs, timespec, uid_t};
+use libfuse_sys::{
+    fuse_file_info,
+    fuse_setattr_flags::*,
+    helpers::{
+        fuse_conn_info_new, //
+        fuse_file_info_new,
+        fuse_file_info_set_fh,
+        fuse_file_info_set_flags,
+    },
+};
+use std::{
+    borrow::Cow,
+    cmp,
+    collections::HashMap,
+    env,
+    ffi::{CStr, CString},
+    fs, io, mem,
+    os::unix::ffi::OsStrExt,
+    path::{Component, Path, PathBuf},
+    process::{self, Command},
+    ptr::{self, NonNull},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc,
+    },
+    thread::{self, JoinHandle},
+};
+
+/// Returns a closure that extracts the values from the expected variant
+/// of `Reply`.
+macro_rules! expect {
+    ($variant:pat => $value:expr) => {
+        |reply| match reply {
+            $variant => $value,
+            _ => unreachable!("unexpected reply"),
+        }
+    };
+}
+
+/// The size of the buffer passed to `readdir` by `Harness::read_dir`.
+const READDIR_SIZE: usize = 4096;
+
+/// A directory entry decoded from the buffer filled by `readdir`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub ino: NodeId,
+    pub offset: off_t,
+    pub kind: Option<FileType>,
+    pub name: CString,
+}
+
+/// A driver that processes synthetic requests as the session would on
+/// receiving them from the kernel, and returns the replies.
+///
+/// The requests go through the same dispatch as those received by the
+/// session, i.e. the arguments and the replies are converted in the same
+/// way, e.g. the data returned by `read` is truncated to the requested
+/// size, and the requests are traced and measured. The buffer filled by
+/// `readdir` is decoded into `DirEntry`s. `init` is called by `new`.
+///
+/// The harness also counts the lookups of the entries replied by
+/// `lookup`, `mknod`, `mkdir`, `symlink`, `link` and `create`, so that
+/// `forget_all` can release them as the kernel does when it evicts the
+/// inodes.
+///
+/// Note that the synthetic requests are never interrupted.
+pub struct Harness<T: Operations> {
+    ctx: Context<T>,
+    lookups: HashMap<NodeId, u64>,
+}
+
+impl<T: Operations> Harness<T> {
+    /// Create a harness for the filesystem and initialize it.
+    pub fn new(ops: T) -> Self {
+        let mut ctx = Context::new(ops, Some(Arc::new(Stats::new())));
+        unsafe {
+            let conn = fuse_conn_info_new();
+            assert!(!conn.is_null(), "no memory space");
+            ctx.init(&mut *conn);
+            libc::free(conn as *mut _);
+        }
+        Self {
+            ctx,
+            lookups: HashMap::new(),
+        }
+    }
+
+    /// Returns a reference to the filesystem.
+    pub fn ops(&self) -> &T {
+        self.ctx.ops()
+    }
+
+    /// Returns a mutable reference to the filesystem.
+    pub fn ops_mut(&mut self) -> &mut T {
+        self.ctx.ops_mut()
+    }
+
+    /// Returns the statistics of the requests processed by the harness.
+    pub fn stats(&self) -> Arc<Stats> {
+        self.ctx
+            .stats()
+            .cloned()
+            .expect("the statistics are enabled")
+    }
+
+    /// Consume the harness, returning the filesystem.
+    ///
+    /// `destroy` is not called unless it has been called explicitly.
+    pub fn into_inner(self) -> T {
+        self.ctx.into_ops()
+    }
+
+    /// Call `Operations::destroy`, as the session does on unmount.
+    pub fn destroy(&mut self) -> io::Result<()> {
+        self.ctx.destroy();
+        match self.ctx.take_destroy_error() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the number of lookups of the node not forgotten yet.
+    pub fn nlookup(&self, id: NodeId) -> u64 {
+        self.lookups.get(&id).cloned().unwrap_or(0)
+    }
+
+    fn call<R>(
+        &mut self,
+        op: &'static str,
+        ino: NodeId,
+        fh: Option<u64>,
+        f: impl FnOnce(&mut T) -> OperationResult<Reply<'_>>,
+        map: impl FnOnce(Reply<'_>) -> R,
+    ) -> OperationResult<R> {
+        self.ctx
+            .dispatch(op, ptr::null_mut(), ino, fh, f, |res| res.map(map))
+    }
+
+    fn count(&mut self, entry: &Entry) {
+        // The kernel does not count the lookups of negative entries.
+        if entry.nodeid != 0 {
+            *self.lookups.entry(entry.nodeid).or_insert(0) += 1;
+        }
+    }
+
+    fn count_entry(&mut self, res: OperationResult<Entry>) -> OperationResult<Entry> {
+        if let Ok(ref entry) = res {
+            self.count(entry);
+        }
+        res
+    }
+
+    pub fn lookup(&mut self, parent: NodeId, name: &CStr) -> OperationResult<Entry> {
+        let res = self.call(
+            "lookup",
+            parent,
+            None,
+            |ops| dispatch::lookup(ops, parent, name),
+            expect!(Reply::Entry(entry) => entry),
+        );
+        self.count_entry(res)
+    }
+
+    /// Look up the path relative to the root, one component at a time.
+    ///
+    /// `..` is not supported and results in `EINVAL`.
+    pub fn lookup_path(&mut self, path: impl AsRef<Path>) -> OperationResult<Entry> {
+        let mut entry = Entry {
+            nodeid: ROOT_NODEID,
+            attr: self.getattr(ROOT_NODEID, None)?,
+            ..Entry::default()
+        };
+        for component in path.as_ref().components() {
+            match component {
+                Component::RootDir | Component::CurDir => continue,
+                Component::Normal(name) => {
+                    let name = CString::new(name.as_bytes()).map_err(|_| libc::EINVAL)?;
+                    entry = self.lookup(entry.nodeid, &name)?;
+                    if entry.nodeid == 0 {
+                        return Err(libc::ENOENT);
+                    }
+                }
+                Component::ParentDir | Component::Prefix(..) => return Err(libc::EINVAL),
+            }
+        }
+        Ok(entry)
+    }
+
+    pub fn forget(&mut self, id: NodeId, nlookup: u64) {
+        if let Some(count) = self.lookups.get_mut(&id) {
+            *count = count.saturating_sub(nlookup);
+            if *count == 0 {
+                self.lookups.remove(&id);
+            }
+        }
+        self.forget_inner(id, nlookup);
+    }
+
+    /// Forget all lookups counted by the harness.
+    pub fn forget_all(&mut self) {
+        for (id, nlookup) in mem::take(&mut self.lookups) {
+            self.forget_inner(id, nlookup);
+        }
+    }
+
+    fn forget_inner(&mut self, id: NodeId, nlookup: u64) {
+        let _ = self.call(
+            "forget",
+            id,
+            None,
+            |ops| dispatch::forget(ops, id, nlookup),
+            expect!(Reply::None => ()),
+        );
+    }
+
+    pub fn getattr(&mut self, id: NodeId, fh: Option<u64>) -> OperationResult<stat> {
+        self.call(
+            "getattr",
+            id,
+            fh,
+            |ops| dispatch::getattr(ops, id, fh),
+            expect!(Reply::Attr(attr, _) => attr),
+        )
+    }
+
+    /// Set the attributes, returning the updated ones.
+    pub fn setattr(
+        &mut self,
+        id: NodeId,
+        attrs: &SetAttr,
+        fh: Option<u64>,
+    ) -> OperationResult<stat> {
+        let attrs = SetAttrs {
+            attr: &attrs.attr,
+            to_set: attrs.to_set,
+        };
+        self.call(
+            "setattr",
+            id,
+            fh,
+            |ops| dispatch::setattr(ops, id, &attrs, fh),
+            expect!(Reply::Attr(attr, _) => attr),
+        )
+    }
+
+    pub fn readlink(&mut self, id: NodeId) -> OperationResult<CString> {
+        self.call(
+            "readlink",
+            id,
+            None,
+            |ops| dispatch::readlink(ops, id),
+            expect!(Reply::Readlink(link) => link),
+        )
+    }
+
+    pub fn mknod(
+        &mut self,
+        parent: NodeId,
+        name: &CStr,
+        mode: mode_t,
+        rdev: dev_t,
+    ) -> OperationResult<Entry> {
+        let res = self.call(
+            "mknod",
+            parent,
+            None,
+            |ops| dispatch::mknod(ops, parent, name, mode, rdev),
+            expect!(Reply::Entry(entry) => entry),
+        );
+        self.count_entry(res)
+    }
+
+    pub fn mkdir(&mut self, parent: NodeId, name: &CStr, mode: mode_t) -> OperationResult<Entry> {
+        let res = self.call(
+            "mkdir",
+            parent,
+            None,
+            |ops| dispatch::mkdir(ops, parent, name, mode),
+            expect!(Reply::Entry(entry) => entry),
+        );
+        self.count_entry(res)
+    }
+
+    pub fn unlink(&mut self, parent: NodeId, name: &CStr) -> OperationResult<()> {
+        self.call(
+            "unlink",
+            parent,
+            None,
+            |ops| dispatch::unlink(ops, parent, name),
+            expect!(Reply::Ok => ()),
+        )
+    }
+
+    pub fn rmdir(&mut self, parent: NodeId, name: &CStr) -> OperationResult<()> {
+        self.call(
+            "rmdir",
+            parent,
+            None,
+            |ops| dispatch::rmdir(ops, parent, name),
+            expect!(Reply::Ok => ()),
+        )
+    }
+
+    pub fn symlink(&mut self, link: &CStr, parent: NodeId, name: &CStr) -> OperationResult<Entry> {
+        let res = self.call(
+            "symlink",
+            parent,
+            None,
+            |ops| dispatch::symlink(ops, link, parent, name),
+            expect!(Reply::Entry(entry) => entry),
+        );
+        self.count_entry(res)
+    }
+
+    pub fn rename(
+        &mut self,
+        parent: NodeId,
+        name: &CStr,
+        newparent: NodeId,
+        newname: &CStr,
+        flags: RenameFlags,
+    ) -> OperationResult<()> {
+        self.call(
+            "rename",
+            parent,
+            None,
+            |ops| dispatch::rename(ops, parent, name, newparent, newname, flags),
+            expect!(Reply::Ok => ()),
+        )
+    }
+
+    pub fn link(
+        &mut self,
+        id: NodeId,
+        newparent: NodeId,
+        newname: &CStr,
+    ) -> OperationResult<Entry> {
+        let res = self.call(
+            "link",
+            id,
+            None,
+            |ops| dispatch::link(ops, id, newparent, newname),
+            expect!(Reply::Entry(entry) => entry),
+        );
+        self.count_entry(res)
+    }
+
+    /// Open the file with the flags, returning the file handle.
+    pub fn open(&mut self, id: NodeId, flags: c_int) -> OperationResult<u64> {
+        let mut fi = FileInfo::new(flags, 0);
+        self.call(
+            "open",
+            id,
+            None,
+            |ops| dispatch::open(ops, id, fi.as_mut()),
+            expect!(Reply::Open(fh) => fh),
+        )
+    }
+
+    /// Create and open the file, returning the entry and the file handle.
+    pub fn create(
+        &mut self,
+        parent: NodeId,
+        name: &CStr,
+        mode: mode_t,
+        flags: c_int,
+    ) -> OperationResult<(Entry, u64)> {
+        let mut fi = FileInfo::new(flags, 0);
+        let (entry, fh) = self.call(
+            "create",
+            parent,
+            None,
+            |ops| dispatch::create(ops, parent, name, mode, fi.as_mut()),
+            expect!(Reply::Create(entry, fh) => (entry, fh)),
+        )?;
+        self.count(&entry);
+        Ok((entry, fh))
+    }
+
+    /// Read at most `size` bytes at the offset.
+    pub fn read(
+        &mut self,
+        id: NodeId,
+        fh: u64,
+        offset: off_t,
+        size: usize,
+    ) -> OperationResult<Vec<u8>> {
+        let mut fi = FileInfo::new(0, fh);
+        self.call(
+            "read",
+            id,
+            Some(fh),
+            |ops| dispatch::read(ops, id, offset, size, fi.as_mut()),
+            expect!(Reply::Buf(data) => data.into_owned()),
+        )
+    }
+
+    /// Write the data at the offset, returning the number of bytes written.
+    pub fn write(
+        &mut self,
+        id: NodeId,
+        fh: u64,
+        offset: off_t,
+        data: &[u8],
+    ) -> OperationResult<usize> {
+        let mut fi = FileInfo::new(0, fh);
+        self.call(
+            "write",
+            id,
+            Some(fh),
+            |ops| dispatch::write(ops, id, data, offset, fi.as_mut()),
+            expect!(Reply::Write(count) => count),
+        )
+    }
+
+    pub fn flush(&mut self, id: NodeId, fh: u64) -> OperationResult<()> {
+        let mut fi = FileInfo::new(0, fh);
+        self.call(
+            "flush",
+            id,
+            Some(fh),
+            |ops| dispatch::flush(ops, id, fi.as_mut()),
+            expect!(Reply::Ok => ()),
+        )
+    }
+
+    pub fn release(&mut self, id: NodeId, fh: u64) -> OperationResult<()> {
+        let mut fi = FileInfo::new(0, fh);
+        self.call(
+            "release",
+            id,
+            Some(fh),
+            |ops| dispatch::release(ops, id, fi.as_mut()),
+            expect!(Reply::Ok => ()),
+        )
+    }
+
+    /// Open the directory, returning the file handle.
+    pub fn opendir(&mut self, id: NodeId) -> OperationResult<u64> {
+        let mut fi = FileInfo::new(libc::O_RDONLY | libc::O_DIRECTORY, 0);
+        self.call(
+            "opendir",
+            id,
+            None,
+            |ops| dispatch::opendir(ops, id, fi.as_mut()),
+            expect!(Reply::Open(fh) => fh),
+        )
+    }
+
+    /// Read the directory entries after the offset into a buffer of
+    /// `size` bytes, and decode them.
+    pub fn readdir(
+        &mut self,
+        id: NodeId,
+        fh: u64,
+        offset: off_t,
+        size: usize,
+    ) -> OperationResult<Vec<DirEntry>> {
+        self.call(
+            "readdir",
+            id,
+            Some(fh),
+            |ops| dispatch::readdir(ops, ptr::null_mut(), id, size, offset, fh),
+            expect!(Reply::Buf(data) => decode_dirents(&data)),
+        )
+    }
+
+    pub fn releasedir(&mut self, id: NodeId, fh: u64) -> OperationResult<()> {
+        self.call(
+            "releasedir",
+            id,
+            Some(fh),
+            |ops| dispatch::releasedir(ops, id, fh),
+            expect!(Reply::Ok => ()),
+        )
+    }
+
+    /// Read all entries of the directory, from `opendir` to `releasedir`.
+    pub fn read_dir(&mut self, id: NodeId) -> OperationResult<Vec<DirEntry>> {
+        let fh = self.opendir(id)?;
+        let mut entries = vec![];
+        let res = loop {
+            let offset = entries.last().map_or(0, |entry: &DirEntry| entry.offset);
+            match self.readdir(id, fh, offset, READDIR_SIZE) {
+                Ok(ref chunk) if chunk.is_empty() => break Ok(()),
+                Ok(chunk) => entries.extend(chunk),
+                Err(errno) => break Err(errno),
+            }
+        };
+        self.releasedir(id, fh)?;
+        res.map(|()| entries)
+    }
+
+    pub fn statfs(&mut self, id: NodeId) -> OperationResult<statvfs> {
+        self.call(
+            "statfs",
+            id,
+            None,
+            |ops| dispatch::statfs(ops, id),
+            expect!(Reply::Statfs(st) => st),
+        )
+    }
+
+    pub fn setxattr(
+        &mut self,
+        id: NodeId,
+        name: &CStr,
+        value: &[u8],
+        flags: XAttrFlags,
+    ) -> OperationResult<()> {
+        self.call(
+            "setxattr",
+            id,
+            None,
+            |ops| dispatch::setxattr(ops, id, name, value, flags),
+            expect!(Reply::Ok => ()),
+        )
+    }
+
+    /// Get the value of the extended attribute into a buffer of `size`
+    /// bytes.
+    ///
+    /// As with `getxattr(2)`, the size of the value is returned if `size`
+    /// is zero.
+    pub fn getxattr(
+        &mut self,
+        id: NodeId,
+        name: &CStr,
+        size: usize,
+    ) -> OperationResult<XAttrReply<'static>> {
+        self.call(
+            "getxattr",
+            id,
+            None,
+            |ops| dispatch::getxattr(ops, id, name, size),
+            xattr,
+        )
+    }
+
+    /// List the names of the extended attributes into a buffer of `size`
+    /// bytes.
+    pub fn listxattr(&mut self, id: NodeId, size: usize) -> OperationResult<XAttrReply<'static>> {
+        self.call(
+            "listxattr",
+            id,
+            None,
+            |ops| dispatch::listxattr(ops, id, size),
+            xattr,
+        )
+    }
+
+    pub fn removexattr(&mut self, id: NodeId, name: &CStr) -> OperationResult<()> {
+        self.call(
+            "removexattr",
+            id,
+            None,
+            |ops| dispatch::removexattr(ops, id, name),
+            expect!(Reply::Ok => ()),
+        )
+    }
+
+    pub fn access(&mut self, id: NodeId, mask: c_int) -> OperationResult<()> {
+        self.call(
+            "access",
+            id,
+            None,
+            |ops| dispatch::access(ops, id, mask),
+            expect!(Reply::Ok => ()),
+        )
+    }
+}
+
+/// The attributes to be set by `Harness::setattr`.
+#[derive(Clone, Copy)]
+pub struct SetAttr {
+    attr: stat,
+    to_set: c_int,
+}
+
+impl Default for SetAttr {
+    fn default() -> Self {
+        Self {
+            attr: unsafe { mem::zeroed() },
+            to_set: 0,
+        }
+    }
+}
+
+impl SetAttr {
+    /// Create an empty set of the attributes.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn mode(mut self, mode: mode_t) -> Self {
+        self.attr.st_mode = mode;
+        self.to_set |= FUSE_SET_ATTR_MODE;
+        self
+    }
+
+    pub fn uid(mut self, uid: uid_t) -> Self {
+        self.attr.st_uid = uid;
+        self.to_set |= FUSE_SET_ATTR_UID;
+        self
+    }
+
+    pub fn gid(mut self, gid: gid_t) -> Self {
+        self.attr.st_gid = gid;
+        self.to_set |= FUSE_SET_ATTR_GID;
+        self
+    }
+
+    pub fn size(mut self, size: off_t) -> Self {
+        self.attr.st_size = size;
+        self.to_set |= FUSE_SET_ATTR_SIZE;
+        self
+    }
+
+    /// Set the access time. `UTIME_NOW` in `tv_nsec` means the current time.
+    pub fn atime(mut self, ts: timespec) -> Self {
+        if ts.tv_nsec == libc::UTIME_NOW {
+            self.to_set |= FUSE_SET_ATTR_ATIME_NOW;
+        } else {
+            self.attr.st_atime = ts.tv_sec;
+            self.attr.st_atime_nsec = ts.tv_nsec;
+            self.to_set |= FUSE_SET_ATTR_ATIME;
+        }
+        self
+    }
+
+    /// Set the modification time. `UTIME_NOW` in `tv_nsec` means the
+    /// current time.
+    pub fn mtime(mut self, ts: timespec) -> Self {
+        if ts.tv_nsec == libc::UTIME_NOW {
+            self.to_set |= FUSE_SET_ATTR_MTIME_NOW;
+        } else {
+            self.attr.st_mtime = ts.tv_sec;
+            self.attr.st_mtime_nsec = ts.tv_nsec;
+            self.to_set |= FUSE_SET_ATTR_MTIME;
+        }
+        self
+    }
+}
+
+/// A filesystem mounted on a temporary directory by `mount_tmp`.
+///
+/// The event loop runs in a background thread until the filesystem is
+/// unmounted. Dropping the value unmounts it and removes the directory,
+/// ignoring the errors.
+pub struct MountedFs<T: Operations> {
+    mountpoint: PathBuf,
+    thread: Option<JoinHandle<io::Result<T>>>,
+}
+
+/// Mount the filesystem on a new temporary directory with the default
+/// options.
+pub fn mount_tmp<T>(ops: T) -> io::Result<MountedFs<T>>
+where
+    T: Operations + Send + 'static,
+{
+    mount_tmp_with(Builder::new("testing"), ops)
+}
+
+/// Mount the filesystem on a new temporary directory, building the
+/// session with `builder`.
+pub fn mount_tmp_with<T>(builder: Builder, ops: T) -> io::Result<MountedFs<T>>
+where
+    T: Operations + Send + 'static,
+{
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+    let mountpoint = env::temp_dir().join(format!(
+        "libfuse-{}-{}",
+        process::id(),
+        COUNT.fetch_add(1, Ordering::SeqCst)
+    ));
+    fs::create_dir(&mountpoint)?;
+
+    let (tx, rx) = mpsc::channel();
+    let thread = {
+        let mountpoint = mountpoint.clone();
+        thread::spawn(move || {
+            let mut session = match builder.build(ops).and_then(|mut session| {
+                session.mount(&mountpoint)?;
+                Ok(session)
+            }) {
+                Ok(session) => session,
+                Err(err) => {
+                    let _ = tx.send(Err(err.into()));
+                    return Err(io::Error::new(io::ErrorKind::Other, "failed to mount"));
+                }
+            };
+            let _ = tx.send(Ok(()));
+            session.run_loop()?;
+            Ok(session.into_inner())
+        })
+    };
+
+    let res = rx.recv().unwrap_or_else(|_| {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "the session thread panicked",
+        ))
+    });
+    if let Err(err) = res {
+        let _ = thread.join();
+        let _ = fs::remove_dir(&mountpoint);
+        return Err(err);
+    }
+
+    Ok(MountedFs {
+        mountpoint,
+        thread: Some(thread),
+    })
+}
+
+impl<T: Operations> MountedFs<T> {
+    /// Returns the path of the mountpoint.
+    pub fn path(&self) -> &Path {
+        &self.mountpoint
+    }
+
+    /// Unmount the filesystem and wait for the event loop to exit,
+    /// returning the filesystem.
+    pub fn unmount(mut self) -> io::Result<T> {
+        self.unmount_inner()
+    }
+
+    fn unmount_inner(&mut self) -> io::Result<T> {
+        let thread = match self.thread.take() {
+            Some(thread) => thread,
+            None => return Err(io::Error::new(io::ErrorKind::Other, "already unmounted")),
+        };
+
+        // The event loop exits when the connection is closed by unmounting.
+        let res = if unsafe { libc::geteuid() } == 0 {
+            let path = CString::new(self.mountpoint.as_os_str().as_bytes())?;
+            match unsafe { libc::umount(path.as_ptr()) } {
+                0 => Ok(()),
+                _ => Err(io::Error::last_os_error()),
+            }
+        } else {
+            let status = Command::new("fusermount3")
+                .arg("-u")
+                .arg(&self.mountpoint)
+                .status()?;
+            if status.success() {
+                Ok(())
+            } else {
+                Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("fusermount3 exited with {}", status),
+                ))
+            }
+        };
+        if let Err(err) = res {
+            self.thread = Some(thread);
+            return Err(err);
+        }
+
+        let ops = thread.join().unwrap_or_else(|_| {
+            Err(io::Error::new(
+                io::ErrorKind::Other,
+                "the session thread panicked",
+            ))
+        });
+        let _ = fs::remove_dir(&self.mountpoint);
+        ops
+    }
+}
+
+impl<T: Operations> Drop for MountedFs<T> {
+    fn drop(&mut self) {
+        if self.thread.is_some() {
+            let _ = self.unmount_inner();
+        }
+    }
+}
+
+/// A `fuse_file_info` allocated outside of libfuse.
+struct FileInfo(NonNull<fuse_file_info>);
+
+impl FileInfo {
+    fn new(flags: c_int, fh: u64) -> Self {
+        let fi = NonNull::new(unsafe { fuse_file_info_new() }).expect("no memory space");
+        unsafe {
+            fuse_file_info_set_flags(fi.as_ptr(), flags);
+            fuse_file_info_set_fh(fi.as_ptr(), fh);
+        }
+        FileInfo(fi)
+    }
+
+    fn as_mut(&mut self) -> &mut fuse_file_info {
+        unsafe { self.0.as_mut() }
+    }
+}
+
+impl Drop for FileInfo {
+    fn drop(&mut self) {
+        unsafe {
+            libc::free(self.0.as_ptr() as *mut _);
+        }
+    }
+}
+
+/// Convert the reply of `getxattr` or `listxattr`.
+fn xattr(reply: Reply<'_>) -> XAttrReply<'static> {
+    match reply {
+        Reply::Xattr(size) => XAttrReply::Size(size),
+        Reply::Buf(data) => XAttrReply::Data(Cow::Owned(data.into_owned())),
+        _ => unreachable!("unexpected reply"),
+    }
+}
+
+/// Decode the entries in the layout of `struct fuse_dirent`, i.e.
+/// `ino: u64`, `off: u64`, `namelen: u32`, `type: u32` and the name,
+/// padded to 8 bytes.
+pub(crate) fn decode_dirents(mut buf: &[u8]) -> Vec<DirEntry> {
+    const HEADER_LEN: usize = 24;
+
+    let u64_at = |buf: &[u8], pos: usize| {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&buf[pos..pos + 8]);
+        u64::from_ne_bytes(bytes)
+    };
+    let u32_at = |buf: &[u8], pos: usize| {
+        let mut bytes = [0u8; 4];
+        bytes.copy_from_slice(&buf[pos..pos + 4]);
+        u32::from_ne_bytes(bytes)
+    };
+
+    let mut entries = vec![];
+    while buf.len() >= HEADER_LEN {
+        let namelen = u32_at(buf, 16) as usize;
+        let name = &buf[HEADER_LEN..HEADER_LEN + namelen];
+        entries.push(DirEntry {
+            ino: u64_at(buf, 0),
+            offset: u64_at(buf, 8) as off_t,
+            kind: FileType::from_mode((u32_at(buf, 20) as mode_t) << 12),
+            name: CString::new(name).expect("the name contains a NUL"),
+        });
+        let len = (HEADER_LEN + namelen + 7) & !7;
+        buf = &buf[cmp::min(len, buf.len())..];
+    }
+    entries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file::{OpenOptions, ReadOptions};
+
+    /// A filesystem with a single file of ten bytes and an attribute.
+    #[derive(Default)]
+    struct File {
+        mode: mode_t,
+        opened: u64,
+    }
+
+    impl Operations for File {
+        fn getattr(&mut self, _: NodeId, _: Option<u64>) -> OperationResult<(stat, f64)> {
+            let mut attr: stat = unsafe { mem::zeroed() };
+            attr.st_mode = libc::S_IFREG | self.mode;
+            Ok((attr, 0.0))
+        }
+
+        fn setattr(
+            &mut self,
+            id: NodeId,
+            attrs: &SetAttrs<'_>,
+            fh: Option<u64>,
+        ) -> OperationResult<(stat, f64)> {
+            if let Some(mode) = attrs.mode() {
+                self.mode = mode;
+            }
+            self.getattr(id, fh)
+        }
+
+        fn open(&mut self, _: NodeId, _: &mut OpenOptions<'_>) -> OperationResult<u64> {
+            self.opened += 1;
+            Ok(self.opened)
+        }
+
+        fn read(
+            &mut self,
+            _: NodeId,
+            _: off_t,
+            _: usize,
+            _: &mut ReadOptions<'_>,
+            fh: u64,
+        ) -> OperationResult<Cow<'_, [u8]>> {
+            if fh != self.opened {
+                return Err(libc::EBADF);
+            }
+            Ok(Cow::Borrowed(b"0123456789"))
+        }
+
+        fn getxattr(
+            &mut self,
+            _: NodeId,
+            name: &CStr,
+            _: usize,
+        ) -> OperationResult<XAttrReply<'_>> {
+            match name.to_bytes() {
+                b"user.test" => Ok(XAttrReply::Data(Cow::Borrowed(b"value"))),
+                _ => Err(libc::ENODATA),
+            }
+        }
+    }
+
+    fn name(name: &str) -> CString {
+        CString::new(name).unwrap()
+    }
+
+    #[test]
+    fn read_is_truncated() {
+        let mut harness = Harness::new(File::default());
+        let fh = harness.open(ROOT_NODEID, libc::O_RDONLY).unwrap();
+        assert_eq!(harness.read(ROOT_NODEID, fh, 0, 4).unwrap(), b"0123");
+        assert_eq!(
+            harness.read(ROOT_NODEID, fh + 1, 0, 4).err(),
+            Some(libc::EBADF)
+        );
+    }
+
+    #[test]
+    fn getxattr_sizing() {
+        let mut harness = Harness::new(File::default());
+        let attr = name("user.test");
+        match harness.getxattr(ROOT_NODEID, &attr, 16).unwrap() {
+            XAttrReply::Data(data) => assert_eq!(&*data, b"value"),
+            XAttrReply::Size(..) => panic!("the data should be replied"),
+        }
+        assert_eq!(
+            harness.getxattr(ROOT_NODEID, &attr, 2).err(),
+            Some(libc::ERANGE)
+        );
+        assert_eq!(
+            harness.getxattr(ROOT_NODEID, &name("user.none"), 16).err(),
+            Some(libc::ENODATA)
+        );
+    }
+
+    #[test]
+    fn getxattr_size_query() {
+        let mut harness = Harness::new(File::default());
+        match harness
+            .getxattr(ROOT_NODEID, &name("user.test"), 0)
+            .unwrap()
+        {
+            XAttrReply::Size(size) => assert_eq!(size, 5),
+            XAttrReply::Data(..) => panic!("the size should be replied"),
+        }
+    }
+
+    #[test]
+    fn setattr_and_stats() {
+        let mut harness = Harness::new(File::default());
+        let attr = harness
+            .setattr(ROOT_NODEID, &SetAttr::new().mode(0o600), None)
+            .unwrap();
+        assert_eq!(attr.st_mode, libc::S_IFREG | 0o600);
+        assert_eq!(harness.access(ROOT_NODEID, libc::R_OK), Err(libc::ENOSYS));
+
+        let fh = harness.open(ROOT_NODEID, libc::O_RDONLY).unwrap();
+        harness.read(ROOT_NODEID, fh, 0, 4).unwrap();
+
+        let stats = harness.stats().snapshot();
+        assert_eq!(stats.get("setattr").map(|stats| stats.count()), Some(1));
+        assert_eq!(
+            stats.get("access").map(|stats| stats.errors().clone()),
+            Some(vec![(libc::ENOSYS, 1)].into_iter().collect())
+        );
+        assert_eq!(stats.bytes_read(), 4);
+    }
+}
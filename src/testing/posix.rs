@@ -0,0 +1,528 @@
+//! Checks of the POSIX behavior of a mounted filesystem.
+//!
+//! The checks operate on the files through the kernel, and are intended
+//! to be run against a filesystem mounted by `mount_tmp`, e.g.
+//! `Suite::new().skip("xattr").run(mounted.path())`. The checks for the
+//! features the filesystem does not support are expected to be skipped.
+
+use libc::{c_int, c_void, uid_t};
+use std::{
+    collections::HashSet,
+    ffi::CString,
+    fmt,
+    fs::{self, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{MetadataExt, PermissionsExt},
+    },
+    path::Path,
+};
+
+/// A check, which is given an empty directory to work in.
+pub type Check = fn(&Path) -> io::Result<()>;
+
+/// The bundled checks and their names.
+pub const CHECKS: &[(&str, Check)] = &[
+    ("create_excl", create_excl),
+    ("mkdir_exists", mkdir_exists),
+    ("rename_replace", rename_replace),
+    ("rename_dir_nonempty", rename_dir_nonempty),
+    ("rename_file_over_dir", rename_file_over_dir),
+    ("unlink_open", unlink_open),
+    ("unlink_dir", unlink_dir),
+    ("rmdir_nonempty", rmdir_nonempty),
+    ("hard_link", hard_link),
+    ("symlink", symlink),
+    ("mode_bits", mode_bits),
+    ("sticky_bit", sticky_bit),
+    ("append", append),
+    ("truncate", truncate),
+    ("xattr", xattr),
+    ("readdir", readdir),
+];
+
+/// A set of the checks to be run.
+#[derive(Debug, Default)]
+pub struct Suite {
+    skip: HashSet<&'static str>,
+}
+
+impl Suite {
+    /// Create a suite running all bundled checks.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Skip the check, e.g. for the features not supported by the
+    /// filesystem.
+    ///
+    /// # Panics
+    /// Panics if no check has the name.
+    pub fn skip(mut self, name: &str) -> Self {
+        let name = CHECKS
+            .iter()
+            .map(|&(check, _)| check)
+            .find(|&check| check == name)
+            .unwrap_or_else(|| panic!("unknown check: {}", name));
+        self.skip.insert(name);
+        self
+    }
+
+    /// Run the checks in the directory.
+    ///
+    /// Each check is run in a new subdirectory named after it, which is
+    /// removed afterwards.
+    pub fn run(&self, root: &Path) -> Report {
+        let results = CHECKS
+            .iter()
+            .map(|&(name, check)| {
+                if self.skip.contains(name) {
+                    return (name, Outcome::Skipped);
+                }
+                let dir = root.join(name);
+                let res = fs::create_dir(&dir).and_then(|()| check(&dir));
+                let _ = fs::remove_dir_all(&dir);
+                match res {
+                    Ok(()) => (name, Outcome::Passed),
+                    Err(ref err) if Skip::is_skip(err) => (name, Outcome::Skipped),
+                    Err(err) => (name, Outcome::Failed(err)),
+                }
+            })
+            .collect();
+        Report { results }
+    }
+}
+
+/// The outcome of a check.
+#[derive(Debug)]
+pub enum Outcome {
+    Passed,
+    Failed(io::Error),
+    Skipped,
+}
+
+/// The outcomes of the checks run by `Suite::run`.
+#[derive(Debug)]
+pub struct Report {
+    results: Vec<(&'static str, Outcome)>,
+}
+
+impl Report {
+    /// Returns whether no check has failed.
+    pub fn is_success(&self) -> bool {
+        self.failures().next().is_none()
+    }
+
+    /// Returns an iterator over the names of the checks and their outcomes.
+    pub fn iter(&self) -> impl Iterator<Item = (&'static str, &Outcome)> {
+        self.results.iter().map(|(name, outcome)| (*name, outcome))
+    }
+
+    /// Returns an iterator over the failed checks and their errors.
+    pub fn failures(&self) -> impl Iterator<Item = (&'static str, &io::Error)> {
+        self.iter().filter_map(|(name, outcome)| match outcome {
+            Outcome::Failed(err) => Some((name, err)),
+            _ => None,
+        })
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (name, outcome) in self.iter() {
+            match outcome {
+                Outcome::Passed => writeln!(f, "{} ... ok", name)?,
+                Outcome::Failed(err) => writeln!(f, "{} ... FAILED: {}", name, err)?,
+                Outcome::Skipped => writeln!(f, "{} ... skipped", name)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The error returned by a check which cannot be run in the environment,
+/// which is reported as skipped rather than failed.
+#[derive(Debug)]
+struct Skip(&'static str);
+
+impl Skip {
+    fn error(reason: &'static str) -> io::Error {
+        io::Error::new(io::ErrorKind::Other, Skip(reason))
+    }
+
+    fn is_skip(err: &io::Error) -> bool {
+        err.get_ref().map_or(false, |err| err.is::<Skip>())
+    }
+}
+
+impl fmt::Display for Skip {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+impl std::error::Error for Skip {}
+
+macro_rules! ensure {
+    ($cond:expr, $($arg:tt)+) => {
+        if !$cond {
+            return Err(io::Error::new(io::ErrorKind::Other, format!($($arg)+)));
+        }
+    };
+}
+
+/// Ensure that the operation has failed with one of the error numbers.
+fn expect_errno<T>(res: io::Result<T>, errnos: &[c_int], what: &str) -> io::Result<()> {
+    match res {
+        Ok(..) => Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("{} unexpectedly succeeded", what),
+        )),
+        Err(ref err) if errnos.iter().any(|&e| err.raw_os_error() == Some(e)) => Ok(()),
+        Err(err) => Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("{}: unexpected error: {}", what, err),
+        )),
+    }
+}
+
+fn ensure_not_found(path: &Path) -> io::Result<()> {
+    expect_errno(
+        fs::symlink_metadata(path),
+        &[libc::ENOENT],
+        &format!("lookup of {}", path.display()),
+    )
+}
+
+fn create_excl(dir: &Path) -> io::Result<()> {
+    let path = dir.join("file");
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    options.open(&path)?;
+    expect_errno(
+        options.open(&path),
+        &[libc::EEXIST],
+        "O_EXCL on an existing file",
+    )
+}
+
+fn mkdir_exists(dir: &Path) -> io::Result<()> {
+    let path = dir.join("dir");
+    fs::create_dir(&path)?;
+    expect_errno(
+        fs::create_dir(&path),
+        &[libc::EEXIST],
+        "mkdir on an existing directory",
+    )
+}
+
+fn rename_replace(dir: &Path) -> io::Result<()> {
+    let (from, to) = (dir.join("from"), dir.join("to"));
+    fs::write(&from, b"from")?;
+    fs::write(&to, b"to")?;
+    let ino = fs::metadata(&from)?.ino();
+
+    fs::rename(&from, &to)?;
+    ensure_not_found(&from)?;
+    ensure!(
+        fs::read(&to)? == b"from",
+        "the target has not been replaced"
+    );
+    ensure!(
+        fs::metadata(&to)?.ino() == ino,
+        "the inode number has changed"
+    );
+    Ok(())
+}
+
+fn rename_dir_nonempty(dir: &Path) -> io::Result<()> {
+    let (from, to) = (dir.join("from"), dir.join("to"));
+    fs::create_dir(&from)?;
+    fs::create_dir(&to)?;
+    fs::write(to.join("file"), b"")?;
+    expect_errno(
+        fs::rename(&from, &to),
+        &[libc::ENOTEMPTY, libc::EEXIST],
+        "rename onto a non-empty directory",
+    )
+}
+
+fn rename_file_over_dir(dir: &Path) -> io::Result<()> {
+    let (from, to) = (dir.join("from"), dir.join("to"));
+    fs::write(&from, b"")?;
+    fs::create_dir(&to)?;
+    expect_errno(
+        fs::rename(&from, &to),
+        &[libc::EISDIR],
+        "rename of a file onto a directory",
+    )
+}
+
+fn unlink_open(dir: &Path) -> io::Result<()> {
+    let path = dir.join("file");
+    fs::write(&path, b"content")?;
+    let mut file = fs::File::open(&path)?;
+
+    fs::remove_file(&path)?;
+    ensure_not_found(&path)?;
+
+    let mut content = vec![];
+    file.read_to_end(&mut content)?;
+    ensure!(
+        content == b"content",
+        "the unlinked file is not readable through the open file"
+    );
+    Ok(())
+}
+
+fn unlink_dir(dir: &Path) -> io::Result<()> {
+    let path = dir.join("dir");
+    fs::create_dir(&path)?;
+    expect_errno(
+        fs::remove_file(&path),
+        &[libc::EISDIR, libc::EPERM],
+        "unlink of a directory",
+    )
+}
+
+fn rmdir_nonempty(dir: &Path) -> io::Result<()> {
+    let path = dir.join("dir");
+    fs::create_dir(&path)?;
+    fs::write(path.join("file"), b"")?;
+    expect_errno(
+        fs::remove_dir(&path),
+        &[libc::ENOTEMPTY, libc::EEXIST],
+        "rmdir of a non-empty directory",
+    )
+}
+
+fn hard_link(dir: &Path) -> io::Result<()> {
+    let (orig, link) = (dir.join("orig"), dir.join("link"));
+    fs::write(&orig, b"orig")?;
+    fs::hard_link(&orig, &link)?;
+
+    let (orig_meta, link_meta) = (fs::metadata(&orig)?, fs::metadata(&link)?);
+    ensure!(
+        orig_meta.ino() == link_meta.ino(),
+        "the link has a different inode number"
+    );
+    ensure!(
+        link_meta.nlink() == 2,
+        "st_nlink is {}, expected 2",
+        link_meta.nlink()
+    );
+
+    fs::write(&link, b"link")?;
+    ensure!(
+        fs::read(&orig)? == b"link",
+        "the write through the link is not visible"
+    );
+
+    fs::remove_file(&orig)?;
+    let nlink = fs::metadata(&link)?.nlink();
+    ensure!(nlink == 1, "st_nlink is {} after unlink, expected 1", nlink);
+    Ok(())
+}
+
+fn symlink(dir: &Path) -> io::Result<()> {
+    let path = dir.join("link");
+    std::os::unix::fs::symlink("target", &path)?;
+    ensure!(
+        fs::symlink_metadata(&path)?.file_type().is_symlink(),
+        "the link is not a symbolic link"
+    );
+    ensure!(
+        fs::read_link(&path)? == Path::new("target"),
+        "readlink returned a different target"
+    );
+    Ok(())
+}
+
+fn mode_bits(dir: &Path) -> io::Result<()> {
+    let path = dir.join("dir");
+    fs::create_dir(&path)?;
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o1777))?;
+    let mode = fs::metadata(&path)?.mode() & 0o7777;
+    ensure!(mode == 0o1777, "the mode is {:o}, expected 1777", mode);
+    Ok(())
+}
+
+/// The UID of `nobody`, used as a user other than the owner.
+const NOBODY: uid_t = 65534;
+
+/// Switches the filesystem UID of the current thread until dropped.
+///
+/// Unlike the effective UID, the filesystem UID is per thread, so the
+/// event loop running in the same process is not affected.
+struct FsUid(uid_t);
+
+impl FsUid {
+    fn switch(uid: uid_t) -> Self {
+        FsUid(unsafe { libc::setfsuid(uid) } as uid_t)
+    }
+}
+
+impl Drop for FsUid {
+    fn drop(&mut self) {
+        unsafe {
+            libc::setfsuid(self.0);
+        }
+    }
+}
+
+/// Ensure that a user other than the owners cannot remove nor rename the
+/// files in a sticky directory.
+///
+/// This check requires the root privilege to act as another user, and is
+/// skipped without it.
+fn sticky_bit(dir: &Path) -> io::Result<()> {
+    if unsafe { libc::geteuid() } != 0 {
+        return Err(Skip::error("the check requires the root privilege"));
+    }
+    let sticky = dir.join("sticky");
+    fs::create_dir(&sticky)?;
+    fs::set_permissions(&sticky, fs::Permissions::from_mode(0o1777))?;
+    let path = sticky.join("file");
+    fs::write(&path, b"")?;
+
+    {
+        let _fsuid = FsUid::switch(NOBODY);
+        expect_errno(
+            fs::remove_file(&path),
+            &[libc::EPERM, libc::EACCES],
+            "unlink by a user other than the owners",
+        )?;
+        expect_errno(
+            fs::rename(&path, sticky.join("renamed")),
+            &[libc::EPERM, libc::EACCES],
+            "rename by a user other than the owners",
+        )?;
+    }
+
+    fs::remove_file(&path)?;
+    Ok(())
+}
+
+fn append(dir: &Path) -> io::Result<()> {
+    let path = dir.join("file");
+    fs::write(&path, b"abc")?;
+
+    let mut file = OpenOptions::new().append(true).open(&path)?;
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(b"def")?;
+    drop(file);
+
+    ensure!(
+        fs::read(&path)? == b"abcdef",
+        "O_APPEND did not write at the end of the file"
+    );
+    Ok(())
+}
+
+fn truncate(dir: &Path) -> io::Result<()> {
+    let path = dir.join("file");
+    fs::write(&path, b"hello world")?;
+
+    let file = OpenOptions::new().write(true).open(&path)?;
+    file.set_len(5)?;
+    ensure!(fs::read(&path)? == b"hello", "shrinking truncate failed");
+    file.set_len(8)?;
+    ensure!(
+        fs::read(&path)? == b"hello\0\0\0",
+        "extending truncate did not fill with zeros"
+    );
+    drop(file);
+
+    OpenOptions::new().write(true).truncate(true).open(&path)?;
+    let len = fs::metadata(&path)?.len();
+    ensure!(len == 0, "the size is {} after O_TRUNC", len);
+    Ok(())
+}
+
+fn xattr(dir: &Path) -> io::Result<()> {
+    let path = dir.join("file");
+    fs::write(&path, b"")?;
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let name = CString::new("user.libfuse")?;
+
+    let cvt = |res: isize| {
+        if res < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(res as usize)
+        }
+    };
+    let get = || {
+        let mut buf = vec![0u8; 256];
+        let len = cvt(unsafe {
+            libc::getxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                buf.as_mut_ptr() as *mut c_void,
+                buf.len(),
+            )
+        })?;
+        buf.truncate(len);
+        Ok(buf)
+    };
+
+    cvt(unsafe {
+        libc::setxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            b"value".as_ptr() as *const c_void,
+            5,
+            0,
+        )
+    } as isize)?;
+    ensure!(get()? == b"value", "getxattr returned a different value");
+
+    let mut list = vec![0u8; 1024];
+    let len =
+        cvt(unsafe { libc::listxattr(path.as_ptr(), list.as_mut_ptr() as *mut _, list.len()) })?;
+    ensure!(
+        list[..len].split(|&b| b == 0).any(|n| n == name.as_bytes()),
+        "listxattr does not contain the attribute"
+    );
+
+    cvt(unsafe { libc::removexattr(path.as_ptr(), name.as_ptr()) } as isize)?;
+    expect_errno(get(), &[libc::ENODATA], "getxattr after removexattr")?;
+    expect_errno(
+        cvt(unsafe { libc::removexattr(path.as_ptr(), name.as_ptr()) } as isize),
+        &[libc::ENODATA],
+        "removexattr of a missing attribute",
+    )
+}
+
+fn readdir(dir: &Path) -> io::Result<()> {
+    fs::write(dir.join("a"), b"")?;
+    fs::write(dir.join("b"), b"")?;
+    fs::create_dir(dir.join("c"))?;
+
+    let mut names = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.file_name()))
+        .collect::<io::Result<Vec<_>>>()?;
+    names.sort();
+    ensure!(
+        names == ["a", "b", "c"],
+        "read_dir returned {:?}, expected [\"a\", \"b\", \"c\"]",
+        names
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skip_is_distinct_from_failure() {
+        assert!(Skip::is_skip(&Skip::error("skipped")));
+        assert!(!Skip::is_skip(&io::Error::new(
+            io::ErrorKind::Other,
+            "failed"
+        )));
+        assert!(!Skip::is_skip(&io::Error::from_raw_os_error(libc::EPERM)));
+    }
+}
@@ -0,0 +1,91 @@
+//! Instrumentation of the dispatched operations with `tracing`.
+//!
+//! When the `tracing` feature is enabled, each request is processed in
+//! a span named `request` with the target `libfuse`, which has the
+//! following fields:
+//!
+//! * `op` - the name of the operation, e.g. `lookup`
+//! * `ino` - the node ID passed to the operation
+//! * `fh` - the file handle, if the operation is on an opened file or
+//!   opens one
+//! * `seq` - a sequential number of the request in the process, which
+//!   is not the unique ID assigned by the kernel since libfuse does not
+//!   expose it
+//! * `pid` - the process ID of the caller, or of this process for the
+//!   requests synthesized by `testing::Harness`
+//! * `errno` - the error number, if the operation failed
+//! * `size` - the size of the replied data, for `read`, `write`,
+//!   `readdir`, `getxattr` and `listxattr`
+
+use libfuse_sys::{fuse_ino_t, fuse_req_t};
+
+#[cfg(feature = "tracing")]
+mod imp {
+    use super::*;
+    use libfuse_sys::{fuse_req_ctx, helpers::fuse_ctx_pid};
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use tracing::{field::Empty, Span};
+
+    static SEQ: AtomicU64 = AtomicU64::new(0);
+
+    pub(crate) type Guard = tracing::span::EnteredSpan;
+
+    pub(crate) fn enter(
+        op: &'static str,
+        req: fuse_req_t,
+        ino: fuse_ino_t,
+        fh: Option<u64>,
+    ) -> Guard {
+        let pid = if req.is_null() {
+            std::process::id() as libc::pid_t
+        } else {
+            unsafe { fuse_ctx_pid(fuse_req_ctx(req)) }
+        };
+        let span = tracing::debug_span!(
+            target: "libfuse",
+            "request",
+            op,
+            ino,
+            fh = Empty,
+            seq = SEQ.fetch_add(1, Ordering::Relaxed) + 1,
+            pid,
+            errno = Empty,
+            size = Empty,
+        );
+        if let Some(fh) = fh {
+            span.record("fh", fh);
+        }
+        span.entered()
+    }
+
+    pub(crate) fn record_fh(fh: u64) {
+        Span::current().record("fh", fh);
+    }
+
+    pub(crate) fn record_errno(errno: i32) {
+        Span::current().record("errno", errno);
+    }
+
+    pub(crate) fn record_size(size: usize) {
+        Span::current().record("size", size as u64);
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+mod imp {
+    use super::*;
+
+    pub(crate) struct Guard(());
+
+    pub(crate) fn enter(_: &'static str, _: fuse_req_t, _: fuse_ino_t, _: Option<u64>) -> Guard {
+        Guard(())
+    }
+
+    pub(crate) fn record_fh(_: u64) {}
+
+    pub(crate) fn record_errno(_: i32) {}
+
+    pub(crate) fn record_size(_: usize) {}
+}
+
+pub(crate) use self::imp::*;